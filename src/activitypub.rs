@@ -1,5 +1,6 @@
 use axum::{response::IntoResponse, Json};
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Actor {
@@ -9,15 +10,28 @@ pub struct Actor {
     pub actor_type: String,
     pub id: String,
     pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summary: Option<String>,
     pub icon: Option<Media>,
     pub inbox: String,
     pub outbox: String,
-    #[serde(rename = "publicKey")]
-    pub public_key: ActorPublicKey,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub followers: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub endpoints: Option<ActorEndpoints>,
+    /// A single object unless there are several keys
+    #[serde(rename = "publicKey", with = "one_or_many")]
+    pub public_key: Vec<ActorPublicKey>,
     #[serde(rename = "preferredUsername")]
     pub preferred_username: Option<String>,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ActorEndpoints {
+    #[serde(rename = "sharedInbox")]
+    pub shared_inbox: Option<String>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ActorPublicKey {
     pub id: String,
@@ -26,6 +40,38 @@ pub struct ActorPublicKey {
     pub pem: String,
 }
 
+impl Actor {
+    pub fn find_public_key(&self, key_id: &str) -> Option<&ActorPublicKey> {
+        self.public_key.iter()
+            .find(|public_key| public_key.id == key_id)
+    }
+}
+
+mod one_or_many {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany<T> {
+        One(T),
+        Many(Vec<T>),
+    }
+
+    pub fn serialize<S: Serializer, T: Serialize>(values: &[T], serializer: S) -> Result<S::Ok, S::Error> {
+        match values {
+            [value] => value.serialize(serializer),
+            values => values.serialize(serializer),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>, T: Deserialize<'de>>(deserializer: D) -> Result<Vec<T>, D::Error> {
+        Ok(match OneOrMany::deserialize(deserializer)? {
+            OneOrMany::One(value) => vec![value],
+            OneOrMany::Many(values) => values,
+        })
+    }
+}
+
 /// `ActivityPub` "activity"
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Action<O> {
@@ -54,3 +100,75 @@ pub struct Media {
     pub content_type: String,
     pub url: String,
 }
+
+/// A page of `collection_id` with the `items` after `after`, fetched
+/// with one more than `page_size` to tell whether there is a next page
+pub fn collection_page(collection_id: &str, after: &str, page_size: usize, total_items: i64, mut items: Vec<String>) -> serde_json::Value {
+    let page_id = |after: &str| if after.is_empty() {
+        format!("{}?page=true", collection_id)
+    } else {
+        format!("{}?page=true&after={}", collection_id, urlencoding::encode(after))
+    };
+    let next = (items.len() > page_size).then(|| {
+        items.truncate(page_size);
+        page_id(&items[page_size - 1])
+    });
+    let mut page = json!({
+        "@context": "https://www.w3.org/ns/activitystreams",
+        "id": page_id(after),
+        "type": "OrderedCollectionPage",
+        "totalItems": total_items,
+        "partOf": collection_id,
+        "orderedItems": items,
+    });
+    if let Some(next) = next {
+        page["next"] = json!(next);
+    }
+    page
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const FOLLOWERS: &str = "https://relay.example/tag/rust/followers";
+
+    fn items(range: std::ops::Range<usize>) -> Vec<String> {
+        range.map(|i| format!("https://example.com/users/{}", i))
+            .collect()
+    }
+
+    #[test]
+    fn first_page() {
+        let page = collection_page(FOLLOWERS, "", 2, 5, items(0..3));
+        assert_eq!(page["id"], "https://relay.example/tag/rust/followers?page=true");
+        assert_eq!(page["partOf"], FOLLOWERS);
+        assert_eq!(page["totalItems"], 5);
+        assert_eq!(page["orderedItems"], json!(items(0..2)));
+        assert_eq!(page["next"], "https://relay.example/tag/rust/followers?page=true&after=https%3A%2F%2Fexample.com%2Fusers%2F1");
+    }
+
+    #[test]
+    fn middle_page() {
+        let page = collection_page(FOLLOWERS, "https://example.com/users/1", 2, 5, items(2..5));
+        assert_eq!(page["id"], "https://relay.example/tag/rust/followers?page=true&after=https%3A%2F%2Fexample.com%2Fusers%2F1");
+        assert_eq!(page["orderedItems"], json!(items(2..4)));
+        assert_eq!(page["next"], "https://relay.example/tag/rust/followers?page=true&after=https%3A%2F%2Fexample.com%2Fusers%2F3");
+    }
+
+    #[test]
+    fn last_page() {
+        let page = collection_page(FOLLOWERS, "https://example.com/users/3", 2, 5, items(4..5));
+        assert_eq!(page["orderedItems"], json!(items(4..5)));
+        assert!(page.get("next").is_none());
+
+        // Exactly full
+        let page = collection_page(FOLLOWERS, "https://example.com/users/1", 2, 4, items(2..4));
+        assert_eq!(page["orderedItems"], json!(items(2..4)));
+        assert!(page.get("next").is_none());
+
+        let page = collection_page(FOLLOWERS, "", 2, 0, vec![]);
+        assert_eq!(page["orderedItems"], json!([]));
+        assert!(page.get("next").is_none());
+    }
+}
@@ -1,21 +1,183 @@
-use std::sync::Arc;
+use std::{collections::HashSet, sync::{Arc, RwLock}};
 use deunicode::deunicode;
 use sigh::{PublicKey, Key};
 
-use crate::activitypub;
+use crate::{activitypub, config::Config};
 
+/// Separates the tags of a tag set actor like `/tag/rust.tokio`,
+/// hashtags cannot contain it
+pub const TAG_SET_DELIMITER: char = '.';
+/// Maximum number of tags in a tag set actor
+pub const TAG_SET_MAX: usize = 8;
+/// Ends the path of a tag prefix actor like `/tag/rust*`
+pub const TAG_PREFIX_WILDCARD: char = '*';
+/// Bounds for the length of a tag prefix, in characters
+pub const TAG_PREFIX_MIN: usize = 3;
+pub const TAG_PREFIX_MAX: usize = 32;
+/// `preferredUsername` of the firehose actor at `/relay`, as with
+/// LitePub relays
+pub const FIREHOSE_USERNAME: &str = "relay";
+
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub enum ActorKind {
     TagRelay(String),
+    /// Relays posts with any of the (sorted) tags
+    TagSetRelay(Vec<String>),
+    /// Relays posts with any tag that starts with the prefix
+    TagPrefixRelay(String),
     InstanceRelay(String),
+    /// Relays the posts of one `user@host` account
+    AccountRelay(String),
+    /// Relays every public post, only with `firehose` enabled
+    Firehose,
+}
+
+/// Case-insensitive, and transliterated to ASCII so that any
+/// Unicode normalization form of a tag maps to the same actor
+pub fn normalize_tag(tag: &str) -> String {
+    deunicode(tag)
+        .to_lowercase()
+        .replace(char::is_whitespace, "")
+}
+
+/// Guesses the MIME type of an image by its extension
+fn image_type(url: &str) -> &'static str {
+    let ext = url.rsplit('.').next()
+        .unwrap_or("")
+        .to_lowercase();
+    match ext.as_str() {
+        "png" => "image/png",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "svg" => "image/svg+xml",
+        _ => "image/jpeg",
+    }
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
 }
 
 impl ActorKind {
     pub fn from_tag(tag: &str) -> Self {
-        let tag = deunicode(tag)
-            .to_lowercase()
-            .replace(char::is_whitespace, "");
-        ActorKind::TagRelay(tag)
+        ActorKind::TagRelay(normalize_tag(tag))
+    }
+
+    /// Parses the path of a tag actor which may be a tag set or a
+    /// tag prefix
+    pub fn from_tag_path(path: &str) -> Option<Self> {
+        if let Some(prefix) = path.strip_suffix(TAG_PREFIX_WILDCARD) {
+            let prefix = normalize_tag(prefix);
+            let len = prefix.chars().count();
+            if ! (TAG_PREFIX_MIN..=TAG_PREFIX_MAX).contains(&len)
+                || prefix.contains([TAG_SET_DELIMITER, TAG_PREFIX_WILDCARD])
+            {
+                return None;
+            }
+            return Some(ActorKind::TagPrefixRelay(prefix));
+        }
+
+        let mut tags = path.split(TAG_SET_DELIMITER)
+            .map(normalize_tag)
+            .filter(|tag| ! tag.is_empty())
+            .collect::<Vec<_>>();
+        tags.sort();
+        tags.dedup();
+        match tags.len() {
+            0 => None,
+            1 => tags.pop().map(ActorKind::TagRelay),
+            len if len <= TAG_SET_MAX => Some(ActorKind::TagSetRelay(tags)),
+            _ => None,
+        }
+    }
+
+    /// Tag prefix actors that match a tag, of those with a prefix in
+    /// `followed`.
+    ///
+    /// Instead of applying every followed pattern to every tag, this
+    /// looks up the `TAG_PREFIX_MAX` possible prefixes at most.
+    pub fn tag_prefixes(tag: &str, followed: &HashSet<String>) -> impl Iterator<Item = Self> {
+        let tag = normalize_tag(tag);
+        let prefixes = if followed.is_empty() {
+            vec![]
+        } else {
+            tag.char_indices()
+                .map(|(pos, c)| pos + c.len_utf8())
+                .skip(TAG_PREFIX_MIN - 1)
+                .take(TAG_PREFIX_MAX + 1 - TAG_PREFIX_MIN)
+                .filter(|end| followed.contains(&tag[..*end]))
+                .map(|end| ActorKind::TagPrefixRelay(tag[..end].to_string()))
+                .collect::<Vec<_>>()
+        };
+        prefixes.into_iter()
+    }
+
+    /// Whether a tag actor serves only tags of `allowed_tags`. Tag
+    /// prefixes could match any tag and are never allowed. Other
+    /// actors are not restricted.
+    pub fn tags_allowed(&self, allowed_tags: &HashSet<String>) -> bool {
+        match self {
+            ActorKind::TagRelay(tag) =>
+                allowed_tags.contains(tag),
+            ActorKind::TagSetRelay(tags) =>
+                tags.iter().all(|tag| allowed_tags.contains(tag)),
+            ActorKind::TagPrefixRelay(_) =>
+                false,
+            _ =>
+                true,
+        }
+    }
+
+    /// Order in which actors get to Announce a post to an inbox that
+    /// follows several of them: firehose, instance, account, tags,
+    /// tag sets, tag prefixes.
+    /// Actors of the same kind are ordered alphabetically.
+    pub fn precedence(&self) -> u8 {
+        match self {
+            ActorKind::Firehose => 0,
+            ActorKind::InstanceRelay(_) => 1,
+            ActorKind::AccountRelay(_) => 2,
+            ActorKind::TagRelay(_) => 3,
+            ActorKind::TagSetRelay(_) => 4,
+            ActorKind::TagPrefixRelay(_) => 5,
+        }
+    }
+
+    /// Parses `user@host`, with an optional leading `@`
+    pub fn from_account(account: &str) -> Option<Self> {
+        let (user, host) = account.trim_start_matches('@')
+            .split_once('@')?;
+        if user.is_empty() || host.is_empty() || host.contains('@') {
+            return None;
+        }
+        Some(ActorKind::AccountRelay(format!("{}@{}", user, host).to_lowercase()))
+    }
+
+    /// Parses the `preferredUsername` of a relay actor, accepting
+    /// both `-` and `_` after the prefix
+    pub fn from_username(username: &str) -> Option<Self> {
+        if username == FIREHOSE_USERNAME {
+            return Some(ActorKind::Firehose);
+        }
+        let strip = |prefix: &str| username.strip_prefix(prefix)
+            .and_then(|rest| rest.strip_prefix(['-', '_']))
+            .filter(|rest| ! rest.is_empty());
+        if let Some(prefix) = strip("tagprefix") {
+            ActorKind::from_tag_path(&format!("{}{}", prefix, TAG_PREFIX_WILDCARD))
+        } else if let Some(tag) = strip("tag") {
+            ActorKind::from_tag_path(tag)
+        } else if let Some(account) = strip("account") {
+            // `user.host` because the username must not contain `@`
+            let (user, host) = account.split_once('.')?;
+            ActorKind::from_account(&format!("{}@{}", user, host))
+        } else {
+            strip("instance")
+                .map(|instance| ActorKind::InstanceRelay(instance.to_lowercase()))
+        }
     }
 }
 
@@ -30,44 +192,311 @@ impl Actor {
         match &self.kind {
             ActorKind::TagRelay(tag) =>
                 format!("https://{}/tag/{}", self.host, tag),
+            ActorKind::TagSetRelay(tags) =>
+                format!("https://{}/tag/{}", self.host, tags.join(&TAG_SET_DELIMITER.to_string())),
+            ActorKind::TagPrefixRelay(prefix) =>
+                format!("https://{}/tag/{}{}", self.host, prefix, TAG_PREFIX_WILDCARD),
             ActorKind::InstanceRelay(instance) =>
                 format!("https://{}/instance/{}", self.host, instance),
+            ActorKind::AccountRelay(account) =>
+                format!("https://{}/account/{}", self.host, account),
+            ActorKind::Firehose =>
+                format!("https://{}/{}", self.host, FIREHOSE_USERNAME),
         }
     }
 
-    pub fn key_id(&self) -> String {
-        format!("{}#key", self.uri())
+    pub fn summary(&self, config: &Config) -> String {
+        match &self.kind {
+            ActorKind::TagRelay(tag) =>
+                config.tag_summary.replace("{tag}", &escape_html(tag)),
+            ActorKind::TagSetRelay(tags) =>
+                config.tag_summary.replace("{tag}", &escape_html(&tags.join(", #"))),
+            ActorKind::TagPrefixRelay(prefix) =>
+                config.tag_summary.replace("{tag}", &escape_html(&format!("{}{}", prefix, TAG_PREFIX_WILDCARD))),
+            ActorKind::InstanceRelay(instance) =>
+                config.instance_summary.replace("{host}", &escape_html(instance)),
+            ActorKind::AccountRelay(account) =>
+                config.account_summary.replace("{account}", &escape_html(account)),
+            ActorKind::Firehose =>
+                config.firehose_summary.replace("{host}", &escape_html(&self.host)),
+        }
+    }
+
+    pub fn followers_uri(&self) -> String {
+        format!("{}/followers", self.uri())
+    }
+
+    pub fn key_id(&self, config: &Config) -> String {
+        config.key_id((&self.kind).into(), &self.uri())
+    }
+
+    /// `preferredUsername`, as parsed by `ActorKind::from_username()`
+    pub fn username(&self) -> String {
+        match &self.kind {
+            ActorKind::TagRelay(tag) =>
+                format!("tag-{}", tag),
+            ActorKind::TagSetRelay(tags) =>
+                format!("tag-{}", tags.join(&TAG_SET_DELIMITER.to_string())),
+            // `*` is not allowed in usernames
+            ActorKind::TagPrefixRelay(prefix) =>
+                format!("tagprefix-{}", prefix),
+            ActorKind::InstanceRelay(instance) =>
+                format!("instance-{}", instance),
+            ActorKind::AccountRelay(account) =>
+                format!("account-{}", account.replacen('@', ".", 1)),
+            ActorKind::Firehose =>
+                FIREHOSE_USERNAME.to_string(),
+        }
     }
 
-    pub fn as_activitypub(&self, pub_key: &PublicKey) -> activitypub::Actor {
+    /// `pub_keys` as in `KeyRing::get()`
+    pub fn as_activitypub(&self, pub_keys: &[(String, PublicKey)], config: &Config) -> activitypub::Actor {
+        let icon = config.icon(&self.kind);
         activitypub::Actor {
-            jsonld_context: serde_json::Value::String("https://www.w3.org/ns/activitystreams".to_string()),
-            actor_type: "Service".to_string(),
+            jsonld_context: config.jsonld_context(),
+            actor_type: config.actor_type.as_str().to_string(),
             id: self.uri(),
             name: Some(match &self.kind {
                 ActorKind::TagRelay(tag) =>
                     format!("#{}", tag),
+                ActorKind::TagSetRelay(tags) =>
+                    tags.iter()
+                        .map(|tag| format!("#{}", tag))
+                        .collect::<Vec<_>>()
+                        .join(" "),
+                ActorKind::TagPrefixRelay(prefix) =>
+                    format!("#{}{}", prefix, TAG_PREFIX_WILDCARD),
                 ActorKind::InstanceRelay(instance) =>
                     instance.to_string(),
+                ActorKind::AccountRelay(account) =>
+                    format!("@{}", account),
+                ActorKind::Firehose =>
+                    self.host.to_string(),
             }),
+            summary: Some(self.summary(config)),
             icon: Some(activitypub::Media {
                 media_type: "Image".to_string(),
-                content_type: "image/jpeg".to_string(),
-                url: "https://fedi.buzz/assets/favicon48.png".to_string(),
+                content_type: image_type(&icon).to_string(),
+                url: icon,
             }),
             inbox: self.uri(),
             outbox: format!("{}/outbox", self.uri()),
-            public_key: activitypub::ActorPublicKey {
-                id: self.key_id(),
-                owner: Some(self.uri()),
-                pem: pub_key.to_pem().unwrap(),
-            },
-            preferred_username: Some(match &self.kind {
-                ActorKind::TagRelay(tag) =>
-                    format!("tag-{}", tag),
-                ActorKind::InstanceRelay(instance) =>
-                    format!("instance-{}", instance),
-            }),
+            followers: Some(self.followers_uri()),
+            endpoints: None,
+            public_key: pub_keys.iter()
+                .map(|(name, pub_key)| activitypub::ActorPublicKey {
+                    id: format!("{}#{}", self.uri(), name),
+                    owner: Some(self.uri()),
+                    pem: pub_key.to_pem().unwrap(),
+                })
+                .collect(),
+            preferred_username: Some(self.username()),
+        }
+    }
+}
+
+/// Prefixes of tag prefix actors that have followers, so that posts
+/// only target those. Follows add to it, the relay reloads it.
+#[derive(Clone, Default)]
+pub struct FollowedPrefixes(Arc<RwLock<Arc<HashSet<String>>>>);
+
+impl FollowedPrefixes {
+    pub fn get(&self) -> Arc<HashSet<String>> {
+        self.0.read().unwrap()
+            .clone()
+    }
+
+    pub fn insert(&self, prefix: &str) {
+        let mut prefixes = self.0.write().unwrap();
+        if ! prefixes.contains(prefix) {
+            Arc::make_mut(&mut prefixes)
+                .insert(prefix.to_string());
         }
     }
+
+    pub fn replace(&self, prefixes: HashSet<String>) {
+        *self.0.write().unwrap() = Arc::new(prefixes);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn from_tag_mixed_case() {
+        let rust = ActorKind::TagRelay("rust".to_string());
+        assert_eq!(ActorKind::from_tag("rust"), rust);
+        assert_eq!(ActorKind::from_tag("Rust"), rust);
+        assert_eq!(ActorKind::from_tag("RUST"), rust);
+    }
+
+    #[test]
+    fn from_tag_accented() {
+        let cafe = ActorKind::TagRelay("cafe".to_string());
+        // precomposed (NFC)
+        assert_eq!(ActorKind::from_tag("Caf\u{e9}"), cafe);
+        // decomposed (NFD)
+        assert_eq!(ActorKind::from_tag("Cafe\u{301}"), cafe);
+        assert_eq!(ActorKind::from_tag("cafe"), cafe);
+    }
+
+    #[test]
+    fn from_tag_path() {
+        assert_eq!(ActorKind::from_tag_path("Rust"), Some(ActorKind::TagRelay("rust".to_string())));
+        assert_eq!(ActorKind::from_tag_path("tokio.Rust.rust"), Some(ActorKind::TagSetRelay(vec![
+            "rust".to_string(),
+            "tokio".to_string(),
+        ])));
+        assert_eq!(ActorKind::from_tag_path("rust..tokio."), ActorKind::from_tag_path("tokio.rust"));
+        assert_eq!(ActorKind::from_tag_path(".."), None);
+        assert_eq!(ActorKind::from_tag_path("a.b.c.d.e.f.g.h.i"), None);
+    }
+
+    #[test]
+    fn tag_set_uri() {
+        let actor = Actor {
+            host: Arc::new("relay.example".to_string()),
+            kind: ActorKind::from_tag_path("tokio.rust").unwrap(),
+        };
+        assert_eq!(actor.uri(), "https://relay.example/tag/rust.tokio");
+        assert_eq!(ActorKind::from_username("tag-rust.tokio"), Some(actor.kind));
+    }
+
+    #[test]
+    fn tag_prefix() {
+        let actor = Actor {
+            host: Arc::new("relay.example".to_string()),
+            kind: ActorKind::from_tag_path("Rust*").unwrap(),
+        };
+        assert_eq!(actor.kind, ActorKind::TagPrefixRelay("rust".to_string()));
+        assert_eq!(actor.uri(), "https://relay.example/tag/rust*");
+        let json = serde_json::to_value(actor.as_activitypub(&[], &Config::test(""))).unwrap();
+        assert_eq!(json["preferredUsername"], "tagprefix-rust");
+        assert_eq!(ActorKind::from_username("tagprefix-rust"), Some(actor.kind.clone()));
+        assert_eq!(ActorKind::from_username("tag-rust*"), Some(actor.kind));
+        assert_eq!(ActorKind::from_username("tagprefix-rust*"), None);
+        assert_eq!(ActorKind::from_tag_path("ru*"), None);
+        assert_eq!(ActorKind::from_tag_path("ru*st*"), None);
+        assert_eq!(ActorKind::from_tag_path("rust.tokio*"), None);
+        assert_eq!(ActorKind::from_tag_path(&format!("{}*", "a".repeat(TAG_PREFIX_MAX + 1))), None);
+    }
+
+    #[test]
+    fn firehose() {
+        let actor = Actor {
+            host: Arc::new("relay.example".to_string()),
+            kind: ActorKind::Firehose,
+        };
+        assert_eq!(actor.uri(), "https://relay.example/relay");
+        assert_eq!(ActorKind::from_username("relay"), Some(ActorKind::Firehose));
+        let json = serde_json::to_value(actor.as_activitypub(&[], &Config::test(""))).unwrap();
+        assert_eq!(json["preferredUsername"], "relay");
+        assert_eq!(json["type"], "Service");
+        let json = serde_json::to_value(actor.as_activitypub(&[], &Config::test("actor_type: Application"))).unwrap();
+        assert_eq!(json["type"], "Application");
+    }
+
+    #[test]
+    fn tag_prefixes() {
+        let followed = ["rus", "rust", "rustlang", "rustacean", "rs"]
+            .map(str::to_string)
+            .into();
+        let prefixes = ActorKind::tag_prefixes("RustLang", &followed)
+            .collect::<Vec<_>>();
+        assert_eq!(prefixes, ["rus", "rust", "rustlang"].map(|prefix|
+            ActorKind::TagPrefixRelay(prefix.to_string())
+        ));
+        assert_eq!(ActorKind::tag_prefixes("rs", &followed).count(), 0);
+        let followed = (TAG_PREFIX_MIN..=100)
+            .map(|len| "a".repeat(len))
+            .collect();
+        assert_eq!(ActorKind::tag_prefixes(&"a".repeat(100), &followed).count(), TAG_PREFIX_MAX + 1 - TAG_PREFIX_MIN);
+        assert_eq!(ActorKind::tag_prefixes("RustLang", &HashSet::new()).count(), 0);
+    }
+
+    #[test]
+    fn followed_prefixes() {
+        let prefixes = FollowedPrefixes::default();
+        let before = prefixes.get();
+        prefixes.clone().insert("rust");
+        assert!(before.is_empty());
+        assert!(prefixes.get().contains("rust"));
+        prefixes.replace(["go".to_string()].into());
+        assert!(! prefixes.get().contains("rust"));
+        assert!(prefixes.get().contains("go"));
+    }
+
+    #[test]
+    fn summary() {
+        let config = Config::test("");
+        let actor = |kind| Actor {
+            host: Arc::new("relay.example".to_string()),
+            kind,
+        };
+        assert_eq!(
+            actor(ActorKind::from_tag("rust")).summary(&config),
+            "Relay for #rust, follow to receive public posts tagged #rust"
+        );
+        assert_eq!(
+            actor(ActorKind::from_tag_path("rust.tokio").unwrap()).summary(&config),
+            "Relay for #rust, #tokio, follow to receive public posts tagged #rust, #tokio"
+        );
+        assert_eq!(
+            actor(ActorKind::InstanceRelay("<b>".to_string())).summary(&config),
+            "Relay mirroring public posts from &lt;b&gt;"
+        );
+    }
+
+    #[test]
+    fn from_account() {
+        let account = Some(ActorKind::AccountRelay("alice@example.social".to_string()));
+        assert_eq!(ActorKind::from_account("Alice@example.social"), account);
+        assert_eq!(ActorKind::from_account("@alice@example.social"), account);
+        assert_eq!(ActorKind::from_username("account-alice.example.social"), account);
+        assert_eq!(ActorKind::from_account("alice"), None);
+        assert_eq!(ActorKind::from_account("alice@"), None);
+        assert_eq!(ActorKind::from_account("a@b@c"), None);
+    }
+
+    #[test]
+    fn from_username() {
+        assert_eq!(ActorKind::from_username("tag-rust"), Some(ActorKind::TagRelay("rust".to_string())));
+        assert_eq!(ActorKind::from_username("tag_Rust"), Some(ActorKind::TagRelay("rust".to_string())));
+        assert_eq!(ActorKind::from_username("instance-Example.Social"), Some(ActorKind::InstanceRelay("example.social".to_string())));
+        assert_eq!(ActorKind::from_username("instance_example.social"), Some(ActorKind::InstanceRelay("example.social".to_string())));
+        assert_eq!(ActorKind::from_username("tag-"), None);
+        assert_eq!(ActorKind::from_username("tagrust"), None);
+        assert_eq!(ActorKind::from_username("admin"), None);
+    }
+
+    #[test]
+    fn public_keys() {
+        use sigh::alg::{Algorithm, RsaSha256};
+
+        let config = Config::test("key_name: key2");
+        let actor = Actor {
+            host: Arc::new("relay.example".to_string()),
+            kind: ActorKind::from_tag("rust"),
+        };
+        assert_eq!(actor.key_id(&config), "https://relay.example/tag/rust#key2");
+
+        let (_, current) = RsaSha256.generate_keys().unwrap();
+        let (_, previous) = RsaSha256.generate_keys().unwrap();
+        let json = serde_json::to_value(actor.as_activitypub(&[
+            ("key2".to_string(), current.clone()),
+        ], &config)).unwrap();
+        assert_eq!(json["publicKey"]["id"], "https://relay.example/tag/rust#key2");
+
+        let json = serde_json::to_value(actor.as_activitypub(&[
+            ("key2".to_string(), current),
+            ("key".to_string(), previous.clone()),
+        ], &config)).unwrap();
+        assert_eq!(json["publicKey"][0]["id"], "https://relay.example/tag/rust#key2");
+        assert_eq!(json["publicKey"][1]["id"], "https://relay.example/tag/rust#key");
+        let parsed: activitypub::Actor = serde_json::from_value(json).unwrap();
+        let found = parsed.find_public_key("https://relay.example/tag/rust#key").unwrap();
+        assert_eq!(found.pem, previous.to_pem().unwrap());
+        assert!(parsed.find_public_key("https://relay.example/tag/rust#key3").is_none());
+    }
 }
@@ -0,0 +1,191 @@
+use axum::{
+    extract::{Path, Query},
+    http::{header, HeaderMap, Method, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Deserialize;
+use serde_json::json;
+use crate::{relay::Command, track_request, State};
+
+/// Default number of actors for `stats`
+const TOP_ACTORS: i64 = 20;
+/// Default days without Announces after which `stats` lists an actor
+/// as idle
+const IDLE_DAYS: i32 = 30;
+
+#[derive(Deserialize)]
+pub struct Params {
+    inbox: Option<String>,
+    limit: Option<i64>,
+    idle_days: Option<i32>,
+}
+
+/// Whether the request carries `Authorization: Bearer <token>`,
+/// compared in constant time
+fn authorized(token: &str, headers: &HeaderMap) -> bool {
+    headers.get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|given| given.len() == token.len()
+                     && openssl::memcmp::eq(given.as_bytes(), token.as_bytes()))
+}
+
+/// `GET /admin/followers`, `GET /admin/stats?limit=…&idle_days=…`,
+/// `GET /admin/deliveries`,
+/// `POST /admin/remove_inbox?inbox=…`,
+/// `POST /admin/reload_blocklist`, `POST /admin/respawn_workers`,
+/// `POST /admin/pause`, `POST /admin/resume`
+pub async fn admin(
+    axum::extract::State(state): axum::extract::State<State>,
+    method: Method,
+    Path(action): Path<String>,
+    Query(params): Query<Params>,
+    headers: HeaderMap,
+) -> Response {
+    // Without a configured token there is no admin API
+    let Some(token) = state.config.admin_token() else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let method_label = method_label(&method);
+    if ! authorized(token, &headers) {
+        track_request(method_label, "admin", "unauthorized");
+        return (StatusCode::UNAUTHORIZED, [(header::WWW_AUTHENTICATE, "Bearer")]).into_response();
+    }
+
+    match (method, action.as_str()) {
+        (Method::GET, "followers") => match state.database.get_follows().await {
+            Ok(follows) => {
+                track_request("GET", "admin", "followers");
+                Json(follows.into_iter()
+                     .map(|(id, inbox, actor)| json!({
+                         "id": id,
+                         "inbox": inbox,
+                         "actor": actor,
+                     }))
+                     .collect::<Vec<_>>()
+                ).into_response()
+            }
+            Err(e) => {
+                tracing::error!("get_follows: {}", e);
+                (StatusCode::INTERNAL_SERVER_ERROR, format!("{}", e)).into_response()
+            }
+        },
+        (Method::GET, "stats") => {
+            let top = state.database.get_top_actors(params.limit.unwrap_or(TOP_ACTORS)).await;
+            let idle = state.database.get_idle_actors(params.idle_days.unwrap_or(IDLE_DAYS)).await;
+            match (top, idle) {
+                (Ok(top), Ok(idle)) => {
+                    track_request("GET", "admin", "stats");
+                    Json(json!({
+                        "top": top.into_iter()
+                            .map(|(actor, announces, last_announce)| json!({
+                                "actor": actor,
+                                "announces": announces,
+                                "last_announce": chrono::NaiveDateTime::from_timestamp_opt(last_announce, 0)
+                                    .map(|time| time.and_utc().to_rfc3339()),
+                            }))
+                            .collect::<Vec<_>>(),
+                        "idle": idle,
+                    })).into_response()
+                }
+                (Err(e), _) | (_, Err(e)) => {
+                    tracing::error!("stats: {}", e);
+                    (StatusCode::INTERNAL_SERVER_ERROR, format!("{}", e)).into_response()
+                }
+            }
+        }
+        (Method::GET, "deliveries") => match state.database.get_actor_deliveries().await {
+            Ok(deliveries) => {
+                track_request("GET", "admin", "deliveries");
+                Json(deliveries.into_iter()
+                     .map(|(actor, last_delivery)| (
+                         actor,
+                         chrono::NaiveDateTime::from_timestamp_opt(last_delivery, 0)
+                             .map(|time| time.and_utc().to_rfc3339()),
+                     ))
+                     .collect::<std::collections::BTreeMap<_, _>>()
+                ).into_response()
+            }
+            Err(e) => {
+                tracing::error!("get_actor_deliveries: {}", e);
+                (StatusCode::INTERNAL_SERVER_ERROR, format!("{}", e)).into_response()
+            }
+        },
+        (Method::POST, "remove_inbox") => {
+            let Some(inbox) = params.inbox else {
+                return (StatusCode::BAD_REQUEST, "Missing inbox").into_response();
+            };
+            match state.database.remove_inbox(&inbox).await {
+                Ok(count) => {
+                    track_request("POST", "admin", "remove_inbox");
+                    tracing::info!(%inbox, count, "admin removed follows of inbox");
+                    Json(json!({ "removed": count })).into_response()
+                }
+                Err(e) => {
+                    tracing::error!("remove_inbox: {}", e);
+                    (StatusCode::INTERNAL_SERVER_ERROR, format!("{}", e)).into_response()
+                }
+            }
+        }
+        (Method::POST, "reload_blocklist") =>
+            command(&state, "reload_blocklist", Command::ReloadBlocklist).await,
+        (Method::POST, "respawn_workers") =>
+            command(&state, "respawn_workers", Command::RespawnWorkers).await,
+        (Method::POST, "pause") =>
+            command(&state, "pause", Command::Pause).await,
+        (Method::POST, "resume") =>
+            command(&state, "resume", Command::Resume).await,
+        _ => {
+            track_request(method_label, "admin", "not_found");
+            StatusCode::NOT_FOUND.into_response()
+        }
+    }
+}
+
+/// The method for `track_request()`: the router only passes GET,
+/// HEAD as GET, and POST
+fn method_label(method: &Method) -> &'static str {
+    if *method == Method::POST {
+        "POST"
+    } else {
+        "GET"
+    }
+}
+
+/// Hands a command to the relay task
+async fn command(state: &State, result: &'static str, command: Command) -> Response {
+    match state.commands.send(command).await {
+        Ok(()) => {
+            track_request("POST", "admin", result);
+            StatusCode::ACCEPTED.into_response()
+        }
+        Err(_) =>
+            (StatusCode::SERVICE_UNAVAILABLE, "Relay is not running").into_response(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use axum::http::HeaderValue;
+    use super::*;
+
+    #[test]
+    fn method_labels() {
+        assert_eq!(method_label(&Method::GET), "GET");
+        assert_eq!(method_label(&Method::HEAD), "GET");
+        assert_eq!(method_label(&Method::POST), "POST");
+    }
+
+    #[test]
+    fn bearer_token() {
+        let mut headers = HeaderMap::new();
+        assert!(! authorized("secret", &headers));
+        headers.insert(header::AUTHORIZATION, HeaderValue::from_static("Bearer secret"));
+        assert!(authorized("secret", &headers));
+        assert!(! authorized("secret2", &headers));
+        assert!(! authorized("secre", &headers));
+        headers.insert(header::AUTHORIZATION, HeaderValue::from_static("Basic secret"));
+        assert!(! authorized("secret", &headers));
+    }
+}
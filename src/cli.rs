@@ -0,0 +1,138 @@
+use std::io::{BufRead, Write};
+use futures::StreamExt;
+use crate::{actor::{Actor, ActorKind}, config::Config, db::{Database, Follow}, inbox};
+
+/// Runs a command given after the config file, returns the process
+/// exit code
+pub async fn run(command: &str, args: &[String], config: &Config, database: &Database) -> i32 {
+    let dry_run = args.iter().any(|arg| arg == "--dry-run");
+    let result = match command {
+        "export-followers" => export_followers(database).await,
+        "import-followers" => import_followers(config, database).await,
+        "normalize-tags" => normalize_tags(config, database, dry_run).await,
+        _ => {
+            eprintln!("Unknown command {}, expected export-followers, import-followers, or normalize-tags [--dry-run]", command);
+            return 2;
+        }
+    };
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{}: {}", command, e);
+            1
+        }
+    }
+}
+
+/// Writes the follows to stdout as JSON lines
+async fn export_followers(database: &Database) -> Result<(), Box<dyn std::error::Error>> {
+    let follows = database.export_follows().await?;
+    futures::pin_mut!(follows);
+    let mut stdout = std::io::BufWriter::new(std::io::stdout().lock());
+    let mut count = 0;
+    while let Some(follow) = follows.next().await {
+        serde_json::to_writer(&mut stdout, &follow?)?;
+        writeln!(stdout)?;
+        count += 1;
+    }
+    stdout.flush()?;
+    eprintln!("Exported {} follows", count);
+    Ok(())
+}
+
+/// Adds the follows from JSON lines on stdin, updating existing ones.
+/// Follows with invalid inboxes are skipped.
+async fn import_followers(config: &Config, database: &Database) -> Result<(), Box<dyn std::error::Error>> {
+    let mut count = 0;
+    for (number, line) in std::io::stdin().lock().lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let follow: Follow = serde_json::from_str(&line)
+            .map_err(|e| format!("line {}: {}", number + 1, e))?;
+        let inbox = match inbox::canonicalize(&follow.inbox, config.allow_insecure_inboxes) {
+            Ok(inbox) => inbox,
+            Err(e) => {
+                eprintln!("line {}: {}", number + 1, e);
+                continue;
+            }
+        };
+        let shared_inbox = follow.shared_inbox
+            .and_then(|shared_inbox| inbox::canonicalize(&shared_inbox, config.allow_insecure_inboxes).ok());
+        database.add_follow(&follow.id, &inbox, &follow.actor, shared_inbox.as_deref())
+            .await?;
+        count += 1;
+    }
+    eprintln!("Imported {} follows", count);
+    Ok(())
+}
+
+/// The normalized form of a tag actor URI if it differs
+fn normalized_tag_actor(hostname: &str, uri: &str) -> Option<Actor> {
+    let prefix = format!("https://{}/tag/", hostname);
+    let path = uri.strip_prefix(&prefix)?;
+    let path = urlencoding::decode(path).ok()?;
+    let actor = Actor {
+        host: std::sync::Arc::new(hostname.to_string()),
+        kind: ActorKind::from_tag_path(&path)?,
+    };
+    Some(actor)
+        .filter(|actor| actor.uri() != uri)
+}
+
+/// Merges the follows of tag actors that differ from their normalized
+/// form into it, or just lists them with `dry_run`. Running it again
+/// finds nothing left to do.
+async fn normalize_tags(config: &Config, database: &Database, dry_run: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let mut merges = 0;
+    for actor in database.get_follow_actors().await? {
+        let Some(normalized_actor) = normalized_tag_actor(&config.hostname, &actor) else { continue };
+        let normalized = normalized_actor.uri();
+        merges += 1;
+        if dry_run {
+            let followers = database.get_actor_followers_count(&actor).await?;
+            println!("{} -> {} ({} followers)", actor, normalized, followers);
+        } else {
+            let moved = database.merge_actor(&actor, &normalized).await?;
+            if let ActorKind::TagSetRelay(tags) = &normalized_actor.kind {
+                database.add_tag_set(&normalized, tags).await?;
+            }
+            println!("{} -> {} ({} follows moved)", actor, normalized, moved);
+        }
+    }
+    eprintln!("{} {} actors", if dry_run { "Would merge" } else { "Merged" }, merges);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn normalized_tag_actors() {
+        let normalize = |uri| normalized_tag_actor("relay.example", uri)
+            .map(|actor| actor.uri());
+        assert_eq!(normalize("https://relay.example/tag/Rust"), Some("https://relay.example/tag/rust".to_string()));
+        assert_eq!(normalize("https://relay.example/tag/M%C3%BCnchen"), Some("https://relay.example/tag/munchen".to_string()));
+        assert_eq!(normalize("https://relay.example/tag/tokio.Rust"), Some("https://relay.example/tag/rust.tokio".to_string()));
+        assert_eq!(normalize("https://relay.example/tag/rust"), None);
+        assert_eq!(normalize("https://relay.example/instance/Example.com"), None);
+        assert_eq!(normalize("https://other.example/tag/Rust"), None);
+    }
+
+    #[test]
+    fn follow_json_line() {
+        let follow = Follow {
+            id: "https://example.com/users/relay".to_string(),
+            inbox: "https://example.com/users/relay/inbox".to_string(),
+            actor: "https://relay.example/tag/rust".to_string(),
+            shared_inbox: None,
+        };
+        let line = serde_json::to_string(&follow).unwrap();
+        assert!(! line.contains('\n'));
+        assert_eq!(serde_json::from_str::<Follow>(&line).unwrap(), follow);
+        let without_shared_inbox = r#"{"id":"https://example.com/users/relay","inbox":"https://example.com/users/relay/inbox","actor":"https://relay.example/tag/rust"}"#;
+        assert_eq!(serde_json::from_str::<Follow>(without_shared_inbox).unwrap(), follow);
+    }
+}
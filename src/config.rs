@@ -1,35 +1,1012 @@
+use std::{collections::{HashMap, HashSet}, net::SocketAddr, num::{NonZeroU32, NonZeroUsize}, path::PathBuf, time::Duration};
 use serde::Deserialize;
 use sigh::{PrivateKey, PublicKey, Key};
+use crate::{actor::{normalize_tag, ActorKind, FIREHOSE_USERNAME}, keys::KeyRing, domain_list::DomainList, resolver::{AddressFilter, IpRange}};
 
 #[derive(Deserialize)]
 pub struct Config {
     pub streams: Vec<String>,
+    /// Seconds without even a heartbeat after which a stream gets
+    /// reconnected
+    #[serde(default = "default_stream_heartbeat_timeout")]
+    stream_heartbeat_timeout: u64,
     pub db: String,
+    /// PostgreSQL connections, must not be 0
+    #[serde(default = "default_db_pool_size")]
+    pub db_pool_size: NonZeroUsize,
+    /// Seconds until connecting to PostgreSQL is aborted, unless
+    /// `connect_timeout` is set in `db`
+    #[serde(default = "default_db_connect_timeout")]
+    db_connect_timeout: u64,
+    /// Seconds after which a connection gets replaced
+    db_max_lifetime: Option<u64>,
     pub hostname: String,
+    /// Port on 127.0.0.1, unless `listen` is set
+    #[serde(default = "default_listen_port")]
     pub listen_port: u16,
+    /// `unix:<path>` or an address with port, instead of `listen_port`
+    listen: Option<String>,
     priv_key_file: String,
     pub_key_file: String,
+    /// Fragment of the signing key's id on every actor
+    #[serde(default = "default_key_name")]
+    key_name: String,
+    /// Public keys to advertise besides the signing key, for rotation
+    #[serde(default)]
+    extra_pub_keys: Vec<ExtraPubKey>,
+    /// Own keypairs for kinds of relays instead of `priv_key_file`
+    #[serde(default)]
+    kind_keys: HashMap<RelayKind, KindKeys>,
+    /// Limit for requests to the same inbox host
+    pub max_requests_per_second: Option<f64>,
+    /// Persist jobs that could not be enqueued, or were still queued at
+    /// shutdown, for retrying them later
+    #[serde(default)]
+    pub spool: bool,
+    /// Seconds after which spooled jobs are discarded
+    #[serde(default = "default_spool_ttl")]
+    spool_ttl: u64,
+    /// Undo Announces of deleted posts
+    #[serde(default)]
+    pub relay_deletes: bool,
+    /// Undo and repeat the Announces of edited posts
+    #[serde(default)]
+    pub relay_updates: bool,
+    /// Relay the boosted posts of reblogs
+    #[serde(default)]
+    pub relay_reblogs: bool,
+    /// Relay unlisted posts besides public ones
+    #[serde(default)]
+    pub relay_unlisted: bool,
+    /// Address the actor's followers collection in `cc:`
+    #[serde(default)]
+    pub cc_followers: bool,
+    /// Kinds of relays that skip replies
+    #[serde(default)]
+    exclude_replies: HashSet<RelayKind>,
+    /// ActivityStreams `type` of all relay actors
+    #[serde(default)]
+    pub actor_type: ActorType,
+    /// Filter posts marked as sensitive
+    #[serde(default)]
+    pub sensitive_policy: SensitivePolicy,
+    /// Accept `http://` and non-public inbox URLs, for testing
+    #[serde(default)]
+    pub allow_insecure_inboxes: bool,
+    /// Non-public address ranges that outgoing requests may go to
+    #[serde(default)]
+    allowed_private_ranges: Vec<IpRange>,
+    /// `@context` of actors and activities
+    #[serde(default = "default_jsonld_context")]
+    jsonld_context: Vec<serde_json::Value>,
+    /// Histogram buckets in seconds for `relay_post_duration`
+    #[serde(default = "default_post_duration_buckets")]
+    post_duration_buckets: Vec<f64>,
+    /// HTML of a note that relay actors send to new followers, at
+    /// most once a day per follower
+    pub welcome_message: Option<String>,
+    /// Moderation endpoint to POST received `Flag` activities to
+    pub flag_forward_url: Option<String>,
+    /// Bearer token for the `/admin/` API, which is disabled without
+    admin_token: Option<String>,
+    /// Drop posts with less text, in characters, besides the markup
+    #[serde(default)]
+    pub min_content_length: usize,
+    /// Drop posts with more links, not counting hashtags and mentions
+    pub max_links: Option<usize>,
+    /// Tags of a post to relay for, the rest are ignored
+    #[serde(default = "default_max_tags_per_post")]
+    pub max_tags_per_post: usize,
+    /// Skip activities whose serialized body has more bytes
+    pub max_body_size: Option<usize>,
+    /// Drop posts that consist of nothing but hashtags and mentions
+    #[serde(default)]
+    pub require_text: bool,
+    /// Recently relayed posts to remember for deduplication
+    #[serde(default = "default_dedup_cache_size")]
+    pub dedup_cache_size: usize,
+    /// Domains to neither relay from nor deliver to
+    #[serde(default)]
+    blocklist: DomainList,
+    /// File with additional blocked domains, one per line
+    blocklist_file: Option<String>,
+    /// If present, the only domains to deliver to and to serve
+    /// instance relays for
+    allowlist: Option<DomainList>,
+    /// If present, the only domains whose posts get relayed
+    source_allowlist: Option<DomainList>,
+    /// If not empty, the only tags to serve relays for
+    #[serde(default)]
+    allowed_tags: Vec<String>,
+    /// If present, only relay posts in these languages
+    pub allowed_languages: Option<HashSet<String>>,
+    /// Relay posts without language if `allowed_languages` is set
+    #[serde(default = "default_true")]
+    pub allow_unknown_language: bool,
+    /// Seconds to remember Announces for relaying deletes
+    #[serde(default = "default_delivery_log_ttl")]
+    delivery_log_ttl: u64,
+    /// Seconds until an outgoing HTTP request is aborted
+    #[serde(default = "default_http_timeout")]
+    http_timeout: u64,
+    /// Seconds to keep unused connections open for reuse
+    #[serde(default = "default_pool_idle_timeout")]
+    pool_idle_timeout: u64,
+    /// Unused connections to keep open per host. As every worker sends
+    /// to its host sequentially, one is enough, especially with HTTP/2.
+    #[serde(default = "default_pool_max_idle_per_host")]
+    pub pool_max_idle_per_host: usize,
+    /// HTTP or SOCKS5 proxy URL for outgoing requests
+    proxy: Option<String>,
+    /// Hosts to reach without the proxy
+    #[serde(default)]
+    no_proxy: Vec<String>,
+    /// Overrides the User-Agent of outgoing requests
+    user_agent: Option<String>,
+    /// List the followers of relay actors instead of only counting them
+    #[serde(default)]
+    pub expose_followers: bool,
+    /// Followers per page of the collection
+    #[serde(default = "default_followers_page_size")]
+    pub followers_page_size: NonZeroUsize,
+    /// Avatar of relay actors, absolute or relative to `hostname`
+    #[serde(default = "default_icon")]
+    icon: String,
+    /// Overrides `icon` for tag relays
+    tag_icon: Option<String>,
+    /// Overrides `icon` for instance relays
+    instance_icon: Option<String>,
+    /// Overrides `icon` for account relays
+    account_icon: Option<String>,
+    /// Jobs queued per inbox host before dropping, must not be 0
+    #[serde(default = "default_worker_queue_size")]
+    pub worker_queue_size: NonZeroUsize,
+    /// Delivery attempts per job before it is abandoned, must not be 0
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: NonZeroU32,
+    /// Look up the software of inbox hosts in their NodeInfo, if
+    /// `sign_created_software` lists any
+    #[serde(default)]
+    pub detect_software: bool,
+    /// Software, by NodeInfo name, to sign for with `(created)` first
+    /// with `detect_software`
+    #[serde(default)]
+    pub sign_created_software: Vec<String>,
+    /// Send deliveries over HTTP, or publish them to NATS
+    #[serde(default)]
+    pub delivery_backend: DeliveryBackend,
+    /// NATS server with `delivery_backend: nats`
+    #[serde(default = "default_nats_url")]
+    pub nats_url: String,
+    /// NATS subject to publish deliveries to
+    #[serde(default = "default_nats_subject")]
+    pub nats_subject: String,
+    /// What to do when a worker queue is full
+    #[serde(default)]
+    pub delivery_mode: DeliveryMode,
+    /// Seconds to wait for room in a full queue with `delivery_mode: block`
+    #[serde(default = "default_block_timeout")]
+    block_timeout: u64,
+    /// Seconds to wait for queued jobs on shutdown before spooling them
+    #[serde(default = "default_shutdown_grace")]
+    shutdown_grace: u64,
+    /// Recent activities to list in each actor's outbox
+    #[serde(default = "default_outbox_size")]
+    pub outbox_size: usize,
+    /// Log deliveries instead of sending them
+    #[serde(default)]
+    pub dry_run: bool,
+    /// Log output, overridden by the `LOG_FORMAT` environment variable
+    #[serde(default)]
+    log_format: LogFormat,
+    /// Profile text of tag relays, `{tag}` gets substituted
+    #[serde(default = "default_tag_summary")]
+    pub tag_summary: String,
+    /// Profile text of instance relays, `{host}` gets substituted
+    #[serde(default = "default_instance_summary")]
+    pub instance_summary: String,
+    /// Profile text of account relays, `{account}` gets substituted
+    #[serde(default = "default_account_summary")]
+    pub account_summary: String,
+    /// Serve the firehose actor at `/relay` that relays every post
+    #[serde(default)]
+    pub firehose: bool,
+    /// Profile text of the firehose actor, `{host}` gets substituted
+    #[serde(default = "default_firehose_summary")]
+    pub firehose_summary: String,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SensitivePolicy {
+    #[default]
+    Pass,
+    ExcludeSensitive,
+    OnlySensitive,
+}
+
+/// Actor types that software recognizes as automated, spelled as in
+/// ActivityStreams
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub enum ActorType {
+    Application,
+    #[default]
+    Service,
+}
+
+impl ActorType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ActorType::Application => "Application",
+            ActorType::Service => "Service",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RelayKind {
+    Instance,
+    /// Includes tag sets
+    Tag,
+    Account,
+    Firehose,
+}
+
+impl From<&ActorKind> for RelayKind {
+    fn from(kind: &ActorKind) -> Self {
+        match kind {
+            ActorKind::InstanceRelay(_) => RelayKind::Instance,
+            ActorKind::TagRelay(_) | ActorKind::TagSetRelay(_) | ActorKind::TagPrefixRelay(_) => RelayKind::Tag,
+            ActorKind::AccountRelay(_) => RelayKind::Account,
+            ActorKind::Firehose => RelayKind::Firehose,
+        }
+    }
+}
+
+impl RelayKind {
+    /// By the first path segment of an actor URI that this relay
+    /// generated
+    pub fn from_actor_uri(actor_uri: &str) -> Option<Self> {
+        let url = reqwest::Url::parse(actor_uri).ok()?;
+        match url.path_segments()?.next()? {
+            "instance" => Some(RelayKind::Instance),
+            "tag" => Some(RelayKind::Tag),
+            "account" => Some(RelayKind::Account),
+            FIREHOSE_USERNAME => Some(RelayKind::Firehose),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryMode {
+    /// Drop (or spool) the job, never delaying the stream
+    #[default]
+    Drop,
+    /// Delay the stream until there is room, up to `block_timeout`
+    Block,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryBackend {
+    /// POST to the inboxes
+    #[default]
+    Http,
+    /// Publish the signed requests to `nats_subject`
+    Nats,
+}
+
+/// Public key that is advertised but not signed with
+#[derive(Debug, Clone, Deserialize)]
+pub struct ExtraPubKey {
+    /// Fragment of the key id, unique per actor
+    pub name: String,
+    pub pub_key_file: String,
+}
+
+/// Keypair for the actors of one kind of relays
+#[derive(Debug, Clone, Deserialize)]
+pub struct KindKeys {
+    /// Fragment of the key id
+    #[serde(default = "default_key_name")]
+    pub key_name: String,
+    pub priv_key_file: String,
+    pub pub_key_file: String,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    /// Human-readable
+    #[default]
+    Text,
+    Json,
+}
+
+impl SensitivePolicy {
+    pub fn allows(self, sensitive: bool) -> bool {
+        match self {
+            SensitivePolicy::Pass => true,
+            SensitivePolicy::ExcludeSensitive => ! sensitive,
+            SensitivePolicy::OnlySensitive => sensitive,
+        }
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_key_name() -> String {
+    "key".to_string()
+}
+
+fn default_spool_ttl() -> u64 {
+    3600
+}
+
+fn default_stream_heartbeat_timeout() -> u64 {
+    60
+}
+
+fn default_max_tags_per_post() -> usize {
+    32
+}
+
+fn default_dedup_cache_size() -> usize {
+    10_000
+}
+
+fn default_delivery_log_ttl() -> u64 {
+    7 * 24 * 3600
+}
+
+fn default_icon() -> String {
+    "https://fedi.buzz/assets/favicon48.png".to_string()
+}
+
+fn default_tag_summary() -> String {
+    "Relay for #{tag}, follow to receive public posts tagged #{tag}".to_string()
+}
+
+fn default_instance_summary() -> String {
+    "Relay mirroring public posts from {host}".to_string()
+}
+
+fn default_account_summary() -> String {
+    "Relay for the public posts of @{account}".to_string()
+}
+
+fn default_firehose_summary() -> String {
+    "Relay for all public posts that {host} receives".to_string()
+}
+
+fn default_db_pool_size() -> NonZeroUsize {
+    NonZeroUsize::new(4).unwrap()
+}
+
+fn default_db_connect_timeout() -> u64 {
+    10
+}
+
+fn default_worker_queue_size() -> NonZeroUsize {
+    NonZeroUsize::new(1024).unwrap()
+}
+
+fn default_listen_port() -> u16 {
+    3000
+}
+
+fn default_followers_page_size() -> NonZeroUsize {
+    NonZeroUsize::new(100).unwrap()
+}
+
+fn default_max_attempts() -> NonZeroU32 {
+    NonZeroU32::new(10).unwrap()
+}
+
+fn default_nats_url() -> String {
+    "nats://localhost:4222".to_string()
+}
+
+fn default_nats_subject() -> String {
+    "buzzrelay.deliveries".to_string()
+}
+
+fn default_block_timeout() -> u64 {
+    5
+}
+
+fn default_outbox_size() -> usize {
+    20
+}
+
+fn default_shutdown_grace() -> u64 {
+    30
+}
+
+/// With the security vocabulary for `publicKey` that signatures
+/// refer to
+fn default_jsonld_context() -> Vec<serde_json::Value> {
+    vec![
+        "https://www.w3.org/ns/activitystreams".into(),
+        "https://w3id.org/security/v1".into(),
+    ]
+}
+
+fn default_post_duration_buckets() -> Vec<f64> {
+    vec![0.0001, 0.001, 0.01, 0.1, 1.0, 5.0]
+}
+
+fn default_http_timeout() -> u64 {
+    15
+}
+
+fn default_pool_idle_timeout() -> u64 {
+    90
+}
+
+fn default_pool_max_idle_per_host() -> usize {
+    1
+}
+
+/// Prefix of environment variables that override top-level keys,
+/// like `BUZZRELAY_DB` for `db`
+const ENV_PREFIX: &str = "BUZZRELAY_";
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("read {0}: {1}")]
+    Read(String, std::io::Error),
+    #[error("parse: {0}")]
+    Parse(#[from] serde_yaml::Error),
+    #[error("parse ${0}: {1}")]
+    Env(String, serde_yaml::Error),
+    #[error("{0}")]
+    Invalid(String),
+}
+
+/// Sets top-level keys from `BUZZRELAY_<KEY>` variables, whose values
+/// are YAML like in the file
+fn apply_env(doc: &mut serde_yaml::Value, vars: impl IntoIterator<Item = (String, String)>) -> Result<(), ConfigError> {
+    if doc.is_null() {
+        *doc = serde_yaml::Mapping::new().into();
+    }
+    let Some(mapping) = doc.as_mapping_mut() else {
+        // reported on deserializing
+        return Ok(());
+    };
+    for (name, value) in vars {
+        let Some(key) = name.strip_prefix(ENV_PREFIX) else {
+            continue;
+        };
+        let value = serde_yaml::from_str(&value)
+            .map_err(|e| ConfigError::Env(name.clone(), e))?;
+        mapping.insert(key.to_lowercase().into(), value);
+    }
+    Ok(())
+}
+
+/// Where the HTTP server listens
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Listen {
+    Tcp(SocketAddr),
+    /// Unix domain socket, for a reverse proxy on the same host
+    Unix(PathBuf),
+}
+
+impl std::fmt::Display for Listen {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Listen::Tcp(addr) => write!(f, "{}", addr),
+            Listen::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// A bare domain, optionally with a port, as it goes into actor URIs
+fn check_hostname(hostname: &str) -> Result<(), ConfigError> {
+    let invalid = |reason| Err(ConfigError::Invalid(format!("hostname {:?}: {}", hostname, reason)));
+    if hostname.is_empty() {
+        return invalid("must not be empty");
+    }
+    if hostname.contains("://") {
+        return invalid("must not include a scheme, like relay.example");
+    }
+    match reqwest::Url::parse(&format!("https://{}/", hostname)) {
+        Ok(url) if url.authority() == hostname && url.path() == "/" && url.domain().is_some() =>
+            Ok(()),
+        _ =>
+            invalid("must be a lowercase domain, like relay.example"),
+    }
 }
 
 impl Config {
-    pub fn load(config_file: &str) -> Config {
+    /// Reads the file, applies environment overrides, and checks the
+    /// result
+    pub fn from_file(config_file: &str) -> Result<Config, ConfigError> {
         let data = std::fs::read_to_string(config_file)
-            .expect("read config");
-        serde_yaml::from_str(&data)
-            .expect("parse config")
+            .map_err(|e| ConfigError::Read(config_file.to_string(), e))?;
+        let vars = std::env::vars_os()
+            .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)));
+        let config = Config::parse(&data, vars)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn parse(data: &str, vars: impl IntoIterator<Item = (String, String)>) -> Result<Config, ConfigError> {
+        let mut doc: serde_yaml::Value = serde_yaml::from_str(data)?;
+        apply_env(&mut doc, vars)?;
+        Ok(serde_yaml::from_value(doc)?)
+    }
+
+    /// Fails on what would otherwise only fail at the first use
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        check_hostname(&self.hostname)?;
+        self.db.parse::<tokio_postgres::Config>()
+            .map_err(|e| ConfigError::Invalid(format!("db: {}", e)))?;
+        KeyRing::load(self)?;
+        self.try_listen()?;
+        self.try_proxy()?;
+        Ok(())
+    }
+
+    pub fn listen(&self) -> Listen {
+        self.try_listen()
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    fn try_listen(&self) -> Result<Listen, ConfigError> {
+        let Some(listen) = &self.listen else {
+            return Ok(Listen::Tcp(SocketAddr::from(([127, 0, 0, 1], self.listen_port))));
+        };
+        if let Some(path) = listen.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err(ConfigError::Invalid("listen: unix: needs a socket path".to_string()));
+            }
+            return Ok(Listen::Unix(PathBuf::from(path)));
+        }
+        listen.parse()
+            .map(Listen::Tcp)
+            .map_err(|e| ConfigError::Invalid(format!("listen {:?}: {}, expected unix:<path> or <address>:<port>", listen, e)))
+    }
+
+    pub fn try_priv_key(&self) -> Result<PrivateKey, ConfigError> {
+        read_priv_key(&self.priv_key_file)
+    }
+
+    pub fn log_format(&self) -> LogFormat {
+        match std::env::var("LOG_FORMAT").as_deref() {
+            Ok("json") => LogFormat::Json,
+            Ok("text") => LogFormat::Text,
+            _ => self.log_format,
+        }
+    }
+
+    /// Minimum interval between requests to the same inbox host
+    pub fn min_request_interval(&self) -> Option<Duration> {
+        self.max_requests_per_second
+            .filter(|rps| *rps > 0.0)
+            .map(|rps| Duration::from_secs_f64(1.0 / rps))
+    }
+
+    pub fn stream_heartbeat_timeout(&self) -> Duration {
+        Duration::from_secs(self.stream_heartbeat_timeout)
+    }
+
+    pub fn block_timeout(&self) -> Duration {
+        Duration::from_secs(self.block_timeout)
+    }
+
+    pub fn shutdown_grace(&self) -> Duration {
+        Duration::from_secs(self.shutdown_grace)
+    }
+
+    pub fn spool_ttl(&self) -> Duration {
+        Duration::from_secs(self.spool_ttl)
+    }
+
+    pub fn delivery_log_ttl(&self) -> Duration {
+        Duration::from_secs(self.delivery_log_ttl)
+    }
+
+    pub fn db_connect_timeout(&self) -> Duration {
+        Duration::from_secs(self.db_connect_timeout)
+    }
+
+    pub fn db_max_lifetime(&self) -> Option<Duration> {
+        self.db_max_lifetime.map(Duration::from_secs)
+    }
+
+    pub fn http_timeout(&self) -> Duration {
+        Duration::from_secs(self.http_timeout)
+    }
+
+    pub fn pool_idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.pool_idle_timeout)
+    }
+
+    pub fn user_agent(&self) -> String {
+        self.user_agent.clone()
+            .unwrap_or_else(|| format!(
+                "{}/{} (+https://{})",
+                env!("CARGO_PKG_NAME"),
+                env!("CARGO_PKG_VERSION"),
+                self.hostname,
+            ))
+    }
+
+    pub fn proxy(&self) -> Option<reqwest::Proxy> {
+        self.try_proxy()
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    fn try_proxy(&self) -> Result<Option<reqwest::Proxy>, ConfigError> {
+        let Some(proxy) = &self.proxy else { return Ok(None); };
+        let proxy = reqwest::Proxy::all(proxy)
+            .map_err(|e| ConfigError::Invalid(format!("proxy {:?}: {}", proxy, e)))?;
+        Ok(Some(proxy.no_proxy(reqwest::NoProxy::from_string(&self.no_proxy.join(",")))))
+    }
+
+    /// Absolute icon URL for an actor
+    pub fn icon(&self, kind: &ActorKind) -> String {
+        let icon = match kind {
+            ActorKind::TagRelay(_) | ActorKind::TagSetRelay(_) | ActorKind::TagPrefixRelay(_) =>
+                self.tag_icon.as_ref(),
+            ActorKind::InstanceRelay(_) =>
+                self.instance_icon.as_ref(),
+            ActorKind::AccountRelay(_) =>
+                self.account_icon.as_ref(),
+            ActorKind::Firehose =>
+                None,
+        }.unwrap_or(&self.icon);
+        if icon.starts_with("https://") || icon.starts_with("http://") {
+            icon.to_string()
+        } else {
+            format!("https://{}/{}", self.hostname, icon.trim_start_matches('/'))
+        }
+    }
+
+    pub fn excludes_replies(&self, kind: &ActorKind) -> bool {
+        self.exclude_replies.contains(&kind.into())
+    }
+
+    /// Whether an actor may be followed with `allowed_tags`
+    pub fn accepts_follow(&self, kind: &ActorKind) -> bool {
+        self.allowed_tags()
+            .is_none_or(|allowed_tags| kind.tags_allowed(&allowed_tags))
+    }
+
+    pub fn address_filter(&self) -> AddressFilter {
+        AddressFilter::new(self.allowed_private_ranges.clone())
+    }
+
+    /// Sorted, the exporter requires at least one
+    pub fn post_duration_buckets(&self) -> Vec<f64> {
+        let mut buckets = self.post_duration_buckets.iter()
+            .cloned()
+            .filter(|bucket| bucket.is_finite())
+            .collect::<Vec<_>>();
+        if buckets.is_empty() {
+            return default_post_duration_buckets();
+        }
+        buckets.sort_by(f64::total_cmp);
+        buckets.dedup();
+        buckets
+    }
+
+    /// A single context is not wrapped in a list
+    pub fn jsonld_context(&self) -> serde_json::Value {
+        match self.jsonld_context.as_slice() {
+            [] => default_jsonld_context().into(),
+            [context] => context.clone(),
+            contexts => contexts.into(),
+        }
+    }
+
+    pub fn admin_token(&self) -> Option<&str> {
+        self.admin_token.as_deref()
+            .filter(|token| ! token.is_empty())
+    }
+
+    /// Normalized like tag actors, `None` if any tag is allowed
+    pub fn allowed_tags(&self) -> Option<HashSet<String>> {
+        (! self.allowed_tags.is_empty()).then(|| {
+            self.allowed_tags.iter()
+                .map(|tag| normalize_tag(tag.trim_start_matches('#')))
+                .collect()
+        })
+    }
+
+    pub fn blocklist(&self) -> DomainList {
+        self.try_blocklist()
+            .expect("read blocklist_file")
+    }
+
+    /// Rereads `blocklist_file`
+    pub fn try_blocklist(&self) -> Result<DomainList, std::io::Error> {
+        let mut blocklist = self.blocklist.clone();
+        if let Some(blocklist_file) = &self.blocklist_file {
+            let data = std::fs::read_to_string(blocklist_file)?;
+            blocklist.extend(data.lines());
+        }
+        Ok(blocklist)
+    }
+
+    pub fn allowlist(&self) -> Option<&DomainList> {
+        self.allowlist.as_ref()
+            .filter(|allowlist| ! allowlist.is_empty())
+    }
+
+    pub fn source_allowlist(&self) -> Option<&DomainList> {
+        self.source_allowlist.as_ref()
+            .filter(|source_allowlist| ! source_allowlist.is_empty())
+    }
+
+    /// Id of the key that `actor_uri` of a `kind` currently signs with
+    pub fn key_id(&self, kind: RelayKind, actor_uri: &str) -> String {
+        let key_name = self.kind_keys.get(&kind)
+            .map_or(&self.key_name, |keys| &keys.key_name);
+        format!("{}#{}", actor_uri, key_name)
+    }
+
+    pub fn kind_keys(&self) -> impl Iterator<Item = (&RelayKind, &KindKeys)> {
+        self.kind_keys.iter()
+    }
+
+    /// Key names and public keys to advertise for actors without
+    /// `kind_keys`, the signing key first
+    pub fn try_pub_keys(&self) -> Result<Vec<(String, PublicKey)>, ConfigError> {
+        let mut pub_keys = vec![(self.key_name.clone(), read_pub_key(&self.pub_key_file)?)];
+        for extra in &self.extra_pub_keys {
+            if pub_keys.iter().any(|(name, _)| *name == extra.name) {
+                return Err(ConfigError::Invalid(format!("duplicate key name {} in extra_pub_keys", extra.name)));
+            }
+            pub_keys.push((extra.name.clone(), read_pub_key(&extra.pub_key_file)?));
+        }
+        Ok(pub_keys)
+    }
+}
+
+pub fn read_priv_key(priv_key_file: &str) -> Result<PrivateKey, ConfigError> {
+    let data = std::fs::read_to_string(priv_key_file)
+        .map_err(|e| ConfigError::Read(format!("priv_key_file {}", priv_key_file), e))?;
+    PrivateKey::from_pem(data.as_bytes())
+        .map_err(|e| ConfigError::Invalid(format!("parse priv_key_file {}: {}", priv_key_file, e)))
+}
+
+pub fn read_pub_key(pub_key_file: &str) -> Result<PublicKey, ConfigError> {
+    let data = std::fs::read_to_string(pub_key_file)
+        .map_err(|e| ConfigError::Read(format!("pub_key_file {}", pub_key_file), e))?;
+    PublicKey::from_pem(data.as_bytes())
+        .map_err(|e| ConfigError::Invalid(format!("parse pub_key_file {}: {}", pub_key_file, e)))
+}
+
+#[cfg(test)]
+impl Config {
+    /// Minimal config with `extra` YAML
+    pub fn test(extra: &str) -> Config {
+        Config::try_test(extra).unwrap()
+    }
+
+    pub fn try_test(extra: &str) -> Result<Config, serde_yaml::Error> {
+        serde_yaml::from_str(&format!(r#"
+streams: []
+db: ""
+hostname: relay.example
+listen_port: 3000
+priv_key_file: private-key.pem
+pub_key_file: public-key.pem
+{}
+"#, extra))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn worker_queue_size() {
+        assert_eq!(Config::test("").worker_queue_size.get(), 1024);
+        assert_eq!(Config::test("worker_queue_size: 16").worker_queue_size.get(), 16);
+        assert!(Config::try_test("worker_queue_size: 0").is_err());
+    }
+
+    #[test]
+    fn listen() {
+        assert_eq!(Config::test("").listen(), Listen::Tcp(SocketAddr::from(([127, 0, 0, 1], 3000))));
+        assert_eq!(Config::test("listen: \"[::1]:8080\"").listen(), Listen::Tcp("[::1]:8080".parse().unwrap()));
+        let listen = Config::test("listen: unix:/run/buzzrelay.sock").listen();
+        assert_eq!(listen, Listen::Unix(PathBuf::from("/run/buzzrelay.sock")));
+        assert_eq!(listen.to_string(), "unix:/run/buzzrelay.sock");
+        assert!(Config::test("listen: \"unix:\"").try_listen().is_err());
+        assert!(Config::test("listen: localhost").try_listen().is_err());
+    }
+
+    #[test]
+    fn proxy() {
+        assert!(Config::test("").proxy().is_none());
+        assert!(Config::test("proxy: socks5h://127.0.0.1:9050").proxy().is_some());
+        let error = Config::test("proxy: \"http://[::1\"").try_proxy().unwrap_err().to_string();
+        assert!(error.starts_with("proxy "), "{}", error);
+    }
+
+    #[test]
+    fn followers_page_size() {
+        assert_eq!(Config::test("").followers_page_size.get(), 100);
+        assert_eq!(Config::test("followers_page_size: 20").followers_page_size.get(), 20);
+        assert!(Config::try_test("followers_page_size: 0").is_err());
+    }
+
+    #[test]
+    fn db_pool() {
+        let config = Config::test("");
+        assert_eq!(config.db_pool_size.get(), 4);
+        assert_eq!(config.db_connect_timeout(), Duration::from_secs(10));
+        assert_eq!(config.db_max_lifetime(), None);
+        let config = Config::test("db_max_lifetime: 600");
+        assert_eq!(config.db_max_lifetime(), Some(Duration::from_secs(600)));
+        assert!(Config::try_test("db_pool_size: 0").is_err());
+    }
+
+    #[test]
+    fn post_duration_buckets() {
+        assert_eq!(Config::test("").post_duration_buckets(), default_post_duration_buckets());
+        let config = Config::test("post_duration_buckets: [1, 0.5, 1]");
+        assert_eq!(config.post_duration_buckets(), vec![0.5, 1.0]);
+        let config = Config::test("post_duration_buckets: []");
+        assert_eq!(config.post_duration_buckets(), default_post_duration_buckets());
+    }
+
+    #[test]
+    fn jsonld_context() {
+        assert_eq!(Config::test("").jsonld_context(), serde_json::json!([
+            "https://www.w3.org/ns/activitystreams",
+            "https://w3id.org/security/v1",
+        ]));
+        let config = Config::test("jsonld_context: [\"https://www.w3.org/ns/activitystreams\"]");
+        assert_eq!(config.jsonld_context(), serde_json::json!("https://www.w3.org/ns/activitystreams"));
+        let config = Config::test(r#"
+jsonld_context:
+  - https://www.w3.org/ns/activitystreams
+  - toot: "http://joinmastodon.org/ns#"
+"#);
+        assert_eq!(config.jsonld_context()[1]["toot"], "http://joinmastodon.org/ns#");
+    }
+
+    #[test]
+    fn exclude_replies() {
+        let config = Config::test(r#"
+exclude_replies:
+  - instance
+"#);
+        assert!(config.excludes_replies(&ActorKind::InstanceRelay("example.com".to_string())));
+        assert!(! config.excludes_replies(&ActorKind::from_tag("rust")));
+    }
+
+    #[test]
+    fn actor_type() {
+        assert_eq!(Config::test("").actor_type, ActorType::Service);
+        assert_eq!(Config::test("actor_type: Application").actor_type, ActorType::Application);
+        assert!(Config::try_test("actor_type: Person").is_err());
+    }
+
+    #[test]
+    fn allowed_tags() {
+        let config = Config::test("");
+        assert!(config.allowed_tags().is_none());
+        assert!(config.accepts_follow(&ActorKind::from_tag("anything")));
+        assert!(config.accepts_follow(&ActorKind::TagPrefixRelay("any".to_string())));
+
+        let config = Config::test(r##"
+allowed_tags:
+  - Rust
+  - "#tokio"
+"##);
+        assert_eq!(config.allowed_tags(), Some(["rust".to_string(), "tokio".to_string()].into()));
+        assert!(config.accepts_follow(&ActorKind::from_tag("rust")));
+        assert!(! config.accepts_follow(&ActorKind::from_tag("python")));
+        assert!(config.accepts_follow(&ActorKind::from_tag_path("rust.tokio").unwrap()));
+        assert!(! config.accepts_follow(&ActorKind::from_tag_path("rust.python").unwrap()));
+        assert!(! config.accepts_follow(&ActorKind::from_tag_path("rus*").unwrap()));
+        assert!(config.accepts_follow(&ActorKind::InstanceRelay("example.com".to_string())));
+        assert!(config.accepts_follow(&ActorKind::Firehose));
+    }
+
+    #[test]
+    fn env_overrides() {
+        let yaml = "streams: []\ndb: \"\"\nhostname: relay.example\nlisten_port: 3000\npriv_key_file: a.pem\npub_key_file: b.pem\n";
+        let config = Config::parse(yaml, [
+            ("BUZZRELAY_HOSTNAME".to_string(), "other.example".to_string()),
+            ("BUZZRELAY_LISTEN_PORT".to_string(), "8080".to_string()),
+            ("BUZZRELAY_ALLOWED_TAGS".to_string(), "[rust, tokio]".to_string()),
+            ("HOSTNAME".to_string(), "ignored.example".to_string()),
+        ]).unwrap();
+        assert_eq!(config.hostname, "other.example");
+        assert_eq!(config.listen_port, 8080);
+        assert_eq!(config.allowed_tags, ["rust", "tokio"]);
+
+        assert!(matches!(
+            Config::parse(yaml, [("BUZZRELAY_LISTEN_PORT".to_string(), "[".to_string())]),
+            Err(ConfigError::Env(name, _)) if name == "BUZZRELAY_LISTEN_PORT"
+        ));
+        assert!(matches!(
+            Config::parse(yaml, [("BUZZRELAY_LISTEN_PORT".to_string(), "http".to_string())]),
+            Err(ConfigError::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn hostnames() {
+        assert!(check_hostname("relay.example").is_ok());
+        assert!(check_hostname("localhost:8080").is_ok());
+        assert!(check_hostname("").is_err());
+        assert!(check_hostname("https://relay.example").is_err());
+        assert!(check_hostname("relay.example/").is_err());
+        assert!(check_hostname("Relay.example").is_err());
+        assert!(check_hostname("relay example").is_err());
+        assert!(check_hostname("127.0.0.1").is_err());
+    }
+
+    #[test]
+    fn validate() {
+        // In a directory that is never created
+        let missing = std::env::temp_dir()
+            .join(format!("buzzrelay-test-{}-missing", std::process::id()))
+            .join("private-key.pem");
+        let mut config = Config::test("");
+        config.priv_key_file = missing.display().to_string();
+        let error = config.validate().unwrap_err().to_string();
+        assert!(error.contains(&format!("priv_key_file {}", missing.display())), "{}", error);
+
+        let mut config = Config::test("");
+        config.db = "host=localhost port=none".to_string();
+        let error = config.validate().unwrap_err().to_string();
+        assert!(error.starts_with("db: "), "{}", error);
+    }
+
+    #[test]
+    fn kind_keys() {
+        let config = Config::test(r#"
+kind_keys:
+  instance:
+    key_name: instance-key
+    priv_key_file: instance-private-key.pem
+    pub_key_file: instance-public-key.pem
+"#);
+        assert_eq!(config.key_id(RelayKind::Instance, "https://relay.example/instance/example.com"), "https://relay.example/instance/example.com#instance-key");
+        assert_eq!(config.key_id(RelayKind::Tag, "https://relay.example/tag/rust"), "https://relay.example/tag/rust#key");
+        assert_eq!(config.kind_keys().count(), 1);
+    }
+
+    #[test]
+    fn relay_kind_from_actor_uri() {
+        assert_eq!(RelayKind::from_actor_uri("https://relay.example/tag/rust"), Some(RelayKind::Tag));
+        assert_eq!(RelayKind::from_actor_uri("https://relay.example/tag/rust+golang"), Some(RelayKind::Tag));
+        assert_eq!(RelayKind::from_actor_uri("https://relay.example/instance/example.com"), Some(RelayKind::Instance));
+        assert_eq!(RelayKind::from_actor_uri("https://relay.example/account/alice@example.com"), Some(RelayKind::Account));
+        assert_eq!(RelayKind::from_actor_uri("https://relay.example/relay"), Some(RelayKind::Firehose));
+        assert_eq!(RelayKind::from_actor_uri("https://relay.example/actor/admin"), None);
+        assert_eq!(RelayKind::from_actor_uri("relay"), None);
     }
 
-    pub fn priv_key(&self) -> PrivateKey {
-        let data = std::fs::read_to_string(&self.priv_key_file)
-            .expect("read priv_key_file");
-        PrivateKey::from_pem(data.as_bytes())
-            .expect("priv_key")
+    #[test]
+    fn icon_default() {
+        let config = Config::test("");
+        assert_eq!(config.icon(&ActorKind::from_tag("rust")), default_icon());
     }
 
-    pub fn pub_key(&self) -> PublicKey {
-        let data = std::fs::read_to_string(&self.pub_key_file)
-            .expect("read pub_key_file");
-        PublicKey::from_pem(data.as_bytes())
-            .expect("pub_key")
+    #[test]
+    fn icon_overrides() {
+        let config = Config::test(r#"
+icon: /default.png
+instance_icon: https://cdn.example/instance.png
+"#);
+        assert_eq!(config.icon(&ActorKind::from_tag("rust")), "https://relay.example/default.png");
+        assert_eq!(config.icon(&ActorKind::InstanceRelay("example.social".to_string())), "https://cdn.example/instance.png");
     }
 }
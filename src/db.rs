@@ -1,110 +1,662 @@
-use std::{sync::Arc, time::Instant};
-use metrics::histogram;
+use std::{collections::HashMap, sync::{atomic::{AtomicUsize, Ordering}, Arc}, time::{Duration, Instant}};
+use futures::{Stream, StreamExt};
+use metrics::{histogram, increment_counter};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
 use tokio_postgres::{Client, Error, NoTls, Statement};
+use crate::config::Config;
 
 
 const CREATE_SCHEMA_COMMANDS: &[&str] = &[
     "CREATE TABLE IF NOT EXISTS follows (id TEXT NOT NULL, inbox TEXT NOT NULL, actor TEXT NOT NULL, UNIQUE (inbox, actor))",
     "CREATE INDEX IF NOT EXISTS follows_actor ON follows (actor) INCLUDE (inbox)",
+    "ALTER TABLE follows ADD COLUMN IF NOT EXISTS shared_inbox TEXT",
+    "CREATE INDEX IF NOT EXISTS follows_actor_id ON follows (actor, id)",
+    "CREATE TABLE IF NOT EXISTS announces (status_id TEXT NOT NULL, actor TEXT NOT NULL, key_id TEXT NOT NULL, announce_id TEXT NOT NULL, object TEXT NOT NULL, inboxes TEXT[] NOT NULL, created TIMESTAMPTZ NOT NULL DEFAULT now())",
+    // Status ids are local to the stream's host
+    "ALTER TABLE announces ADD COLUMN IF NOT EXISTS stream TEXT NOT NULL DEFAULT ''",
+    "DROP INDEX IF EXISTS announces_status_id",
+    "CREATE INDEX IF NOT EXISTS announces_stream_status_id ON announces (stream, status_id)",
+    "CREATE TABLE IF NOT EXISTS tag_sets (actor TEXT NOT NULL, tag TEXT NOT NULL, UNIQUE (actor, tag))",
+    "CREATE INDEX IF NOT EXISTS tag_sets_tag ON tag_sets (tag) INCLUDE (actor)",
+    "CREATE TABLE IF NOT EXISTS actor_stats (actor TEXT NOT NULL PRIMARY KEY, announces BIGINT NOT NULL, last_announce TIMESTAMPTZ NOT NULL)",
+    "CREATE TABLE IF NOT EXISTS actor_deliveries (actor TEXT NOT NULL PRIMARY KEY, last_delivery TIMESTAMPTZ NOT NULL)",
+    "CREATE TABLE IF NOT EXISTS spool (post_url TEXT NOT NULL, actor_id TEXT NOT NULL, inbox TEXT NOT NULL, key_id TEXT NOT NULL, body BYTEA NOT NULL, created TIMESTAMPTZ NOT NULL DEFAULT now())",
+    "ALTER TABLE spool ADD COLUMN IF NOT EXISTS attempts INTEGER NOT NULL DEFAULT 0",
 ];
 
+/// An Announce of a post by a relay actor
+pub struct Announce {
+    pub actor: String,
+    pub key_id: String,
+    pub announce_id: String,
+    pub object: String,
+    pub inboxes: Vec<String>,
+}
+
+/// A row of `follows`, for export and import
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Follow {
+    pub id: String,
+    pub inbox: String,
+    pub actor: String,
+    #[serde(default)]
+    pub shared_inbox: Option<String>,
+}
+
+/// A relay job persisted for later delivery
+pub struct SpooledJob {
+    pub post_url: String,
+    pub actor_id: String,
+    pub inbox: String,
+    pub body: Vec<u8>,
+    /// Delivery attempts before spooling
+    pub attempts: u32,
+}
+
 #[derive(Clone)]
 pub struct Database {
+    pool: Arc<Pool>,
+}
+
+/// Connections that get used round-robin, as each one pipelines
+/// concurrent queries anyway
+struct Pool {
+    pg_config: tokio_postgres::Config,
+    max_lifetime: Option<Duration>,
+    connections: Vec<Mutex<Option<Connection>>>,
+    next: AtomicUsize,
+}
+
+struct Connection {
     inner: Arc<DatabaseInner>,
+    created: Instant,
 }
 
 struct DatabaseInner {
     client: Client,
     add_follow: Statement,
-    del_follow: Statement,
-    get_following_inboxes: Statement,
+    remove_follower: Statement,
+    remove_inbox: Statement,
+    remove_gone_inbox: Statement,
+    get_following_inboxes_for_actors: Statement,
     get_follows_count: Statement,
     get_followers_count: Statement,
+    get_actor_followers_count: Statement,
+    get_actor_followers: Statement,
+    get_follows: Statement,
+    export_follows: Statement,
+    get_follow_actors: Statement,
+    merge_follows: Statement,
+    remove_tag_set: Statement,
+    add_announce: Statement,
+    prune_announces: Statement,
+    take_announces: Statement,
+    add_tag_set: Statement,
+    get_tag_sets: Statement,
+    get_tag_prefix_actors: Statement,
+    add_actor_stats: Statement,
+    get_top_actors: Statement,
+    get_idle_actors: Statement,
+    add_actor_deliveries: Statement,
+    get_actor_deliveries: Statement,
+    add_spool: Statement,
+    prune_spool: Statement,
+    take_spool: Statement,
+}
+
+async fn connect_client(pg_config: &tokio_postgres::Config) -> Result<Client, Error> {
+    let (client, connection) = pg_config.connect(NoTls).await?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            tracing::error!("postgresql: {}", e);
+        }
+    });
+    Ok(client)
+}
+
+impl DatabaseInner {
+    async fn connect(pg_config: &tokio_postgres::Config) -> Result<Self, Error> {
+        Self::prepare(connect_client(pg_config).await?).await
+    }
+
+    async fn prepare(client: Client) -> Result<Self, Error> {
+        let add_follow = client.prepare("INSERT INTO follows (id, inbox, actor, shared_inbox) VALUES ($1, $2, $3, $4) ON CONFLICT (inbox, actor) DO UPDATE SET id=EXCLUDED.id, shared_inbox=EXCLUDED.shared_inbox")
+            .await?;
+        let remove_follower = client.prepare("DELETE FROM follows WHERE (id=$1 OR inbox=$2) AND actor=$3")
+            .await?;
+        let remove_inbox = client.prepare("DELETE FROM follows WHERE inbox=$1")
+            .await?;
+        let remove_gone_inbox = client.prepare("DELETE FROM follows WHERE COALESCE(shared_inbox, inbox)=$1")
+            .await?;
+        let get_following_inboxes_for_actors = client.prepare("SELECT DISTINCT actor, COALESCE(shared_inbox, inbox) FROM follows WHERE actor = ANY($1)")
+            .await?;
+        let get_follows_count = client.prepare("SELECT COUNT(id) FROM follows")
+            .await?;
+        let get_followers_count = client.prepare("SELECT COUNT(DISTINCT id) FROM follows")
+            .await?;
+        let get_actor_followers_count = client.prepare("SELECT COUNT(DISTINCT id) FROM follows WHERE actor=$1")
+            .await?;
+        let get_actor_followers = client.prepare("SELECT DISTINCT id FROM follows WHERE actor=$1 AND id > $2 ORDER BY id LIMIT $3")
+            .await?;
+        let get_follows = client.prepare("SELECT id, inbox, actor FROM follows ORDER BY actor, inbox")
+            .await?;
+        let export_follows = client.prepare("SELECT id, inbox, actor, shared_inbox FROM follows")
+            .await?;
+        let get_follow_actors = client.prepare("SELECT DISTINCT actor FROM follows")
+            .await?;
+        let merge_follows = client.prepare("WITH moved AS (DELETE FROM follows WHERE actor=$1 RETURNING id, inbox, shared_inbox) INSERT INTO follows (id, inbox, actor, shared_inbox) SELECT id, inbox, $2, shared_inbox FROM moved ON CONFLICT (inbox, actor) DO NOTHING")
+            .await?;
+        let remove_tag_set = client.prepare("DELETE FROM tag_sets WHERE actor=$1")
+            .await?;
+
+        let add_announce = client.prepare("INSERT INTO announces (stream, status_id, actor, key_id, announce_id, object, inboxes) VALUES ($1, $2, $3, $4, $5, $6, $7)")
+            .await?;
+        let prune_announces = client.prepare("DELETE FROM announces WHERE created < now() - make_interval(secs => $1)")
+            .await?;
+        let take_announces = client.prepare("DELETE FROM announces WHERE stream=$1 AND status_id=$2 RETURNING actor, key_id, announce_id, object, inboxes")
+            .await?;
+        let add_tag_set = client.prepare("INSERT INTO tag_sets (actor, tag) SELECT $1, unnest($2::TEXT[]) ON CONFLICT DO NOTHING")
+            .await?;
+        let get_tag_sets = client.prepare("SELECT DISTINCT actor FROM tag_sets WHERE tag = ANY($1)")
+            .await?;
+        let get_tag_prefix_actors = client.prepare("SELECT DISTINCT actor FROM follows WHERE actor LIKE '%*'")
+            .await?;
+        let add_actor_stats = client.prepare("INSERT INTO actor_stats (actor, announces, last_announce) SELECT unnest($1::TEXT[]), unnest($2::BIGINT[]), now() ON CONFLICT (actor) DO UPDATE SET announces=actor_stats.announces+EXCLUDED.announces, last_announce=EXCLUDED.last_announce")
+            .await?;
+        let get_top_actors = client.prepare("SELECT actor, announces, EXTRACT(EPOCH FROM last_announce)::BIGINT FROM actor_stats ORDER BY announces DESC LIMIT $1")
+            .await?;
+        let get_idle_actors = client.prepare("SELECT DISTINCT follows.actor FROM follows LEFT JOIN actor_stats ON actor_stats.actor=follows.actor WHERE actor_stats.last_announce IS NULL OR actor_stats.last_announce < now() - make_interval(days => $1) ORDER BY follows.actor")
+            .await?;
+        let add_actor_deliveries = client.prepare("INSERT INTO actor_deliveries (actor, last_delivery) SELECT unnest($1::TEXT[]), now() ON CONFLICT (actor) DO UPDATE SET last_delivery=EXCLUDED.last_delivery")
+            .await?;
+        let get_actor_deliveries = client.prepare("SELECT actor, EXTRACT(EPOCH FROM last_delivery)::BIGINT FROM actor_deliveries ORDER BY actor")
+            .await?;
+        let add_spool = client.prepare("INSERT INTO spool (post_url, actor_id, inbox, key_id, body, attempts) VALUES ($1, $2, $3, $4, $5, $6)")
+            .await?;
+        let prune_spool = client.prepare("DELETE FROM spool WHERE created < now() - make_interval(secs => $1)")
+            .await?;
+        let take_spool = client.prepare("DELETE FROM spool RETURNING post_url, actor_id, inbox, body, attempts")
+            .await?;
+
+        Ok(DatabaseInner {
+            client,
+            add_follow,
+            remove_follower,
+            remove_inbox,
+            remove_gone_inbox,
+            get_following_inboxes_for_actors,
+            get_follows_count,
+            get_followers_count,
+            get_actor_followers_count,
+            get_actor_followers,
+            get_follows,
+            export_follows,
+            get_follow_actors,
+            merge_follows,
+            remove_tag_set,
+            add_announce,
+            prune_announces,
+            take_announces,
+            add_tag_set,
+            get_tag_sets,
+            get_tag_prefix_actors,
+            add_actor_stats,
+            get_top_actors,
+            get_idle_actors,
+            add_actor_deliveries,
+            get_actor_deliveries,
+            add_spool,
+            prune_spool,
+            take_spool,
+        })
+    }
 }
 
 impl Database {
-    pub async fn connect(conn_str: &str) -> Self {
-        let (client, connection) = tokio_postgres::connect(conn_str, NoTls)
+    /// Connects the first of `db_pool_size` connections and creates
+    /// the schema, the others connect on demand
+    pub async fn connect(config: &Config) -> Self {
+        let mut pg_config = config.db.parse::<tokio_postgres::Config>()
+            .expect("db");
+        if pg_config.get_connect_timeout().is_none() {
+            pg_config.connect_timeout(config.db_connect_timeout());
+        }
+
+        let client = connect_client(&pg_config)
             .await
             .unwrap();
-
-        tokio::spawn(async move {
-            if let Err(e) = connection.await {
-                tracing::error!("postgresql: {}", e);
-            }
-        });
-
         for command in CREATE_SCHEMA_COMMANDS {
             client.execute(*command, &[])
                 .await
                 .unwrap();
         }
-        let add_follow = client.prepare("INSERT INTO follows (id, inbox, actor) VALUES ($1, $2, $3)")
-            .await
-            .unwrap();
-        let del_follow = client.prepare("DELETE FROM follows WHERE id=$1 AND actor=$2")
-            .await
-            .unwrap();
-        let get_following_inboxes = client.prepare("SELECT DISTINCT inbox FROM follows WHERE actor=$1")
-            .await
-            .unwrap();
-        let get_follows_count = client.prepare("SELECT COUNT(id) FROM follows")
-            .await
-            .unwrap();
-        let get_followers_count = client.prepare("SELECT COUNT(DISTINCT id) FROM follows")
+        let first = DatabaseInner::prepare(client)
             .await
             .unwrap();
 
+        let mut connections = vec![Mutex::new(Some(Connection {
+            inner: Arc::new(first),
+            created: Instant::now(),
+        }))];
+        connections.resize_with(config.db_pool_size.get(), || Mutex::new(None));
         Database {
-            inner: Arc::new(DatabaseInner {
-                client,
-                add_follow,
-                del_follow,
-                get_following_inboxes,
-                get_follows_count,
-                get_followers_count,
+            pool: Arc::new(Pool {
+                pg_config,
+                max_lifetime: config.db_max_lifetime(),
+                connections,
+                next: AtomicUsize::new(0),
             }),
         }
     }
 
-    pub async fn add_follow(&self, id: &str, inbox: &str, actor: &str) -> Result<(), Error> {
+    /// Next connection of the pool, replacing it if it has been closed,
+    /// for example by a database restart, or is too old
+    async fn conn(&self) -> Result<Arc<DatabaseInner>, Error> {
+        let pool = &self.pool;
+        let index = pool.next.fetch_add(1, Ordering::Relaxed) % pool.connections.len();
+        let mut slot = pool.connections[index].lock().await;
+        if let Some(connection) = slot.as_ref() {
+            let expired = pool.max_lifetime
+                .is_some_and(|max_lifetime| connection.created.elapsed() >= max_lifetime);
+            if ! connection.inner.client.is_closed() && ! expired {
+                return Ok(connection.inner.clone());
+            }
+            tracing::info!(index, expired, "reconnecting to postgresql");
+            increment_counter!("postgres_reconnects_total");
+        }
+        *slot = None;
+        let inner = Arc::new(DatabaseInner::connect(&pool.pg_config).await?);
+        *slot = Some(Connection {
+            inner: inner.clone(),
+            created: Instant::now(),
+        });
+        Ok(inner)
+    }
+
+    pub async fn ping(&self) -> Result<(), Error> {
+        let db = self.conn().await?;
+        db.client.simple_query("SELECT 1")
+            .await?;
+        Ok(())
+    }
+
+    /// Idempotent: a repeated Follow only refreshes the existing row
+    pub async fn add_follow(&self, id: &str, inbox: &str, actor: &str, shared_inbox: Option<&str>) -> Result<(), Error> {
+        let db = self.conn().await?;
         let t1 = Instant::now();
-        self.inner.client.execute(&self.inner.add_follow, &[&id, &inbox, &actor])
+        db.client.execute(&db.add_follow, &[&id, &inbox, &actor, &shared_inbox])
             .await?;
         let t2 = Instant::now();
         histogram!("postgres_query_duration", t2 - t1, "query" => "add_follow");
         Ok(())
     }
 
-    pub async fn del_follow(&self, id: &str, actor: &str) -> Result<(), Error> {
+    /// Returns the number of removed follows, 0 if there were none
+    pub async fn remove_follower(&self, id: &str, inbox: &str, actor: &str) -> Result<u64, Error> {
+        let db = self.conn().await?;
         let t1 = Instant::now();
-        self.inner.client.execute(&self.inner.del_follow, &[&id, &actor])
+        let count = db.client.execute(&db.remove_follower, &[&id, &inbox, &actor])
             .await?;
         let t2 = Instant::now();
-        histogram!("postgres_query_duration", t2 - t1, "query" => "del_follow");
-        Ok(())
+        histogram!("postgres_query_duration", t2 - t1, "query" => "remove_follower");
+        Ok(count)
     }
 
-    pub async fn get_following_inboxes(&self, actor: &str) -> Result<impl Iterator<Item = String>, Error> {
+    pub async fn remove_inbox(&self, inbox: &str) -> Result<u64, Error> {
+        let db = self.conn().await?;
         let t1 = Instant::now();
-        let rows = self.inner.client.query(&self.inner.get_following_inboxes, &[&actor])
+        let count = db.client.execute(&db.remove_inbox, &[&inbox])
             .await?;
         let t2 = Instant::now();
-        histogram!("postgres_query_duration", t2 - t1, "query" => "get_following_inboxes");
-        Ok(rows.into_iter()
-           .map(|row| row.get(0))
-        )
+        histogram!("postgres_query_duration", t2 - t1, "query" => "remove_inbox");
+        Ok(count)
+    }
+
+    /// Removes the follows that get delivered to `inbox`: those
+    /// without a shared inbox by their inbox, the others by their
+    /// shared inbox.
+    pub async fn remove_gone_inbox(&self, inbox: &str) -> Result<u64, Error> {
+        let db = self.conn().await?;
+        let t1 = Instant::now();
+        let count = db.client.execute(&db.remove_gone_inbox, &[&inbox])
+            .await?;
+        let t2 = Instant::now();
+        histogram!("postgres_query_duration", t2 - t1, "query" => "remove_gone_inbox");
+        Ok(count)
+    }
+
+    /// Inboxes of several actors in one query, by actor
+    pub async fn get_following_inboxes_for_actors(&self, actors: &[Arc<String>]) -> Result<HashMap<String, Vec<String>>, Error> {
+        let db = self.conn().await?;
+        let actors = actors.iter()
+            .map(|actor| actor.as_str())
+            .collect::<Vec<_>>();
+        let t1 = Instant::now();
+        let rows = db.client.query(&db.get_following_inboxes_for_actors, &[&actors])
+            .await?;
+        let t2 = Instant::now();
+        histogram!("postgres_query_duration", t2 - t1, "query" => "get_following_inboxes_for_actors");
+        let mut inboxes: HashMap<String, Vec<String>> = HashMap::new();
+        for row in rows {
+            inboxes.entry(row.get(0))
+                .or_default()
+                .push(row.get(1));
+        }
+        Ok(inboxes)
     }
 
     pub async fn get_follows_count(&self) -> Result<i64, Error> {
-        let row = self.inner.client.query_one(&self.inner.get_follows_count, &[])
+        let db = self.conn().await?;
+        let row = db.client.query_one(&db.get_follows_count, &[])
             .await?;
         Ok(row.get(0))
     }
 
     pub async fn get_followers_count(&self) -> Result<i64, Error> {
-        let row = self.inner.client.query_one(&self.inner.get_followers_count, &[])
+        let db = self.conn().await?;
+        let row = db.client.query_one(&db.get_followers_count, &[])
             .await?;
         Ok(row.get(0))
     }
+
+    pub async fn get_actor_followers_count(&self, actor: &str) -> Result<i64, Error> {
+        let db = self.conn().await?;
+        let row = db.client.query_one(&db.get_actor_followers_count, &[&actor])
+            .await?;
+        Ok(row.get(0))
+    }
+
+    /// Follower ids in order, starting after `after`, which is empty for
+    /// the first page. Unlike an offset, this key neither skips nor
+    /// repeats followers when others come or go between pages.
+    pub async fn get_actor_followers(&self, actor: &str, after: &str, limit: i64) -> Result<Vec<String>, Error> {
+        let db = self.conn().await?;
+        let t1 = Instant::now();
+        let rows = db.client.query(&db.get_actor_followers, &[&actor, &after, &limit])
+            .await?;
+        let t2 = Instant::now();
+        histogram!("postgres_query_duration", t2 - t1, "query" => "get_actor_followers");
+        Ok(rows.into_iter()
+           .map(|row| row.get(0))
+           .collect())
+    }
+
+    /// All follows as `(id, inbox, actor)`
+    pub async fn get_follows(&self) -> Result<Vec<(String, String, String)>, Error> {
+        let db = self.conn().await?;
+        let rows = db.client.query(&db.get_follows, &[])
+            .await?;
+        Ok(rows.into_iter()
+           .map(|row| (row.get(0), row.get(1), row.get(2)))
+           .collect())
+    }
+
+    /// Streams all follows without loading them into memory
+    pub async fn export_follows(&self) -> Result<impl Stream<Item = Result<Follow, Error>>, Error> {
+        let db = self.conn().await?;
+        let rows = db.client.query_raw(&db.export_follows, std::iter::empty::<&str>())
+            .await?;
+        Ok(rows.map(|row| row.map(|row| Follow {
+            id: row.get(0),
+            inbox: row.get(1),
+            actor: row.get(2),
+            shared_inbox: row.get(3),
+        })))
+    }
+
+    /// All actors that have followers
+    pub async fn get_follow_actors(&self) -> Result<Vec<String>, Error> {
+        let db = self.conn().await?;
+        let rows = db.client.query(&db.get_follow_actors, &[])
+            .await?;
+        Ok(rows.into_iter()
+           .map(|row| row.get(0))
+           .collect())
+    }
+
+    /// Moves the follows of actor `from` to actor `to`, dropping those
+    /// of inboxes that follow both, and forgets the tags of `from` if
+    /// it is a tag set. Returns the number of moved follows.
+    pub async fn merge_actor(&self, from: &str, to: &str) -> Result<u64, Error> {
+        let db = self.conn().await?;
+        let count = db.client.execute(&db.merge_follows, &[&from, &to])
+            .await?;
+        db.client.execute(&db.remove_tag_set, &[&from])
+            .await?;
+        Ok(count)
+    }
+
+    /// `status_id` as in the stream from host `stream`
+    pub async fn add_announce(&self, stream: &str, status_id: &str, announce: &Announce) -> Result<(), Error> {
+        let db = self.conn().await?;
+        let t1 = Instant::now();
+        db.client.execute(&db.add_announce, &[
+            &stream, &status_id,
+            &announce.actor, &announce.key_id, &announce.announce_id,
+            &announce.object, &announce.inboxes,
+        ])
+            .await?;
+        let t2 = Instant::now();
+        histogram!("postgres_query_duration", t2 - t1, "query" => "add_announce");
+        Ok(())
+    }
+
+    pub async fn prune_announces(&self, ttl: Duration) -> Result<u64, Error> {
+        let db = self.conn().await?;
+        db.client.execute(&db.prune_announces, &[&ttl.as_secs_f64()])
+            .await
+    }
+
+    pub async fn take_announces(&self, stream: &str, status_id: &str) -> Result<impl Iterator<Item = Announce>, Error> {
+        let db = self.conn().await?;
+        let rows = db.client.query(&db.take_announces, &[&stream, &status_id])
+            .await?;
+        Ok(rows.into_iter()
+           .map(|row| Announce {
+               actor: row.get(0),
+               key_id: row.get(1),
+               announce_id: row.get(2),
+               object: row.get(3),
+               inboxes: row.get(4),
+           })
+        )
+    }
+
+    /// Registers the tags of a followed tag set actor
+    pub async fn add_tag_set(&self, actor: &str, tags: &[String]) -> Result<(), Error> {
+        let db = self.conn().await?;
+        db.client.execute(&db.add_tag_set, &[&actor, &tags])
+            .await?;
+        Ok(())
+    }
+
+    /// Tag set actors that include any of `tags`
+    pub async fn get_tag_sets(&self, tags: &[String]) -> Result<impl Iterator<Item = String>, Error> {
+        let db = self.conn().await?;
+        let t1 = Instant::now();
+        let rows = db.client.query(&db.get_tag_sets, &[&tags])
+            .await?;
+        let t2 = Instant::now();
+        histogram!("postgres_query_duration", t2 - t1, "query" => "get_tag_sets");
+        Ok(rows.into_iter()
+           .map(|row| row.get(0))
+        )
+    }
+
+    /// Followed actors that end like tag prefix actors
+    pub async fn get_tag_prefix_actors(&self) -> Result<impl Iterator<Item = String>, Error> {
+        let db = self.conn().await?;
+        let rows = db.client.query(&db.get_tag_prefix_actors, &[])
+            .await?;
+        Ok(rows.into_iter()
+           .map(|row| row.get(0))
+        )
+    }
+
+    /// Adds to the counts of Announces per actor
+    pub async fn add_actor_stats(&self, actors: &[String], announces: &[i64]) -> Result<(), Error> {
+        let db = self.conn().await?;
+        let t1 = Instant::now();
+        db.client.execute(&db.add_actor_stats, &[&actors, &announces])
+            .await?;
+        let t2 = Instant::now();
+        histogram!("postgres_query_duration", t2 - t1, "query" => "add_actor_stats");
+        Ok(())
+    }
+
+    /// The actors with the most Announces as `(actor, announces,
+    /// last_announce)` with the time in seconds since the epoch
+    pub async fn get_top_actors(&self, limit: i64) -> Result<Vec<(String, i64, i64)>, Error> {
+        let db = self.conn().await?;
+        let rows = db.client.query(&db.get_top_actors, &[&limit])
+            .await?;
+        Ok(rows.into_iter()
+           .map(|row| (row.get(0), row.get(1), row.get(2)))
+           .collect())
+    }
+
+    /// Followed actors without any Announces for `days`
+    pub async fn get_idle_actors(&self, days: i32) -> Result<Vec<String>, Error> {
+        let db = self.conn().await?;
+        let rows = db.client.query(&db.get_idle_actors, &[&days])
+            .await?;
+        Ok(rows.into_iter()
+           .map(|row| row.get(0))
+           .collect())
+    }
+
+    /// Sets the last delivery of these actors to now
+    pub async fn add_actor_deliveries(&self, actors: &[String]) -> Result<(), Error> {
+        let db = self.conn().await?;
+        let t1 = Instant::now();
+        db.client.execute(&db.add_actor_deliveries, &[&actors])
+            .await?;
+        let t2 = Instant::now();
+        histogram!("postgres_query_duration", t2 - t1, "query" => "add_actor_deliveries");
+        Ok(())
+    }
+
+    /// `(actor, last_delivery)` with the time in seconds since the
+    /// epoch
+    pub async fn get_actor_deliveries(&self) -> Result<Vec<(String, i64)>, Error> {
+        let db = self.conn().await?;
+        let rows = db.client.query(&db.get_actor_deliveries, &[])
+            .await?;
+        Ok(rows.into_iter()
+           .map(|row| (row.get(0), row.get(1)))
+           .collect())
+    }
+
+    pub async fn add_spool(&self, post_url: &str, actor_id: &str, inbox: &str, key_id: &str, body: &[u8], attempts: u32) -> Result<(), Error> {
+        let db = self.conn().await?;
+        let t1 = Instant::now();
+        db.client.execute(&db.add_spool, &[&post_url, &actor_id, &inbox, &key_id, &body, &(attempts as i32)])
+            .await?;
+        let t2 = Instant::now();
+        histogram!("postgres_query_duration", t2 - t1, "query" => "add_spool");
+        Ok(())
+    }
+
+    pub async fn prune_spool(&self, ttl: Duration) -> Result<u64, Error> {
+        let db = self.conn().await?;
+        db.client.execute(&db.prune_spool, &[&ttl.as_secs_f64()])
+            .await
+    }
+
+    pub async fn take_spool(&self) -> Result<impl Iterator<Item = SpooledJob>, Error> {
+        let db = self.conn().await?;
+        let rows = db.client.query(&db.take_spool, &[])
+            .await?;
+        Ok(rows.into_iter()
+           .map(|row| SpooledJob {
+               post_url: row.get(0),
+               actor_id: row.get(1),
+               inbox: row.get(2),
+               body: row.get(3),
+               attempts: row.get::<_, i32>(4) as u32,
+           })
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rand::Rng;
+    use crate::mock_inbox::test_database;
+
+    #[tokio::test]
+    #[ignore = "requires PostgreSQL at TEST_DATABASE"]
+    async fn remove_gone_inbox() {
+        let database = test_database().await;
+        // Distinct per run in a shared database
+        let host = format!("test{}.example", rand::thread_rng().gen::<u32>());
+        let actor = format!("https://relay.example/instance/{}", host);
+        let url = |path: &str| format!("https://{}{}", host, path);
+        database.add_follow(&url("/users/a"), &url("/users/a/inbox"), &actor, Some(&url("/inbox"))).await.unwrap();
+        database.add_follow(&url("/users/b"), &url("/users/b/inbox"), &actor, Some(&url("/inbox"))).await.unwrap();
+        // Followed before shared inboxes were stored
+        database.add_follow(&url("/users/c"), &url("/users/c/inbox"), &actor, None).await.unwrap();
+
+        // Deliveries go to the shared inbox
+        assert_eq!(database.remove_gone_inbox(&url("/users/a/inbox")).await.unwrap(), 0);
+        assert_eq!(database.remove_inbox(&url("/inbox")).await.unwrap(), 0);
+        assert_eq!(database.remove_gone_inbox(&url("/users/c/inbox")).await.unwrap(), 1);
+        assert_eq!(database.remove_gone_inbox(&url("/inbox")).await.unwrap(), 2);
+        let inboxes = database.get_following_inboxes_for_actors(&[std::sync::Arc::new(actor)]).await.unwrap();
+        assert!(inboxes.is_empty(), "{:?}", inboxes);
+    }
+
+    #[tokio::test]
+    #[ignore = "requires PostgreSQL at TEST_DATABASE"]
+    async fn get_actor_followers() {
+        let database = test_database().await;
+        let host = format!("test{}.example", rand::thread_rng().gen::<u32>());
+        let actor = format!("https://relay.example/instance/{}", host);
+        let url = |path: &str| format!("https://{}{}", host, path);
+        for user in ["a", "b", "c"] {
+            database.add_follow(&url(&format!("/users/{}", user)), &url(&format!("/users/{}/inbox", user)), &actor, None).await.unwrap();
+        }
+        // One follower with another inbox
+        database.add_follow(&url("/users/b"), &url("/inbox"), &actor, None).await.unwrap();
+
+        assert_eq!(database.get_actor_followers_count(&actor).await.unwrap(), 3);
+        assert_eq!(database.get_actor_followers(&actor, "", 2).await.unwrap(), [url("/users/a"), url("/users/b")]);
+        assert_eq!(database.get_actor_followers(&actor, &url("/users/a"), 2).await.unwrap(), [url("/users/b"), url("/users/c")]);
+        assert_eq!(database.get_actor_followers(&actor, &url("/users/b"), 2).await.unwrap(), [url("/users/c")]);
+        assert!(database.get_actor_followers(&actor, &url("/users/c"), 2).await.unwrap().is_empty());
+
+        for inbox in ["/users/a/inbox", "/users/b/inbox", "/users/c/inbox", "/inbox"] {
+            database.remove_inbox(&url(inbox)).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "requires PostgreSQL at TEST_DATABASE"]
+    async fn spool_keeps_attempts() {
+        let database = test_database().await;
+        let inbox = format!("https://test{}.example/inbox", rand::thread_rng().gen::<u32>());
+        database.add_spool("https://example.com/@alice/1", "https://relay.example/tag/rust", &inbox, "https://relay.example/tag/rust#key", b"{}", 3).await.unwrap();
+        let spooled = database.take_spool().await.unwrap()
+            .find(|job| job.inbox == inbox)
+            .unwrap();
+        assert_eq!(spooled.attempts, 3);
+        assert_eq!(spooled.body, b"{}");
+    }
+
+    #[tokio::test]
+    #[ignore = "requires PostgreSQL at TEST_DATABASE"]
+    async fn remove_inbox() {
+        let database = test_database().await;
+        let host = format!("test{}.example", rand::thread_rng().gen::<u32>());
+        let actor = format!("https://relay.example/instance/{}", host);
+        let url = |path: &str| format!("https://{}{}", host, path);
+        database.add_follow(&url("/users/a"), &url("/users/a/inbox"), &actor, Some(&url("/inbox"))).await.unwrap();
+        database.add_follow(&url("/users/b"), &url("/users/b/inbox"), &actor, Some(&url("/inbox"))).await.unwrap();
+
+        assert_eq!(database.remove_inbox(&url("/users/a/inbox")).await.unwrap(), 1);
+        let inboxes = database.get_following_inboxes_for_actors(&[std::sync::Arc::new(actor.clone())]).await.unwrap();
+        assert_eq!(inboxes[&actor], [url("/inbox")]);
+        assert_eq!(database.remove_inbox(&url("/users/b/inbox")).await.unwrap(), 1);
+    }
 }
@@ -18,3 +18,19 @@ pub fn generate_header(body: &[u8]) -> Result<String, ()> {
 
     Ok(digest_header)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn empty_body() {
+        assert_eq!(generate_header(&[]).unwrap(), "SHA-256=47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=");
+    }
+
+    #[test]
+    fn standard_base64() {
+        // contains a '/', which must not get URL_SAFE encoded
+        assert_eq!(generate_header(br#"{"type":"Follow"}"#).unwrap(), "SHA-256=GYwYnH3BiO6aICFt0ThC5bUIJ4byvqdpWtR8m5fNkww=");
+    }
+}
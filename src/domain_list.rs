@@ -0,0 +1,110 @@
+use std::{collections::HashSet, sync::{Arc, RwLock}};
+use serde::Deserialize;
+
+/// A set of domains, matching `example.com` exactly and
+/// `*.example.com` on that domain and all of its subdomains
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(from = "Vec<String>")]
+pub struct DomainList {
+    exact: HashSet<String>,
+    suffixes: Vec<String>,
+}
+
+impl From<Vec<String>> for DomainList {
+    fn from(domains: Vec<String>) -> Self {
+        let mut list = DomainList::default();
+        list.extend(domains);
+        list
+    }
+}
+
+impl DomainList {
+    pub fn extend(&mut self, domains: impl IntoIterator<Item = impl AsRef<str>>) {
+        for domain in domains {
+            let domain = domain.as_ref().trim().to_lowercase();
+            if domain.is_empty() || domain.starts_with('#') {
+                continue;
+            }
+            if let Some(suffix) = domain.strip_prefix("*.") {
+                self.suffixes.push(suffix.to_string());
+            } else {
+                self.exact.insert(domain);
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.exact.is_empty() && self.suffixes.is_empty()
+    }
+
+    pub fn contains(&self, host: &str) -> bool {
+        let host = host.to_lowercase();
+        let host = host.trim_end_matches('.');
+        self.exact.contains(host) ||
+            self.suffixes.iter().any(|suffix| {
+                host == suffix ||
+                    (host.ends_with(suffix.as_str()) &&
+                     host[..host.len() - suffix.len()].ends_with('.'))
+            })
+    }
+}
+
+/// A `DomainList` that can be replaced while others check against it,
+/// like the blocklist that both the inbox and the relay consult
+#[derive(Debug, Clone, Default)]
+pub struct SharedDomainList(Arc<RwLock<DomainList>>);
+
+impl SharedDomainList {
+    pub fn new(list: DomainList) -> Self {
+        SharedDomainList(Arc::new(RwLock::new(list)))
+    }
+
+    pub fn contains(&self, host: &str) -> bool {
+        self.0.read().unwrap()
+            .contains(host)
+    }
+
+    pub fn replace(&self, list: DomainList) {
+        *self.0.write().unwrap() = list;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn exact() {
+        let list = DomainList::from(vec!["example.com".to_string()]);
+        assert!(list.contains("example.com"));
+        assert!(list.contains("Example.COM"));
+        assert!(!list.contains("sub.example.com"));
+        assert!(!list.contains("example.org"));
+    }
+
+    #[test]
+    fn suffix() {
+        let list = DomainList::from(vec!["*.example.com".to_string()]);
+        assert!(list.contains("example.com"));
+        assert!(list.contains("sub.example.com"));
+        assert!(list.contains("a.b.example.com"));
+        assert!(!list.contains("badexample.com"));
+        assert!(!list.contains("example.com.evil"));
+    }
+
+    #[test]
+    fn comments() {
+        let list = DomainList::from(vec!["# spam".to_string(), "".to_string()]);
+        assert!(list.is_empty());
+    }
+
+    #[test]
+    fn shared() {
+        let list = SharedDomainList::new(DomainList::from(vec!["example.com".to_string()]));
+        let clone = list.clone();
+        assert!(clone.contains("example.com"));
+        list.replace(DomainList::from(vec!["example.org".to_string()]));
+        assert!(!clone.contains("example.com"));
+        assert!(clone.contains("example.org"));
+    }
+}
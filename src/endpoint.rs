@@ -1,4 +1,8 @@
-use std::sync::Arc;
+use std::{
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
 use axum::{
     async_trait,
@@ -15,6 +19,20 @@ use sigh::{Signature, PublicKey, Key, PrivateKey};
 use crate::fetch::authorized_fetch;
 use crate::activitypub::Actor;
 use crate::error::Error;
+use crate::resolver::AddressFilter;
+
+/// How long fetched actors (and their public keys) are trusted
+const ACTOR_CACHE_TTL: Duration = Duration::from_secs(3600);
+const ACTOR_CACHE_SIZE: usize = 4096;
+/// How long to not refetch an actor that could not be fetched
+const ACTOR_FAILURE_TTL: Duration = Duration::from_secs(60);
+/// Limit actor fetches to ACTOR_FETCHES_PER_WINDOW per host
+const ACTOR_FETCH_WINDOW: Duration = Duration::from_secs(60);
+const ACTOR_FETCHES_PER_WINDOW: u32 = 30;
+
+/// How far the signed `Date:` may be off, as in Mastodon, so that
+/// captured requests cannot be replayed later
+const DATE_SKEW: Duration = Duration::from_secs(12 * 3600);
 
 const SIGNATURE_HEADERS_REQUIRED: &[&str] = &[
     "(request-target)",
@@ -25,7 +43,7 @@ const SIGNATURE_HEADERS_REQUIRED: &[&str] = &[
 pub struct Endpoint<'a> {
     pub payload: serde_json::Value,
     signature: Signature<'a>,
-    remote_actor_uri: String,
+    pub remote_actor_uri: String,
 }
 
 #[async_trait]
@@ -49,8 +67,8 @@ where
             } else {
                 return Err((StatusCode::UNSUPPORTED_MEDIA_TYPE, "No content-type".to_string()));
             };
-        if ! content_type.starts_with("application/json") &&
-            ! (content_type.starts_with("application/") && content_type.ends_with("+json"))
+        if ! (content_type.starts_with("application/json") ||
+              (content_type.starts_with("application/") && content_type.ends_with("+json")))
         {
             return Err((StatusCode::UNSUPPORTED_MEDIA_TYPE, "Invalid content-type".to_string()));
         }
@@ -64,6 +82,11 @@ where
                 return Err((StatusCode::BAD_REQUEST, format!("Header {:?} not signed", header)));
             }
         }
+        let date = req.headers().get("date")
+            .and_then(|value| value.to_str().ok())
+            .ok_or((StatusCode::BAD_REQUEST, "Missing Date: header".to_string()))?;
+        check_date(date, chrono::Utc::now())
+            .map_err(|e| (StatusCode::UNAUTHORIZED, e))?;
 
         // parse digest
         let mut digest_header: String = req.headers().get("digest")
@@ -100,22 +123,205 @@ where
     }
 }
 
+/// Whether the HTTP `date` is within `DATE_SKEW` of `now`
+fn check_date(date: &str, now: chrono::DateTime<chrono::Utc>) -> Result<(), String> {
+    let date = chrono::DateTime::parse_from_rfc2822(date)
+        .map_err(|e| format!("Cannot parse Date: header: {}", e))?;
+    let skew = (now - date.with_timezone(&chrono::Utc)).num_seconds().unsigned_abs();
+    if skew > DATE_SKEW.as_secs() {
+        return Err("Date: header too far from now".to_string());
+    }
+    Ok(())
+}
+
+/// Remote actors by id, so that not every request needs a fetch
+#[derive(Clone)]
+pub struct ActorCache {
+    inner: Arc<Mutex<ActorCacheInner>>,
+}
+
+struct ActorCacheInner {
+    actors: lru::LruCache<String, (Instant, Actor)>,
+    /// When fetching an actor last failed
+    failures: lru::LruCache<String, Instant>,
+    /// Start of the current rate limit window and fetches in it
+    host_fetches: lru::LruCache<String, (Instant, u32)>,
+}
+
+impl ActorCache {
+    pub fn new() -> Self {
+        let size = NonZeroUsize::new(ACTOR_CACHE_SIZE).unwrap();
+        ActorCache {
+            inner: Arc::new(Mutex::new(ActorCacheInner {
+                actors: lru::LruCache::new(size),
+                failures: lru::LruCache::new(size),
+                host_fetches: lru::LruCache::new(size),
+            })),
+        }
+    }
+
+    fn get(&self, id: &str) -> Option<Actor> {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.actors.get(id) {
+            Some((fetched, actor)) if fetched.elapsed() < ACTOR_CACHE_TTL =>
+                Some(actor.clone()),
+            Some(_) => {
+                inner.actors.pop(id);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn insert(&self, id: &str, actor: Actor) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.failures.pop(id);
+        inner.actors.put(id.to_string(), (Instant::now(), actor));
+    }
+
+    fn remove(&self, id: &str) {
+        self.inner.lock().unwrap()
+            .actors.pop(id);
+    }
+
+    /// Checks the negative cache and the rate limit of the actor's
+    /// host before fetching
+    fn may_fetch(&self, id: &str) -> Result<(), Error> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.failures.get(id)
+            .is_some_and(|failed| failed.elapsed() < ACTOR_FAILURE_TTL)
+        {
+            return Err(Error::FetchFailedRecently);
+        }
+
+        let host = reqwest::Url::parse(id)
+            .map_err(|_| Error::InvalidUri)?
+            .host_str()
+            .ok_or(Error::InvalidUri)?
+            .to_string();
+        let now = Instant::now();
+        let fetches = inner.host_fetches.get_or_insert_mut(host, || (now, 0));
+        if fetches.0.elapsed() >= ACTOR_FETCH_WINDOW {
+            *fetches = (now, 0);
+        }
+        if fetches.1 >= ACTOR_FETCHES_PER_WINDOW {
+            return Err(Error::FetchRateLimited);
+        }
+        fetches.1 += 1;
+        Ok(())
+    }
+
+    fn failed(&self, id: &str) {
+        self.inner.lock().unwrap()
+            .failures.put(id.to_string(), Instant::now());
+    }
+}
+
 impl<'a> Endpoint<'a> {
     /// Validates the requesting actor
     pub async fn remote_actor(
         &self,
         client: &reqwest::Client,
+        filter: &AddressFilter,
+        cache: &ActorCache,
         key_id: &str,
         private_key: &PrivateKey,
     ) -> Result<Actor, Error> {
-        let remote_actor: Actor = serde_json::from_value(
-            authorized_fetch(client, &self.remote_actor_uri, key_id, private_key).await?
-        )?;
-        let public_key = PublicKey::from_pem(remote_actor.public_key.pem.as_bytes())?;
+        if let Some(remote_actor) = cache.get(&self.remote_actor_uri) {
+            if self.verify(&remote_actor).is_ok() {
+                return Ok(remote_actor);
+            }
+            // the key may have been rotated, refetch once
+            cache.remove(&self.remote_actor_uri);
+        }
+
+        cache.may_fetch(&self.remote_actor_uri)?;
+        let remote_actor: Actor = match authorized_fetch(client, filter, &self.remote_actor_uri, key_id, private_key).await
+            .and_then(|value| Ok(serde_json::from_value(value)?))
+        {
+            Ok(remote_actor) => remote_actor,
+            Err(e) => {
+                cache.failed(&self.remote_actor_uri);
+                return Err(e);
+            }
+        };
+        self.verify(&remote_actor)?;
+        cache.insert(&self.remote_actor_uri, remote_actor.clone());
+
+        Ok(remote_actor)
+    }
+
+    fn verify(&self, remote_actor: &Actor) -> Result<(), Error> {
+        let Some(remote_key) = self.signature.key_id()
+            .and_then(|key_id| remote_actor.find_public_key(key_id))
+        else {
+            return Err(Error::SignatureFail);
+        };
+        let public_key = PublicKey::from_pem(remote_key.pem.as_bytes())?;
         if ! (self.signature.verify(&public_key)?) {
             return Err(Error::SignatureFail);
         }
 
-        Ok(remote_actor)
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn actor_fetch_rate_limit() {
+        let cache = ActorCache::new();
+        for i in 0..ACTOR_FETCHES_PER_WINDOW {
+            assert!(cache.may_fetch(&format!("https://example.com/users/{}", i)).is_ok());
+        }
+        assert!(matches!(cache.may_fetch("https://example.com/users/alice"), Err(Error::FetchRateLimited)));
+        assert!(cache.may_fetch("https://example.net/users/alice").is_ok());
+    }
+
+    #[test]
+    fn dates() {
+        let now = chrono::DateTime::parse_from_rfc2822("Sun, 06 Nov 1994 08:49:37 GMT").unwrap()
+            .with_timezone(&chrono::Utc);
+        assert!(check_date("Sun, 06 Nov 1994 08:49:37 GMT", now).is_ok());
+        assert!(check_date("Sun, 06 Nov 1994 20:49:37 GMT", now).is_ok());
+        assert!(check_date("Sat, 05 Nov 1994 20:49:37 GMT", now).is_ok());
+        assert!(check_date("Sun, 06 Nov 1994 20:49:38 GMT", now).is_err());
+        assert!(check_date("Sat, 05 Nov 1994 20:49:36 GMT", now).is_err());
+        assert!(check_date("yesterday", now).is_err());
+    }
+
+    #[tokio::test]
+    async fn stale_date() {
+        let body = r#"{"type":"Follow","actor":"https://example.com/actor"}"#;
+        let request = |date: String| Request::builder()
+            .method("POST")
+            .uri("/relay")
+            .header("host", "relay.example")
+            .header("content-type", "application/activity+json")
+            .header("date", date)
+            .header("digest", crate::digest::generate_header(body.as_bytes()).unwrap())
+            .header("signature", r#"keyId="https://example.com/actor#main-key",algorithm="rsa-sha256",headers="(request-target) host date digest",signature="AAAA""#)
+            .body(axum::body::Body::from(body))
+            .unwrap();
+        let client = Arc::new(reqwest::Client::new());
+
+        let endpoint = Endpoint::from_request(request(crate::send::http_date()), &client).await;
+        assert_eq!(endpoint.ok().map(|endpoint| endpoint.remote_actor_uri).as_deref(), Some("https://example.com/actor"));
+
+        let stale = (chrono::Utc::now() - chrono::Duration::days(1))
+            .format("%a, %d %b %Y %H:%M:%S GMT")
+            .to_string();
+        let rejection = Endpoint::from_request(request(stale), &client).await.err();
+        assert_eq!(rejection.map(|(status, _)| status), Some(StatusCode::UNAUTHORIZED));
+    }
+
+    #[test]
+    fn actor_fetch_failure() {
+        let cache = ActorCache::new();
+        cache.failed("https://example.com/users/alice");
+        assert!(matches!(cache.may_fetch("https://example.com/users/alice"), Err(Error::FetchFailedRecently)));
+        assert!(cache.may_fetch("https://example.com/users/bob").is_ok());
     }
 }
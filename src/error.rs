@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("HTTP Digest generation error")]
@@ -14,6 +16,20 @@ pub enum Error {
     Http(#[from] reqwest::Error),
     #[error("Invalid URI")]
     InvalidUri,
-    #[error("Error response from remote")]
-    Response(String),
+    #[error("Refusing to connect to a non-public address")]
+    BlockedAddress,
+    #[error("Invalid inbox: {0}")]
+    InvalidInbox(&'static str),
+    #[error("Error response from remote: HTTP {0}")]
+    Response(http::StatusCode, String),
+    #[error("Rate limited by remote")]
+    RateLimited(Option<Duration>),
+    #[error("Too many actor fetches from this host")]
+    FetchRateLimited,
+    #[error("Fetching the actor failed recently")]
+    FetchFailedRecently,
+    #[error("No NodeInfo link")]
+    NoNodeInfo,
+    #[error("Message queue error: {0}")]
+    Queue(String),
 }
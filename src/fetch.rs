@@ -1,17 +1,15 @@
 use http::StatusCode;
 use serde::de::DeserializeOwned;
-use sigh::{PrivateKey, SigningConfig, alg::RsaSha256};
-use crate::{digest, error::Error};
+use sigh::PrivateKey;
+use crate::{digest, error::Error, resolver::AddressFilter, send::{http_date, sign}};
 
-pub async fn authorized_fetch<T>(
-    client: &reqwest::Client,
+/// A GET request signed like a POST for instances in secure mode,
+/// which is why it has a `Digest:` of the empty body
+fn signed_get(
     uri: &str,
     key_id: &str,
     private_key: &PrivateKey,
-) -> Result<T, Error>
-where
-    T: DeserializeOwned,
-{
+) -> Result<http::Request<Vec<u8>>, Error> {
     let url = reqwest::Url::parse(uri)
         .map_err(|_| Error::InvalidUri)?;
     let host = format!("{}", url.host().ok_or(Error::InvalidUri)?);
@@ -21,19 +19,50 @@ where
         .uri(uri)
         .header("host", &host)
         .header("content-type", "application/activity+json")
-        .header("date", chrono::Utc::now().to_rfc2822()
-            .replace("+0000", "GMT"))
+        .header("date", http_date())
         .header("accept", "application/activity+json")
         .header("digest", digest_header)
         .body(vec![])?;
-    SigningConfig::new(RsaSha256, private_key, key_id)
-        .sign(&mut req)?;
+    sign(&mut req, key_id, private_key)?;
+    Ok(req)
+}
+
+pub async fn authorized_fetch<T>(
+    client: &reqwest::Client,
+    filter: &AddressFilter,
+    uri: &str,
+    key_id: &str,
+    private_key: &PrivateKey,
+) -> Result<T, Error>
+where
+    T: DeserializeOwned,
+{
+    let req = signed_get(uri, key_id, private_key)?;
     let req: reqwest::Request = req.try_into()?;
+    filter.check_url(req.url())?;
     let res = client.execute(req)
         .await?;
     if res.status() >= StatusCode::OK && res.status() < StatusCode::MULTIPLE_CHOICES {
         Ok(res.json().await?)
     } else {
-        Err(Error::Response(res.text().await?))
+        Err(Error::Response(res.status(), res.text().await?))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use sigh::{alg::{Algorithm, RsaSha256}, Signature};
+    use super::*;
+
+    #[test]
+    fn signed_get_verifies() {
+        let (private_key, public_key) = RsaSha256.generate_keys().unwrap();
+        let req = signed_get("https://example.com/users/alice", "https://relay.example/tag/rust#key", &private_key).unwrap();
+        assert_eq!(req.method(), http::Method::GET);
+        assert_eq!(req.headers()["host"], "example.com");
+        let signature = Signature::from(&req);
+        assert_eq!(signature.key_id(), Some("https://relay.example/tag/rust#key"));
+        assert!(signature.headers().unwrap().contains(&"(request-target)"));
+        assert!(signature.verify(&public_key).unwrap());
     }
 }
@@ -0,0 +1,139 @@
+//! Reports (`Flag` activities) that relay actors receive, passed on to
+//! `flag_forward_url`
+
+use std::{
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use metrics::increment_counter;
+use sigh::PrivateKey;
+use crate::{resolver::AddressFilter, send};
+
+/// Limit forwards to FORWARDS_PER_WINDOW per remote host
+const FORWARD_WINDOW: Duration = Duration::from_secs(3600);
+const FORWARDS_PER_WINDOW: u32 = 10;
+/// Remote hosts to remember
+const LIMITER_SIZE: usize = 4096;
+
+/// Forwards reports to the moderation endpoint, if configured
+#[derive(Clone)]
+pub struct Forwarder {
+    url: Option<String>,
+    client: Arc<reqwest::Client>,
+    address_filter: AddressFilter,
+    /// Start of the current rate limit window and forwards in it
+    host_forwards: Arc<Mutex<lru::LruCache<String, (Instant, u32)>>>,
+}
+
+impl Forwarder {
+    pub fn new(url: Option<String>, client: Arc<reqwest::Client>, address_filter: AddressFilter) -> Self {
+        Forwarder {
+            url,
+            client,
+            address_filter,
+            host_forwards: Arc::new(Mutex::new(lru::LruCache::new(
+                NonZeroUsize::new(LIMITER_SIZE).unwrap()
+            ))),
+        }
+    }
+
+    /// Whether `host` may have another report forwarded now, which
+    /// counts as forwarded if so
+    fn may_forward(&self, host: &str) -> bool {
+        let mut host_forwards = self.host_forwards.lock().unwrap();
+        let now = Instant::now();
+        let forwards = host_forwards.get_or_insert_mut(host.to_string(), || (now, 0));
+        if forwards.0.elapsed() >= FORWARD_WINDOW {
+            *forwards = (now, 0);
+        }
+        if forwards.1 >= FORWARDS_PER_WINDOW {
+            return false;
+        }
+        forwards.1 += 1;
+        true
+    }
+
+    /// Forwards a report from `remote_actor_host` in the background,
+    /// signed by the relay actor that received it, so that the
+    /// moderation endpoint can verify where it came from. Returns
+    /// whether it does.
+    pub fn forward(
+        &self,
+        remote_actor_host: &str,
+        key_id: String,
+        private_key: Arc<PrivateKey>,
+        flag: serde_json::Value,
+    ) -> bool {
+        let Some(url) = self.url.clone() else {
+            return false;
+        };
+        if ! self.may_forward(remote_actor_host) {
+            tracing::warn!(host = remote_actor_host, "too many reports, not forwarding");
+            increment_counter!("relay_flags_forwarded_total", "status" => "rate_limited");
+            return false;
+        }
+        let (client, address_filter) = (self.client.clone(), self.address_filter.clone());
+        tokio::spawn(async move {
+            match send::send(&client, &address_filter, &url, &key_id, &private_key, &flag).await {
+                Ok(()) =>
+                    increment_counter!("relay_flags_forwarded_total", "status" => "ok"),
+                Err(e) => {
+                    tracing::error!("forward flag to {}: {}", url, e);
+                    increment_counter!("relay_flags_forwarded_total", "status" => "error");
+                }
+            }
+        });
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+    use sigh::alg::{Algorithm, RsaSha256};
+    use crate::mock_inbox::MockInbox;
+    use super::*;
+
+    #[tokio::test]
+    async fn forward() {
+        let inbox = MockInbox::start().await;
+        let (private_key, public_key) = RsaSha256.generate_keys().unwrap();
+        let private_key = Arc::new(private_key);
+        let client = Arc::new(reqwest::Client::new());
+        let loopback = AddressFilter::new(vec!["127.0.0.0/8".parse().unwrap()]);
+        let flag = json!({
+            "type": "Flag",
+            "actor": "https://a.example/actor",
+            "object": ["https://b.example/users/spam"],
+        });
+
+        let forwarder = Forwarder::new(Some(inbox.url("/reports")), client.clone(), loopback.clone());
+        assert!(forwarder.forward("a.example", "https://relay.example/relay#key".to_string(), private_key.clone(), flag.clone()));
+        let received = inbox.wait_for(1, Duration::from_secs(10)).await;
+        assert_eq!(received[0].uri.path(), "/reports");
+        assert_eq!(received[0].json(), flag);
+        assert!(received[0].verify(&public_key));
+        assert_eq!(received[0].signature_key_id().as_deref(), Some("https://relay.example/relay#key"));
+
+        // Not once the host has sent too many
+        while forwarder.may_forward("a.example") {}
+        assert!(! forwarder.forward("a.example", "https://relay.example/relay#key".to_string(), private_key.clone(), flag.clone()));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(inbox.received().len(), 1);
+
+        // Not without a flag_forward_url
+        let forwarder = Forwarder::new(None, client, loopback);
+        assert!(! forwarder.forward("a.example", "https://relay.example/relay#key".to_string(), private_key, flag));
+    }
+
+    #[test]
+    fn rate_limit() {
+        let forwarder = Forwarder::new(None, Arc::new(reqwest::Client::new()), AddressFilter::new(vec![]));
+        for _ in 0..FORWARDS_PER_WINDOW {
+            assert!(forwarder.may_forward("a.example"));
+        }
+        assert!(! forwarder.may_forward("a.example"));
+        assert!(forwarder.may_forward("b.example"));
+    }
+}
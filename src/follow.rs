@@ -0,0 +1,75 @@
+//! Answers to `Follow` requests of relay actors
+
+use serde_json::json;
+use crate::{activitypub::Action, actor::Actor, domain_list::SharedDomainList};
+
+/// Whether to reject a follow because the follower's instance, or the
+/// instance of its inbox, is on the `blocklist`
+pub fn is_blocked(blocklist: &SharedDomainList, actor_host: &str, inbox: &str) -> bool {
+    let inbox_host = reqwest::Url::parse(inbox).ok()
+        .and_then(|url| url.host_str().map(str::to_string))
+        .unwrap_or_default();
+    blocklist.contains(actor_host) || blocklist.contains(&inbox_host)
+}
+
+/// The `Accept` or `Reject` by `actor` of the `follow` activity that
+/// `follower_id` sent
+pub fn response(
+    action_type: &str,
+    jsonld_context: serde_json::Value,
+    actor: &Actor,
+    follower_id: &str,
+    inbox: &str,
+    follow: serde_json::Value,
+) -> Action<serde_json::Value> {
+    Action {
+        jsonld_context,
+        action_type: action_type.to_string(),
+        actor: actor.uri(),
+        to: Some(json!(follower_id)),
+        id: format!(
+            "https://{}/activity/{}/{}/{}",
+            actor.host,
+            action_type.to_lowercase(),
+            urlencoding::encode(&actor.uri()),
+            urlencoding::encode(inbox),
+        ),
+        object: Some(follow),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use crate::{actor::ActorKind, domain_list::DomainList};
+    use super::*;
+
+    #[test]
+    fn blocked() {
+        let blocklist = SharedDomainList::new(DomainList::from(vec!["*.spam.example".to_string()]));
+        assert!(is_blocked(&blocklist, "spam.example", "https://other.example/inbox"));
+        assert!(is_blocked(&blocklist, "other.example", "https://mx.spam.example/inbox"));
+        assert!(! is_blocked(&blocklist, "other.example", "https://other.example/inbox"));
+        assert!(! is_blocked(&blocklist, "other.example", "not a url"));
+    }
+
+    #[test]
+    fn reject() {
+        let actor = Actor {
+            host: Arc::new("relay.example".to_string()),
+            kind: ActorKind::from_tag("rust"),
+        };
+        let follow = json!({
+            "type": "Follow",
+            "id": "https://spam.example/follows/1",
+        });
+        let reject = response("Reject", json!("https://www.w3.org/ns/activitystreams"), &actor, "https://spam.example/actor", "https://spam.example/inbox", follow.clone());
+        assert_eq!(reject.action_type, "Reject");
+        assert_eq!(reject.actor, "https://relay.example/tag/rust");
+        assert_eq!(reject.to, Some(json!("https://spam.example/actor")));
+        assert_eq!(reject.object, Some(follow.clone()));
+        assert_eq!(reject.id, "https://relay.example/activity/reject/https%3A%2F%2Frelay.example%2Ftag%2Frust/https%3A%2F%2Fspam.example%2Finbox");
+        let accept = response("Accept", json!("https://www.w3.org/ns/activitystreams"), &actor, "https://spam.example/actor", "https://spam.example/inbox", follow);
+        assert_ne!(accept.id, reject.id);
+    }
+}
@@ -0,0 +1,143 @@
+use std::{collections::HashMap, sync::Arc};
+use sigh::{Key, PrivateKey, PublicKey, Signature};
+use crate::{config::{self, Config, ConfigError, RelayKind}, send};
+
+/// Signing key of a group of actors, and the public keys that their
+/// actor documents advertise
+pub struct ActorKeys {
+    pub private_key: Arc<PrivateKey>,
+    /// Key names and public keys, the signing key first
+    pub public_keys: Vec<(String, PublicKey)>,
+}
+
+impl ActorKeys {
+    pub fn check(&self) -> Result<(), String> {
+        check(&self.private_key, &self.public_keys[0].1)
+    }
+}
+
+/// Keys by kind of relay, falling back to `priv_key_file` for kinds
+/// without `kind_keys`
+#[derive(Clone)]
+pub struct KeyRing {
+    default: Arc<ActorKeys>,
+    kinds: Arc<HashMap<RelayKind, ActorKeys>>,
+}
+
+impl KeyRing {
+    pub fn load(config: &Config) -> Result<Self, ConfigError> {
+        let default = ActorKeys {
+            private_key: Arc::new(config.try_priv_key()?),
+            public_keys: config.try_pub_keys()?,
+        };
+        let kinds = config.kind_keys()
+            .map(|(kind, keys)| Ok((*kind, ActorKeys {
+                private_key: Arc::new(config::read_priv_key(&keys.priv_key_file)?),
+                public_keys: vec![(keys.key_name.clone(), config::read_pub_key(&keys.pub_key_file)?)],
+            })))
+            .collect::<Result<_, ConfigError>>()?;
+        Ok(KeyRing {
+            default: Arc::new(default),
+            kinds: Arc::new(kinds),
+        })
+    }
+
+    /// With the default keypair of `Config::test()`, and `kinds`
+    #[cfg(test)]
+    pub fn test(default: (PrivateKey, PublicKey), kinds: impl IntoIterator<Item = (RelayKind, (PrivateKey, PublicKey))>) -> Self {
+        let actor_keys = |(private_key, public_key)| ActorKeys {
+            private_key: Arc::new(private_key),
+            public_keys: vec![("key".to_string(), public_key)],
+        };
+        KeyRing {
+            default: Arc::new(actor_keys(default)),
+            kinds: Arc::new(kinds.into_iter()
+                .map(|(kind, keys)| (kind, actor_keys(keys)))
+                .collect()),
+        }
+    }
+
+    pub fn get(&self, kind: impl Into<RelayKind>) -> &ActorKeys {
+        self.kinds.get(&kind.into())
+            .unwrap_or(&self.default)
+    }
+
+    /// `check()` for every keypair
+    pub fn check(&self) -> Result<(), String> {
+        self.default.check()?;
+        for (kind, keys) in self.kinds.iter() {
+            keys.check()
+                .map_err(|e| format!("kind_keys {:?}: {}", kind, e))?;
+        }
+        Ok(())
+    }
+
+    /// Every keypair in use, the default first, with the kind that
+    /// has it configured in `kind_keys`
+    pub fn keypairs(&self) -> impl Iterator<Item = (Option<RelayKind>, &ActorKeys)> {
+        std::iter::once((None, self.default.as_ref()))
+            .chain(self.kinds.iter().map(|(kind, keys)| (Some(*kind), keys)))
+    }
+}
+
+/// Verifies that `public_key`, which the actor documents advertise,
+/// belongs to `private_key`, which signs all requests. Otherwise every
+/// delivery would fail with HTTP 401.
+pub fn check(private_key: &PrivateKey, public_key: &PublicKey) -> Result<(), String> {
+    let derived = private_key.0.public_key_to_pem()
+        .map_err(|e| format!("deriving the public key: {}", e))?;
+    // As in `Actor::as_activitypub()`
+    let advertised = public_key.to_pem()
+        .map_err(|e| format!("encoding the public key: {}", e))?;
+    if derived != advertised.as_bytes() {
+        return Err("pub_key_file does not belong to priv_key_file".to_string());
+    }
+
+    let mut req = http::Request::builder()
+        .method("POST")
+        .uri("/inbox")
+        .header("host", "relay.example")
+        .header("date", send::http_date())
+        .body(())
+        .map_err(|e| format!("{}", e))?;
+    send::sign(&mut req, "self-check", private_key)
+        .map_err(|e| format!("signing: {}", e))?;
+    match Signature::from(&req).verify(public_key) {
+        Ok(true) => Ok(()),
+        Ok(false) => Err("signature does not verify with the public key".to_string()),
+        Err(e) => Err(format!("verifying: {}", e)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use sigh::alg::{Algorithm, Hs2019, RsaSha256};
+    use super::*;
+
+    #[test]
+    fn matching_keys() {
+        let (private_key, public_key) = RsaSha256.generate_keys().unwrap();
+        assert!(check(&private_key, &public_key).is_ok());
+        let (private_key, public_key) = Hs2019.generate_keys().unwrap();
+        assert!(check(&private_key, &public_key).is_ok());
+    }
+
+    #[test]
+    fn keypairs() {
+        let keys = KeyRing::test(
+            RsaSha256.generate_keys().unwrap(),
+            [(RelayKind::Instance, RsaSha256.generate_keys().unwrap())],
+        );
+        let kinds = keys.keypairs()
+            .map(|(kind, _)| kind)
+            .collect::<Vec<_>>();
+        assert_eq!(kinds, vec![None, Some(RelayKind::Instance)]);
+    }
+
+    #[test]
+    fn mismatched_keys() {
+        let (private_key, _) = RsaSha256.generate_keys().unwrap();
+        let (_, public_key) = RsaSha256.generate_keys().unwrap();
+        assert!(check(&private_key, &public_key).is_err());
+    }
+}
@@ -0,0 +1,123 @@
+//! Serving on a Unix domain socket, for a reverse proxy on the same
+//! host
+
+use std::{
+    future::Future,
+    io,
+    os::unix::fs::FileTypeExt,
+    path::Path,
+    pin::Pin,
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    net::{UnixListener, UnixStream},
+    time::Sleep,
+};
+
+/// How long to wait after a failed accept, like hyper's `AddrIncoming`,
+/// e.g. until file descriptors are available again
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Removes a socket file that a previous run left behind. Refuses to
+/// remove a socket that still accepts connections, or anything that
+/// is not a socket.
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if ! metadata.file_type().is_socket() {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} exists and is not a socket", path.display())));
+    }
+    match std::os::unix::net::UnixStream::connect(path) {
+        Ok(_) =>
+            Err(io::Error::new(io::ErrorKind::AddrInUse, format!("{} is in use by another process", path.display()))),
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+            tracing::info!("removing stale socket {}", path.display());
+            std::fs::remove_file(path)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+pub fn bind(path: &Path) -> io::Result<UnixListener> {
+    remove_stale_socket(path)?;
+    UnixListener::bind(path)
+}
+
+/// Connections for `axum::Server::builder()`
+pub fn accept(listener: UnixListener) -> impl hyper::server::accept::Accept<Conn = UnixStream, Error = io::Error> {
+    retry_accept(move |cx| {
+        listener.poll_accept(cx)
+            .map_ok(|(stream, _)| stream)
+    })
+}
+
+/// Logs errors of `poll_accept` and retries after `ACCEPT_RETRY_DELAY`,
+/// as hyper would stop serving on the first error
+fn retry_accept<C>(
+    mut poll_accept: impl FnMut(&mut Context<'_>) -> Poll<io::Result<C>>,
+) -> impl hyper::server::accept::Accept<Conn = C, Error = io::Error> {
+    let mut retry: Option<Pin<Box<Sleep>>> = None;
+    hyper::server::accept::poll_fn(move |cx| loop {
+        if let Some(delay) = retry.as_mut() {
+            ready!(delay.as_mut().poll(cx));
+            retry = None;
+        }
+        match ready!(poll_accept(cx)) {
+            Ok(conn) => return Poll::Ready(Some(Ok(conn))),
+            Err(e) => {
+                tracing::error!("accept: {}", e);
+                retry = Some(Box::pin(tokio::time::sleep(ACCEPT_RETRY_DELAY)));
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn socket_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir()
+            .join(format!("buzzrelay-test-{}-{}.sock", std::process::id(), name))
+    }
+
+    #[tokio::test]
+    async fn stale_socket() {
+        let path = socket_path("stale");
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+        let listener = bind(&path).unwrap();
+        assert!(bind(&path).is_err());
+        drop(listener);
+        std::fs::remove_file(&path).unwrap();
+        assert!(remove_stale_socket(&path).is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn accept_error() {
+        use hyper::server::accept::Accept;
+
+        let mut results = vec![
+            Err(io::Error::from_raw_os_error(24)), // EMFILE
+            Ok("conn"),
+        ].into_iter();
+        let mut accept = std::pin::pin!(retry_accept(move |_| Poll::Ready(results.next().unwrap())));
+        let start = tokio::time::Instant::now();
+        let conn = std::future::poll_fn(|cx| accept.as_mut().poll_accept(cx)).await;
+        assert_eq!(conn.unwrap().unwrap(), "conn");
+        assert!(start.elapsed() >= ACCEPT_RETRY_DELAY);
+    }
+
+    #[test]
+    fn not_a_socket() {
+        let path = socket_path("file");
+        std::fs::write(&path, "keep").unwrap();
+        assert!(remove_stale_socket(&path).is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "keep");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -5,14 +5,15 @@ use axum::{
     routing::get, Json, Router,
 };
 use axum_extra::routing::SpaRouter;
+use futures::FutureExt;
 use metrics::increment_counter;
 use metrics_util::MetricKindMask;
-use metrics_exporter_prometheus::PrometheusBuilder;
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder};
 use serde_json::json;
-use sigh::{PrivateKey, PublicKey};
-use std::{net::SocketAddr, sync::Arc, time::Duration, collections::HashMap};
+use std::{sync::{atomic::{AtomicBool, Ordering}, Arc}, time::Duration, collections::HashMap};
 use std::{panic, process};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use actor::ActorKind;
 
 mod error;
 mod config;
@@ -25,15 +26,42 @@ mod stream;
 mod relay;
 mod activitypub;
 mod endpoint;
+mod domain_list;
+mod flag;
+mod follow;
+mod outbox;
+mod admin;
+mod cli;
+mod inbox;
+mod resolver;
+mod keys;
+mod listen;
+mod queue;
+mod notify;
+mod nodeinfo;
+mod welcome;
+#[cfg(test)]
+mod mock_inbox;
 
 
 #[derive(Clone)]
 struct State {
     database: db::Database,
     client: Arc<reqwest::Client>,
+    actor_cache: endpoint::ActorCache,
+    stream_connected: Arc<AtomicBool>,
+    config: Arc<config::Config>,
+    outbox: outbox::Outbox,
+    commands: tokio::sync::mpsc::Sender<relay::Command>,
     hostname: Arc<String>,
-    priv_key: PrivateKey,
-    pub_key: PublicKey,
+    keys: keys::KeyRing,
+    /// Shared with the relay task, which reloads it
+    blocklist: domain_list::SharedDomainList,
+    /// Shared with the relay task, which reloads it
+    followed_prefixes: actor::FollowedPrefixes,
+    address_filter: resolver::AddressFilter,
+    welcomes: welcome::Limiter,
+    flags: flag::Forwarder,
 }
 
 
@@ -58,24 +86,32 @@ async fn webfinger(
             return StatusCode::NOT_FOUND.into_response();
         },
     };
-    let (target_kind, target_host) =
-        if resource.starts_with("acct:tag-") {
-            let off = "acct:tag-".len();
-            let at = resource.find('@');
-            (actor::ActorKind::TagRelay(resource[off..at.unwrap_or(resource.len())].to_string()),
-             at.map_or_else(|| state.hostname.clone(), |at| Arc::new(resource[at + 1..].to_string())))
-        } else if resource.starts_with("acct:instance-") {
-            let off = "acct:instance-".len();
-            let at = resource.find('@');
-            (actor::ActorKind::InstanceRelay(resource[off..at.unwrap_or(resource.len())].to_string()),
-             at.map_or_else(|| state.hostname.clone(), |at| Arc::new(resource[at + 1..].to_string())))
-        } else {
+    let acct = match resource.strip_prefix("acct:") {
+        Some(acct) => acct,
+        None => {
+            track_request("GET", "webfinger", "invalid");
+            return StatusCode::NOT_FOUND.into_response();
+        }
+    };
+    let (username, host) = match acct.rsplit_once('@') {
+        Some((username, host)) => (username, Some(host)),
+        None => (acct, None),
+    };
+    let target_kind = match ActorKind::from_username(username) {
+        Some(ActorKind::Firehose) if ! state.config.firehose => {
             track_request("GET", "webfinger", "not_found");
             return StatusCode::NOT_FOUND.into_response();
-        };
+        }
+        Some(target_kind) if host.is_none_or(|host| host.eq_ignore_ascii_case(&state.hostname)) =>
+            target_kind,
+        _ => {
+            track_request("GET", "webfinger", "not_found");
+            return StatusCode::NOT_FOUND.into_response();
+        }
+    };
     track_request("GET", "webfinger", "found");
     let target = actor::Actor {
-        host: target_host,
+        host: state.hostname.clone(),
         kind: target_kind,
     };
     Json(json!({
@@ -95,12 +131,37 @@ async fn get_tag_actor(
     axum::extract::State(state): axum::extract::State<State>,
     Path(tag): Path<String>
 ) -> Response {
+    let Some(kind) = actor::ActorKind::from_tag_path(&tag) else {
+        track_request("GET", "actor", "not_found");
+        return StatusCode::NOT_FOUND.into_response();
+    };
     track_request("GET", "actor", "tag");
     let target = actor::Actor {
         host: state.hostname.clone(),
-        kind: actor::ActorKind::from_tag(&tag),
+        kind,
     };
-    target.as_activitypub(&state.pub_key)
+    target.as_activitypub(&state.keys.get(&target.kind).public_keys, &state.config)
+        .into_response()
+}
+
+/// Any relay actor by `preferredUsername`, like `tag_rust` or
+/// `instance_example.social`, for debugging
+async fn get_actor_by_name(
+    axum::extract::State(state): axum::extract::State<State>,
+    Path(name): Path<String>
+) -> Response {
+    let Some(kind) = actor::ActorKind::from_username(&name)
+        .filter(|kind| *kind != ActorKind::Firehose || state.config.firehose)
+    else {
+        track_request("GET", "actor", "not_found");
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    track_request("GET", "actor", "name");
+    let target = actor::Actor {
+        host: state.hostname.clone(),
+        kind,
+    };
+    target.as_activitypub(&state.keys.get(&target.kind).public_keys, &state.config)
         .into_response()
 }
 
@@ -113,7 +174,44 @@ async fn get_instance_actor(
         host: state.hostname.clone(),
         kind: actor::ActorKind::InstanceRelay(instance.to_lowercase()),
     };
-    target.as_activitypub(&state.pub_key)
+    target.as_activitypub(&state.keys.get(&target.kind).public_keys, &state.config)
+        .into_response()
+}
+
+async fn get_account_actor(
+    axum::extract::State(state): axum::extract::State<State>,
+    Path(account): Path<String>
+) -> Response {
+    let Some(kind) = actor::ActorKind::from_account(&account) else {
+        track_request("GET", "actor", "not_found");
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    track_request("GET", "actor", "account");
+    let target = actor::Actor {
+        host: state.hostname.clone(),
+        kind,
+    };
+    target.as_activitypub(&state.keys.get(&target.kind).public_keys, &state.config)
+        .into_response()
+}
+
+/// The `firehose` actor, if enabled
+fn firehose_target(state: &State) -> Option<actor::Actor> {
+    state.config.firehose.then(|| actor::Actor {
+        host: state.hostname.clone(),
+        kind: ActorKind::Firehose,
+    })
+}
+
+async fn get_firehose_actor(
+    axum::extract::State(state): axum::extract::State<State>,
+) -> Response {
+    let Some(target) = firehose_target(&state) else {
+        track_request("GET", "actor", "not_found");
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    track_request("GET", "actor", "firehose");
+    target.as_activitypub(&state.keys.get(&target.kind).public_keys, &state.config)
         .into_response()
 }
 
@@ -122,9 +220,13 @@ async fn post_tag_relay(
     Path(tag): Path<String>,
     endpoint: endpoint::Endpoint<'_>
 ) -> Response {
+    let Some(kind) = actor::ActorKind::from_tag_path(&tag) else {
+        track_request("POST", "relay", "not_found");
+        return StatusCode::NOT_FOUND.into_response();
+    };
     let target = actor::Actor {
         host: state.hostname.clone(),
-        kind: actor::ActorKind::from_tag(&tag),
+        kind,
     };
     post_relay(state, endpoint, target).await
 }
@@ -141,13 +243,59 @@ async fn post_instance_relay(
     post_relay(state, endpoint, target).await
 }
 
+async fn post_account_relay(
+    axum::extract::State(state): axum::extract::State<State>,
+    Path(account): Path<String>,
+    endpoint: endpoint::Endpoint<'_>
+) -> Response {
+    let Some(kind) = actor::ActorKind::from_account(&account) else {
+        track_request("POST", "relay", "not_found");
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let target = actor::Actor {
+        host: state.hostname.clone(),
+        kind,
+    };
+    post_relay(state, endpoint, target).await
+}
+
+async fn post_firehose_relay(
+    axum::extract::State(state): axum::extract::State<State>,
+    endpoint: endpoint::Endpoint<'_>
+) -> Response {
+    let Some(target) = firehose_target(&state) else {
+        track_request("POST", "relay", "not_found");
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    post_relay(state, endpoint, target).await
+}
+
 async fn post_relay(
     state: State,
     endpoint: endpoint::Endpoint<'_>,
     target: actor::Actor
 ) -> Response {
-    let remote_actor = match endpoint.remote_actor(&state.client, &target.key_id(), &state.priv_key).await {
+    // Not resolved and therefore not checked by the client
+    let remote_actor_host = reqwest::Url::parse(&endpoint.remote_actor_uri).ok()
+        .and_then(|url| url.host_str().map(str::to_string))
+        .unwrap_or_default();
+    if ! state.address_filter.permits_host(&remote_actor_host) {
+        track_request("POST", "relay", "bad_actor");
+        return (StatusCode::BAD_REQUEST, "Actor on a non-public address").into_response();
+    }
+    let remote_actor = match endpoint.remote_actor(&state.client, &state.address_filter, &state.actor_cache, &target.key_id(&state.config), &state.keys.get(&target.kind).private_key).await {
         Ok(remote_actor) => remote_actor,
+        Err(error::Error::FetchRateLimited) => {
+            track_request("POST", "relay", "rate_limited");
+            return StatusCode::TOO_MANY_REQUESTS.into_response();
+        }
+        Err(e @ (error::Error::SignatureFail | error::Error::Signature(_))) => {
+            track_request("POST", "relay", "bad_signature");
+            return (
+                StatusCode::UNAUTHORIZED,
+                format!("Bad signature: {:?}", e)
+            ).into_response();
+        }
         Err(e) => {
             track_request("POST", "relay", "bad_actor");
             return (
@@ -170,42 +318,99 @@ async fn post_relay(
         .and_then(|object| object.get("type").cloned())
         .and_then(|object_type| object_type.as_str().map(std::string::ToString::to_string));
 
+    let allow_insecure = state.config.allow_insecure_inboxes;
+    let inbox = inbox::canonicalize(&remote_actor.inbox, allow_insecure);
+
     if action.action_type == "Follow" {
-        let priv_key = state.priv_key.clone();
+        if ! state.config.accepts_follow(&target.kind) {
+            track_request("POST", "relay", "tag_not_allowed");
+            return (
+                StatusCode::FORBIDDEN,
+                "This relay does not serve that tag"
+            ).into_response();
+        }
+        let inbox = match inbox {
+            Ok(inbox) => inbox,
+            Err(e) => {
+                track_request("POST", "relay", "bad_inbox");
+                return (
+                    StatusCode::BAD_REQUEST,
+                    format!("{}", e)
+                ).into_response();
+            }
+        };
+        let priv_key = state.keys.get(&target.kind).private_key.clone();
         let client = state.client.clone();
+        if follow::is_blocked(&state.blocklist, &remote_actor_host, &inbox) {
+            track_request("POST", "relay", "follow_blocked");
+            increment_counter!("relay_follows_rejected_total", "reason" => "blocklist");
+            tokio::spawn(async move {
+                let reject = follow::response("Reject", state.config.jsonld_context(), &target, &remote_actor.id, &inbox, endpoint.payload);
+                if let Err(e) = send::send(
+                    client.as_ref(), &state.address_filter, &inbox,
+                    &target.key_id(&state.config),
+                    &priv_key,
+                    &reject,
+                ).await {
+                    tracing::error!("post reject: {}", e);
+                }
+            });
+            return (
+                StatusCode::FORBIDDEN,
+                "Instance blocked"
+            ).into_response();
+        }
         tokio::spawn(async move {
-            let accept_id = format!(
-                "https://{}/activity/accept/{}/{}",
-                state.hostname,
-                urlencoding::encode(&target.uri()),
-                urlencoding::encode(&remote_actor.inbox),
-            );
-            let accept = activitypub::Action {
-                jsonld_context: serde_json::Value::String("https://www.w3.org/ns/activitystreams".to_string()),
-                action_type: "Accept".to_string(),
-                actor: target.uri(),
-                to: Some(json!(remote_actor.id.clone())),
-                id: accept_id,
-                object: Some(endpoint.payload),
-            };
+            let accept = follow::response("Accept", state.config.jsonld_context(), &target, &remote_actor.id, &inbox, endpoint.payload);
             let result = send::send(
-                client.as_ref(), &remote_actor.inbox,
-                &target.key_id(),
+                client.as_ref(), &state.address_filter, &inbox,
+                &target.key_id(&state.config),
                 &priv_key,
                 &accept,
             ).await;
             match result {
                 Ok(()) => {
+                    let shared_inbox = remote_actor.endpoints.as_ref()
+                        .and_then(|endpoints| endpoints.shared_inbox.as_deref())
+                        .and_then(|shared_inbox| inbox::canonicalize(shared_inbox, allow_insecure).ok());
                     match state.database.add_follow(
                         &remote_actor.id,
-                        &remote_actor.inbox,
+                        &inbox,
                         &target.uri(),
+                        shared_inbox.as_deref(),
                     ).await {
                         Ok(()) => {
                             track_request("POST", "relay", "follow");
+                            match &target.kind {
+                                ActorKind::TagSetRelay(tags) =>
+                                    if let Err(e) = state.database.add_tag_set(&target.uri(), tags).await {
+                                        tracing::error!("add_tag_set: {}", e);
+                                    },
+                                ActorKind::TagPrefixRelay(prefix) =>
+                                    state.followed_prefixes.insert(prefix),
+                                _ => {}
+                            }
+                            if let Some(message) = &state.config.welcome_message {
+                                if state.welcomes.allow(&remote_actor.id) {
+                                    let create = welcome::create_note(&state.config.jsonld_context(), &target, &remote_actor.id, message);
+                                    let key_id = target.key_id(&state.config);
+                                    let address_filter = state.address_filter.clone();
+                                    tokio::spawn(async move {
+                                        let result = send::send(
+                                            client.as_ref(), &address_filter, &inbox,
+                                            &key_id,
+                                            &priv_key,
+                                            &create,
+                                        ).await;
+                                        increment_counter!("relay_welcomes_total", "status" => if result.is_ok() { "ok" } else { "error" });
+                                        if let Err(e) = result {
+                                            tracing::error!("post welcome: {}", e);
+                                        }
+                                    });
+                                }
+                            }
                         }
                         Err(e) => {
-                            // duplicate key constraint
                             tracing::error!("add_follow: {}", e);
                             track_request("POST", "relay", "follow_error");
                         }
@@ -223,43 +428,149 @@ async fn post_relay(
          "{}"
         ).into_response()
     } else if action.action_type == "Undo" && object_type == Some("Follow".to_string()) {
-        match state.database.del_follow(
+        // Follows from before validation may have kept their inbox as is
+        let inbox = inbox.unwrap_or_else(|_| remote_actor.inbox.clone());
+        match state.database.remove_follower(
             &remote_actor.id,
+            &inbox,
             &target.uri(),
         ).await {
-            Ok(()) => {
-                track_request("POST", "relay", "unfollow");
+            Ok(count) => {
+                // accepted even if there was nothing to remove
+                track_request("POST", "relay", if count > 0 { "unfollow" } else { "unfollow_unknown" });
                 (StatusCode::ACCEPTED,
                  [("content-type", "application/activity+json")],
                  "{}"
                 ).into_response()
             }
             Err(e) => {
-                tracing::error!("del_follow: {}", e);
+                tracing::error!("remove_follower: {}", e);
                 track_request("POST", "relay", "unfollow_error");
                 (StatusCode::INTERNAL_SERVER_ERROR,
                  format!("{}", e)
                  ).into_response()
             }
         }
+    } else if action.action_type == "Flag" {
+        track_request("POST", "relay", "flag");
+        state.flags.forward(
+            &remote_actor_host,
+            target.key_id(&state.config),
+            state.keys.get(&target.kind).private_key.clone(),
+            endpoint.payload,
+        );
+        (StatusCode::ACCEPTED,
+         [("content-type", "application/activity+json")],
+         "{}"
+        ).into_response()
     } else {
         track_request("POST", "relay", "unrecognized");
         (StatusCode::BAD_REQUEST, "Not a recognized request").into_response()
     }
 }
 
-/// An empty ActivityStreams outbox just to satisfy the spec
-async fn outbox() -> Response {
+async fn get_tag_outbox(
+    axum::extract::State(state): axum::extract::State<State>,
+    Path(tag): Path<String>,
+) -> Response {
+    let Some(kind) = actor::ActorKind::from_tag_path(&tag) else {
+        track_request("GET", "outbox", "not_found");
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let target = actor::Actor { host: state.hostname.clone(), kind };
+    outbox(state, target)
+}
+
+async fn get_instance_outbox(
+    axum::extract::State(state): axum::extract::State<State>,
+    Path(instance): Path<String>,
+) -> Response {
+    let kind = actor::ActorKind::InstanceRelay(instance.to_lowercase());
+    let target = actor::Actor { host: state.hostname.clone(), kind };
+    outbox(state, target)
+}
+
+async fn get_account_outbox(
+    axum::extract::State(state): axum::extract::State<State>,
+    Path(account): Path<String>,
+) -> Response {
+    let Some(kind) = actor::ActorKind::from_account(&account) else {
+        track_request("GET", "outbox", "not_found");
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let target = actor::Actor { host: state.hostname.clone(), kind };
+    outbox(state, target)
+}
+
+/// The welcome note of an actor to a follower, for receivers that
+/// fetch it by its id
+async fn get_welcome(
+    axum::extract::State(state): axum::extract::State<State>,
+    Path((name, follower_id)): Path<(String, String)>,
+) -> Response {
+    let (Some(message), Some(kind)) = (
+        &state.config.welcome_message,
+        actor::ActorKind::from_username(&name)
+            .filter(|kind| *kind != ActorKind::Firehose || state.config.firehose),
+    ) else {
+        track_request("GET", "welcome", "not_found");
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    track_request("GET", "welcome", "found");
+    let target = actor::Actor { host: state.hostname.clone(), kind };
+    let mut note = welcome::note(&target, &follower_id, message);
+    note["@context"] = state.config.jsonld_context();
+    ([("content-type", "application/activity+json")],
+     Json(note)).into_response()
+}
+
+async fn get_firehose_outbox(
+    axum::extract::State(state): axum::extract::State<State>,
+) -> Response {
+    let Some(target) = firehose_target(&state) else {
+        track_request("GET", "outbox", "not_found");
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    outbox(state, target)
+}
+
+/// The recently relayed activities of an actor
+fn outbox(state: State, target: actor::Actor) -> Response {
+    track_request("GET", "outbox", "found");
+    let activities = state.outbox.get(&target.uri());
+    ([("content-type", "application/activity+json")],
+     Json(json!({
+         "@context": "https://www.w3.org/ns/activitystreams",
+         "id": format!("{}/outbox", target.uri()),
+         "type": "OrderedCollection",
+         "totalItems": activities.len(),
+         "orderedItems": activities,
+     }))).into_response()
+}
+
+const NODEINFO_VERSIONS: [&str; 2] = ["2.0", "2.1"];
+
+async fn nodeinfo_links(axum::extract::State(state): axum::extract::State<State>) -> Response {
+    track_request("GET", "nodeinfo", "links");
     Json(json!({
-        "@context": "https://www.w3.org/ns/activitystreams",
-        "summary": "buzzrelay stub outbox",
-        "type": "OrderedCollection",
-        "totalItems": 0,
-        "orderedItems": []
+        "links": NODEINFO_VERSIONS.iter()
+            .map(|version| json!({
+                "rel": format!("http://nodeinfo.diaspora.software/ns/schema/{}", version),
+                "href": format!("https://{}/nodeinfo/{}", state.hostname, version),
+            }))
+            .collect::<Vec<_>>(),
     })).into_response()
 }
 
-async fn nodeinfo(axum::extract::State(state): axum::extract::State<State>) -> Response {
+async fn nodeinfo(
+    axum::extract::State(state): axum::extract::State<State>,
+    Path(version): Path<String>,
+) -> Response {
+    if ! NODEINFO_VERSIONS.contains(&version.as_str()) {
+        track_request("GET", "nodeinfo", "not_found");
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    track_request("GET", "nodeinfo", "document");
     let follows_count = state.database.get_follows_count()
         .await
         .unwrap_or(0);
@@ -267,14 +578,19 @@ async fn nodeinfo(axum::extract::State(state): axum::extract::State<State>) -> R
         .await
         .unwrap_or(0);
 
+    let mut software = json!({
+        "name": env!("CARGO_PKG_NAME"),
+        "version": env!("CARGO_PKG_VERSION"),
+    });
+    // repository and homepage were only added in 2.1
+    if version != "2.0" {
+        software["repository"] = json!(env!("CARGO_PKG_REPOSITORY"));
+        software["homepage"] = json!(env!("CARGO_PKG_HOMEPAGE"));
+    }
+
     Json(json!({
-        "version": "2.1",
-        "software": {
-            "name": env!("CARGO_PKG_NAME"),
-            "version": env!("CARGO_PKG_VERSION"),
-            "repository": env!("CARGO_PKG_REPOSITORY"),
-            "homepage": env!("CARGO_PKG_HOMEPAGE"),
-        },
+        "version": version,
+        "software": software,
         "protocols": ["activitypub"],
         "services": {
             "inbound": [],
@@ -293,88 +609,358 @@ async fn nodeinfo(axum::extract::State(state): axum::extract::State<State>) -> R
         "metadata": {
             "rust_version": env!("CARGO_PKG_RUST_VERSION"),
         },
-        "links": vec![
-            json!({
-                "rel": "http://nodeinfo.diaspora.software/ns/schema/2.1",
-                "href": format!("https://{}/.well-known/nodeinfo", state.hostname),
-            }),
-        ],
     })).into_response()
 }
 
+/// Ready once a stream has connected and the database is reachable
+async fn readyz(axum::extract::State(state): axum::extract::State<State>) -> Response {
+    if ! state.stream_connected.load(Ordering::Relaxed) {
+        return (StatusCode::SERVICE_UNAVAILABLE, "No stream connected").into_response();
+    }
+    if let Err(e) = state.database.ping().await {
+        return (StatusCode::SERVICE_UNAVAILABLE, format!("Database: {}", e)).into_response();
+    }
+    "OK".into_response()
+}
+
+async fn get_tag_followers(
+    axum::extract::State(state): axum::extract::State<State>,
+    Path(tag): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Response {
+    let Some(kind) = actor::ActorKind::from_tag_path(&tag) else {
+        track_request("GET", "followers", "not_found");
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let target = actor::Actor {
+        host: state.hostname.clone(),
+        kind,
+    };
+    followers(state, target, params).await
+}
+
+async fn get_instance_followers(
+    axum::extract::State(state): axum::extract::State<State>,
+    Path(instance): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Response {
+    let target = actor::Actor {
+        host: state.hostname.clone(),
+        kind: actor::ActorKind::InstanceRelay(instance.to_lowercase()),
+    };
+    followers(state, target, params).await
+}
+
+async fn get_account_followers(
+    axum::extract::State(state): axum::extract::State<State>,
+    Path(account): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Response {
+    let Some(kind) = actor::ActorKind::from_account(&account) else {
+        track_request("GET", "followers", "not_found");
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let target = actor::Actor {
+        host: state.hostname.clone(),
+        kind,
+    };
+    followers(state, target, params).await
+}
+
+async fn get_firehose_followers(
+    axum::extract::State(state): axum::extract::State<State>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Response {
+    let Some(target) = firehose_target(&state) else {
+        track_request("GET", "followers", "not_found");
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    followers(state, target, params).await
+}
+
+/// The collection only counts followers unless `expose_followers` is
+/// enabled, then it is paged with `?page=true`, followed by
+/// `&after=<last follower of the previous page>`
+async fn followers(
+    state: State,
+    target: actor::Actor,
+    params: HashMap<String, String>,
+) -> Response {
+    let collection_id = target.followers_uri();
+    let paged = params.contains_key("page") && state.config.expose_followers;
+    let after = params.get("after")
+        .map_or("", String::as_str);
+    let count = match state.database.get_actor_followers_count(&target.uri()).await {
+        Ok(count) => count,
+        Err(e) => {
+            tracing::error!("get_actor_followers_count: {}", e);
+            track_request("GET", "followers", "error");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    let collection = if paged {
+        let page_size = state.config.followers_page_size.get();
+        // One more tells whether there is a next page
+        let items = match state.database.get_actor_followers(&target.uri(), after, page_size as i64 + 1).await {
+            Ok(items) => items,
+            Err(e) => {
+                tracing::error!("get_actor_followers: {}", e);
+                track_request("GET", "followers", "error");
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+        };
+        track_request("GET", "followers", "page");
+        activitypub::collection_page(&collection_id, after, page_size, count, items)
+    } else {
+        track_request("GET", "followers", "collection");
+        let mut collection = json!({
+            "@context": "https://www.w3.org/ns/activitystreams",
+            "id": &collection_id,
+            "type": "OrderedCollection",
+            "totalItems": count,
+        });
+        if state.config.expose_followers {
+            collection["first"] = json!(format!("{}?page=true", collection_id));
+        }
+        collection
+    };
+
+    ([("content-type", "application/activity+json")],
+     Json(collection)).into_response()
+}
+
 #[tokio::main]
 async fn main() {
     exit_on_panic();
 
-    tracing_subscriber::registry()
+    let config_file = std::env::args().nth(1)
+        .expect("Call with config.yaml");
+    let config = config::Config::from_file(&config_file)
+        .unwrap_or_else(|e| {
+            eprintln!("{}: {}", config_file, e);
+            process::exit(1);
+        });
+    // Subcommands keep stdout clear of logs
+    let args = std::env::args().skip(2).collect::<Vec<_>>();
+    if let Some((command, args)) = args.split_first() {
+        let database = db::Database::connect(&config).await;
+        process::exit(cli::run(command, args, &config, &database).await);
+    }
+
+    let registry = tracing_subscriber::registry()
         .with(
             tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| {
                 "buzzrelay=trace,tower_http=trace,axum=trace".into()
             }),
-        )
-        .with(tracing_subscriber::fmt::layer())
-        .init();
-
-    let config = config::Config::load(
-        &std::env::args().nth(1)
-            .expect("Call with config.yaml")
-    );
-    let priv_key = config.priv_key();
-    let pub_key = config.pub_key();
+        );
+    match config.log_format() {
+        config::LogFormat::Text =>
+            registry.with(tracing_subscriber::fmt::layer()).init(),
+        config::LogFormat::Json =>
+            registry.with(tracing_subscriber::fmt::layer().json()).init(),
+    }
+    let keys = match keys::KeyRing::load(&config) {
+        Ok(keys) => keys,
+        Err(e) => {
+            tracing::error!("loading keys: {}", e);
+            process::exit(1);
+        }
+    };
+    if let Err(e) = keys.check() {
+        tracing::error!("key self-check failed: {}", e);
+        process::exit(1);
+    }
+    for (kind, actor_keys) in keys.keypairs() {
+        tracing::info!(
+            kind = %kind.map_or("default".to_string(), |kind| format!("{:?}", kind)),
+            key_name = %actor_keys.public_keys[0].0,
+            advertised = actor_keys.public_keys.len(),
+            "signing key verified"
+        );
+    }
+    if config.detect_software && config.sign_created_software.is_empty() {
+        tracing::warn!("detect_software has no effect without sign_created_software");
+    }
+    if config.firehose {
+        tracing::warn!(
+            "firehose enabled: every follower of https://{}/relay receives every public post of the streams",
+            config.hostname
+        );
+    }
 
     let recorder = PrometheusBuilder::new()
         .add_global_label("application", env!("CARGO_PKG_NAME"))
         .idle_timeout(MetricKindMask::ALL, Some(Duration::from_secs(600)))
+        .set_buckets_for_metric(Matcher::Full("relay_post_duration".to_string()), &config.post_duration_buckets())
+        .unwrap()
+        .set_buckets_for_metric(Matcher::Suffix("_duration".to_string()), &[
+            0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0
+        ])
+        .unwrap()
         .install_recorder()
         .unwrap();
 
-    let database = db::Database::connect(&config.db).await;
+    let database = db::Database::connect(&config).await;
+    let publisher = match config.delivery_backend {
+        config::DeliveryBackend::Http => None,
+        config::DeliveryBackend::Nats => match queue::Publisher::connect(&config.nats_url, config.nats_subject.clone()).await {
+            Ok(publisher) => {
+                tracing::info!("publishing deliveries to {} on {}", config.nats_subject, config.nats_url);
+                Some(publisher)
+            }
+            Err(e) => {
+                tracing::error!("connect to nats {}: {}", config.nats_url, e);
+                process::exit(1);
+            }
+        },
+    };
 
-    let stream_rx = stream::spawn(config.streams.into_iter());
+    let (stream_rx, stream_connected) = stream::spawn(config.streams.clone().into_iter(), config.stream_heartbeat_timeout(), config.proxy());
+    let mut client = reqwest::Client::builder()
+        .dns_resolver(Arc::new(resolver::Resolver::new(config.address_filter())))
+        .redirect(config.address_filter().redirect_policy())
+        .timeout(config.http_timeout())
+        .user_agent(config.user_agent())
+        .pool_max_idle_per_host(config.pool_max_idle_per_host)
+        .pool_idle_timeout(Some(config.pool_idle_timeout()))
+        // Sends `Accept-Encoding:` and decodes compressed responses,
+        // actor documents as well as error bodies
+        .gzip(true)
+        .deflate(true)
+        // Negotiated by ALPN, HTTP/2 multiplexes a worker's requests
+        // over one connection
+        .http2_adaptive_window(true);
+    if let Some(proxy) = config.proxy() {
+        client = client.proxy(proxy);
+    }
     let client = Arc::new(
-        reqwest::Client::builder()
-            .timeout(Duration::from_secs(5))
-            .user_agent(concat!(
-                env!("CARGO_PKG_NAME"),
-                "/",
-                env!("CARGO_PKG_VERSION"),
-            ))
-            .pool_max_idle_per_host(1)
-            .pool_idle_timeout(Some(Duration::from_secs(5)))
-            .build()
+        client.build()
             .unwrap()
     );
     let hostname = Arc::new(config.hostname.clone());
-    relay::spawn(client.clone(), hostname.clone(), database.clone(), priv_key.clone(), stream_rx);
+    let config = Arc::new(config);
+    let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    let outbox = outbox::Outbox::new(config.outbox_size);
+    let (commands, commands_rx) = tokio::sync::mpsc::channel(1);
+    let blocklist = domain_list::SharedDomainList::new(config.blocklist());
+    let liveness = notify::Liveness::new();
+    let followed_prefixes = actor::FollowedPrefixes::default();
+    let relay = relay::spawn(client.clone(), publisher, hostname.clone(), database.clone(), keys.clone(), blocklist.clone(), stream_rx, config.clone(), outbox.clone(), commands_rx, shutdown_rx.clone(), liveness.clone(), followed_prefixes.clone());
+
+    tokio::spawn(pause_signals(commands.clone()));
 
     let app = Router::new()
         .route("/tag/:tag", get(get_tag_actor).post(post_tag_relay))
         .route("/instance/:instance", get(get_instance_actor).post(post_instance_relay))
-        .route("/tag/:tag/outbox", get(outbox))
-        .route("/instance/:instance/outbox", get(outbox))
+        .route("/account/:account", get(get_account_actor).post(post_account_relay))
+        .route("/relay", get(get_firehose_actor).post(post_firehose_relay))
+        .route("/actor/:name", get(get_actor_by_name))
+        .route("/tag/:tag/outbox", get(get_tag_outbox))
+        .route("/instance/:instance/outbox", get(get_instance_outbox))
+        .route("/account/:account/outbox", get(get_account_outbox))
+        .route("/relay/outbox", get(get_firehose_outbox))
+        .route("/tag/:tag/followers", get(get_tag_followers))
+        .route("/instance/:instance/followers", get(get_instance_followers))
+        .route("/account/:account/followers", get(get_account_followers))
+        .route("/relay/followers", get(get_firehose_followers))
+        .route("/welcome/:name/:follower", get(get_welcome))
         .route("/.well-known/webfinger", get(webfinger))
-        .route("/.well-known/nodeinfo", get(nodeinfo))
+        .route("/.well-known/nodeinfo", get(nodeinfo_links))
+        .route("/nodeinfo/:version", get(nodeinfo))
+        .route("/healthz", get(|| async { "OK" }))
+        .route("/readyz", get(readyz))
+        .route("/admin/:action", get(admin::admin).post(admin::admin))
         .route("/metrics", get(|| async move {
             recorder.render().into_response()
         }))
         .with_state(State {
             database,
-            client,
+            client: client.clone(),
+            actor_cache: endpoint::ActorCache::new(),
+            stream_connected,
+            config: config.clone(),
+            outbox,
+            commands,
             hostname,
-            priv_key,
-            pub_key,
+            keys,
+            blocklist,
+            followed_prefixes,
+            address_filter: config.address_filter(),
+            welcomes: welcome::Limiter::new(),
+            flags: flag::Forwarder::new(config.flag_forward_url.clone(), client.clone(), config.address_filter()),
         })
         .merge(SpaRouter::new("/", "static"));
 
-    let addr = SocketAddr::from(([127, 0, 0, 1], config.listen_port));
-    let server = axum::Server::bind(&addr)
-        .serve(app.into_make_service());
+    let listen = config.listen();
+    let mut server_shutdown = shutdown_rx;
+    let server_shutdown = async move {
+        let _ = server_shutdown.changed().await;
+    };
+    let server = match &listen {
+        config::Listen::Tcp(addr) =>
+            axum::Server::bind(addr)
+                .serve(app.into_make_service())
+                .with_graceful_shutdown(server_shutdown)
+                .boxed(),
+        config::Listen::Unix(path) => {
+            let listener = listen::bind(path)
+                .unwrap_or_else(|e| {
+                    tracing::error!("listen on {}: {}", path.display(), e);
+                    process::exit(1);
+                });
+            axum::Server::builder(listen::accept(listener))
+                .serve(app.into_make_service())
+                .with_graceful_shutdown(server_shutdown)
+                .boxed()
+        }
+    };
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        tracing::info!("shutting down");
+        let _ = shutdown_tx.send(true);
+    });
 
-    tracing::info!("serving on {}", addr);
-    systemd::daemon::notify(false, [(systemd::daemon::STATE_READY, "1")].iter())
-        .unwrap();
+    tracing::info!("serving on {}", listen);
+    notify::ready();
+    notify::spawn_watchdog(liveness);
     server.await
         .unwrap();
+    if let config::Listen::Unix(path) = &listen {
+        if let Err(e) = std::fs::remove_file(path) {
+            tracing::warn!("remove {}: {}", path.display(), e);
+        }
+    }
+    relay.await
+        .unwrap();
+}
+
+/// SIGUSR1 pauses relaying, SIGUSR2 resumes
+async fn pause_signals(commands: tokio::sync::mpsc::Sender<relay::Command>) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut sigusr1 = signal(SignalKind::user_defined1())
+        .expect("SIGUSR1 handler");
+    let mut sigusr2 = signal(SignalKind::user_defined2())
+        .expect("SIGUSR2 handler");
+    loop {
+        let command = tokio::select! {
+            _ = sigusr1.recv() => relay::Command::Pause,
+            _ = sigusr2.recv() => relay::Command::Resume,
+        };
+        if commands.send(command).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// SIGTERM or Ctrl-C
+async fn shutdown_signal() {
+    let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+        .expect("SIGTERM handler");
+    tokio::select! {
+        _ = sigterm.recv() => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
 }
 
 fn exit_on_panic() {
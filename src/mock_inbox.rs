@@ -0,0 +1,143 @@
+//! Test scaffolding: a local HTTP server that records what gets
+//! delivered to it
+
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use axum::{
+    body::Bytes,
+    http::{HeaderMap, Method, StatusCode, Uri},
+    Router,
+};
+use sigh::{PublicKey, Signature};
+use tokio::time::{sleep, Instant};
+use crate::{db::Database, digest};
+
+/// A request to the mock inbox
+#[derive(Debug, Clone)]
+pub struct Received {
+    pub method: Method,
+    pub uri: Uri,
+    pub headers: HeaderMap,
+    pub body: Vec<u8>,
+}
+
+impl Received {
+    pub fn json(&self) -> serde_json::Value {
+        serde_json::from_slice(&self.body).unwrap()
+    }
+
+    pub fn signature_key_id(&self) -> Option<String> {
+        let req = self.to_request();
+        Signature::from(&req).key_id().map(str::to_string)
+    }
+
+    /// Checks both the signature and the `Digest:` of the body
+    pub fn verify(&self, public_key: &PublicKey) -> bool {
+        let digest_ok = self.headers.get("digest")
+            .and_then(|digest| digest.to_str().ok())
+            .is_some_and(|digest| digest == digest::generate_header(&self.body).unwrap());
+        let req = self.to_request();
+        digest_ok && Signature::from(&req).verify(public_key).unwrap_or(false)
+    }
+
+    fn to_request(&self) -> http::Request<()> {
+        let mut req = http::Request::builder()
+            .method(self.method.clone())
+            .uri(self.uri.clone())
+            .body(())
+            .unwrap();
+        *req.headers_mut() = self.headers.clone();
+        req
+    }
+}
+
+#[derive(Default)]
+struct Inner {
+    received: Vec<Received>,
+    /// Responses for the next requests, `202 Accepted` after
+    responses: VecDeque<(StatusCode, HeaderMap)>,
+}
+
+pub struct MockInbox {
+    addr: SocketAddr,
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl MockInbox {
+    /// Listens on a free port of 127.0.0.1 until dropped with the
+    /// runtime
+    pub async fn start() -> Self {
+        let inner = Arc::new(Mutex::new(Inner::default()));
+        let handler_inner = inner.clone();
+        let app = Router::new()
+            .fallback(move |method: Method, uri: Uri, headers: HeaderMap, body: Bytes| {
+                let inner = handler_inner.clone();
+                async move {
+                    let mut inner = inner.lock().unwrap();
+                    inner.received.push(Received {
+                        method,
+                        uri,
+                        headers,
+                        body: body.to_vec(),
+                    });
+                    inner.responses.pop_front()
+                        .unwrap_or((StatusCode::ACCEPTED, HeaderMap::new()))
+                }
+            });
+        let server = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0)))
+            .serve(app.into_make_service());
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        MockInbox { addr, inner }
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.addr, path)
+    }
+
+    /// Responds to the next requests with these instead of
+    /// `202 Accepted`
+    pub fn respond_with(&self, statuses: impl IntoIterator<Item = StatusCode>) {
+        self.inner.lock().unwrap()
+            .responses.extend(statuses.into_iter().map(|status| (status, HeaderMap::new())));
+    }
+
+    /// Responds to the next request with `302 Found`
+    pub fn redirect_to(&self, location: &str) {
+        let mut headers = HeaderMap::new();
+        headers.insert("location", location.parse().unwrap());
+        self.inner.lock().unwrap()
+            .responses.push_back((StatusCode::FOUND, headers));
+    }
+
+    pub fn received(&self) -> Vec<Received> {
+        self.inner.lock().unwrap()
+            .received.clone()
+    }
+
+    /// Waits up to `timeout` for at least `count` requests
+    pub async fn wait_for(&self, count: usize, timeout: Duration) -> Vec<Received> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let received = self.received();
+            if received.len() >= count || Instant::now() >= deadline {
+                return received;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+    }
+}
+
+/// The database at `TEST_DATABASE` for tests that need PostgreSQL,
+/// which are `#[ignore]`d
+pub async fn test_database() -> Database {
+    let conn_str = std::env::var("TEST_DATABASE")
+        .expect("TEST_DATABASE");
+    let mut config = crate::config::Config::test("");
+    config.db = conn_str;
+    Database::connect(&config).await
+}
@@ -0,0 +1,186 @@
+//! Software of remote instances from their NodeInfo, to pick
+//! interoperability quirks per inbox host
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use http::StatusCode;
+use metrics::increment_counter;
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use crate::{error::Error, resolver::AddressFilter, send::SignedHeaders};
+
+/// How long to remember the software of a host
+const TTL: Duration = Duration::from_secs(24 * 60 * 60);
+/// How long to stick to the defaults after a failed fetch
+const FAILED_TTL: Duration = Duration::from_secs(60 * 60);
+
+const SCHEMA_PREFIX: &str = "http://nodeinfo.diaspora.software/ns/schema/";
+
+#[derive(Deserialize)]
+struct WellKnown {
+    links: Vec<Link>,
+}
+
+#[derive(Deserialize)]
+struct Link {
+    rel: String,
+    href: String,
+}
+
+#[derive(Deserialize)]
+struct NodeInfo {
+    software: Software,
+}
+
+#[derive(Deserialize)]
+struct Software {
+    name: String,
+}
+
+/// The link to the newest NodeInfo schema version, all of which
+/// include `software.name`
+fn nodeinfo_href(well_known: &WellKnown) -> Option<&str> {
+    well_known.links.iter()
+        .filter(|link| link.rel.starts_with(SCHEMA_PREFIX))
+        .max_by(|a, b| a.rel.cmp(&b.rel))
+        .map(|link| link.href.as_str())
+}
+
+/// The NodeInfo document that a remote links to, which must be on the
+/// same host so that followers cannot direct the relay elsewhere
+fn nodeinfo_url(href: &str, host: &str) -> Result<reqwest::Url, Error> {
+    let url = reqwest::Url::parse(href)
+        .map_err(|_| Error::InvalidUri)?;
+    if url.scheme() != "https" || url.host_str() != Some(host) {
+        return Err(Error::InvalidUri);
+    }
+    Ok(url)
+}
+
+async fn get_json<T: DeserializeOwned>(client: &reqwest::Client, filter: &AddressFilter, url: reqwest::Url) -> Result<T, Error> {
+    filter.check_url(&url)?;
+    let res = client.get(url)
+        .header("accept", "application/json")
+        .send()
+        .await?;
+    if res.status() == StatusCode::OK {
+        Ok(res.json().await?)
+    } else {
+        Err(Error::Response(res.status(), res.text().await?))
+    }
+}
+
+/// Lowercase `software.name` of a host
+pub async fn fetch_software(client: &reqwest::Client, filter: &AddressFilter, host: &str) -> Result<String, Error> {
+    let well_known_url = reqwest::Url::parse(&format!("https://{}/.well-known/nodeinfo", host))
+        .map_err(|_| Error::InvalidUri)?;
+    let well_known: WellKnown = get_json(client, filter, well_known_url).await?;
+    let href = nodeinfo_href(&well_known)
+        .ok_or(Error::NoNodeInfo)?;
+    let nodeinfo: NodeInfo = get_json(client, filter, nodeinfo_url(href, host)?).await?;
+    Ok(nodeinfo.software.name.to_lowercase())
+}
+
+/// Software per host, fetched on first use
+pub struct SoftwareCache {
+    /// Software to sign for with `(created)` right away
+    sign_created: HashSet<String>,
+    filter: AddressFilter,
+    hosts: Mutex<HashMap<String, (Option<String>, Instant)>>,
+}
+
+impl SoftwareCache {
+    pub fn new(sign_created: &[String], filter: AddressFilter) -> Self {
+        SoftwareCache {
+            sign_created: sign_created.iter()
+                .map(|software| software.to_lowercase())
+                .collect(),
+            filter,
+            hosts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn cached(&self, host: &str) -> Option<Option<String>> {
+        let hosts = self.hosts.lock().unwrap();
+        let (software, fetched) = hosts.get(host)?;
+        let ttl = if software.is_some() { TTL } else { FAILED_TTL };
+        (fetched.elapsed() < ttl).then(|| software.clone())
+    }
+
+    fn insert(&self, host: &str, software: Option<String>) {
+        self.hosts.lock().unwrap()
+            .insert(host.to_string(), (software, Instant::now()));
+    }
+
+    /// `None` if unknown, also while a failed fetch is remembered
+    pub async fn software(&self, client: &reqwest::Client, host: &str) -> Option<String> {
+        if let Some(software) = self.cached(host) {
+            return software;
+        }
+        let software = match fetch_software(client, &self.filter, host).await {
+            Ok(software) => {
+                increment_counter!("relay_nodeinfo_fetches_total", "status" => "ok");
+                Some(software)
+            }
+            Err(e) => {
+                tracing::debug!("nodeinfo of {}: {}", host, e);
+                increment_counter!("relay_nodeinfo_fetches_total", "status" => "error");
+                None
+            }
+        };
+        self.insert(host, software.clone());
+        software
+    }
+
+    /// The headers to sign first for deliveries to `host`, Mastodon's
+    /// unless its software is listed in `sign_created`
+    pub async fn signed_headers(&self, client: &reqwest::Client, host: &str) -> SignedHeaders {
+        match self.software(client, host).await {
+            Some(software) if self.sign_created.contains(&software) =>
+                SignedHeaders::Created,
+            _ =>
+                SignedHeaders::Date,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn newest_schema() {
+        let well_known: WellKnown = serde_json::from_str(r#"{"links":[
+            {"rel":"http://nodeinfo.diaspora.software/ns/schema/2.0","href":"https://example.com/nodeinfo/2.0"},
+            {"rel":"http://nodeinfo.diaspora.software/ns/schema/2.1","href":"https://example.com/nodeinfo/2.1"},
+            {"rel":"https://www.w3.org/ns/activitystreams#Application","href":"https://example.com/actor"}
+        ]}"#).unwrap();
+        assert_eq!(nodeinfo_href(&well_known), Some("https://example.com/nodeinfo/2.1"));
+        let well_known: WellKnown = serde_json::from_str(r#"{"links":[]}"#).unwrap();
+        assert_eq!(nodeinfo_href(&well_known), None);
+    }
+
+    #[test]
+    fn nodeinfo_urls() {
+        assert!(nodeinfo_url("https://example.com/nodeinfo/2.1", "example.com").is_ok());
+        assert!(nodeinfo_url("http://example.com/nodeinfo/2.1", "example.com").is_err());
+        assert!(nodeinfo_url("https://other.example/nodeinfo/2.1", "example.com").is_err());
+        assert!(nodeinfo_url("https://10.0.0.1/nodeinfo/2.1", "example.com").is_err());
+        assert!(nodeinfo_url("/nodeinfo/2.1", "example.com").is_err());
+    }
+
+    #[tokio::test]
+    async fn signed_headers_by_software() {
+        let cache = SoftwareCache::new(&["Pleroma".to_string()], AddressFilter::default());
+        cache.insert("pleroma.example", Some("pleroma".to_string()));
+        cache.insert("mastodon.example", Some("mastodon".to_string()));
+        cache.insert("unknown.example", None);
+        let client = reqwest::Client::new();
+        assert_eq!(cache.signed_headers(&client, "pleroma.example").await, SignedHeaders::Created);
+        assert_eq!(cache.signed_headers(&client, "mastodon.example").await, SignedHeaders::Date);
+        assert_eq!(cache.signed_headers(&client, "unknown.example").await, SignedHeaders::Date);
+    }
+}
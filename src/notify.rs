@@ -0,0 +1,119 @@
+//! Service notifications to systemd, skipped when not running under
+//! systemd (e.g. in Docker or during development)
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// How often the relay loop reports that it is alive while idle
+pub const LIVENESS_INTERVAL: Duration = Duration::from_secs(5);
+
+fn notify(name: &str, value: &str) {
+    if std::env::var_os("NOTIFY_SOCKET").is_none() {
+        return;
+    }
+    if let Err(e) = systemd::daemon::notify(false, [(name, value)].iter()) {
+        tracing::warn!("systemd notify {}: {}", name, e);
+    }
+}
+
+/// Startup has finished
+pub fn ready() {
+    notify(systemd::daemon::STATE_READY, "1");
+}
+
+/// Keeps `WatchdogSec=` from restarting the service
+fn watchdog() {
+    notify(systemd::daemon::STATE_WATCHDOG, "1");
+}
+
+/// Half the watchdog timeout that systemd passes in `WATCHDOG_USEC`,
+/// if meant for this process
+fn watchdog_interval(usec: Option<&str>, pid: Option<&str>) -> Option<Duration> {
+    if pid.is_some_and(|pid| pid.parse() != Ok(std::process::id())) {
+        return None;
+    }
+    let usec = usec?.parse::<u64>().ok()
+        .filter(|usec| *usec > 0)?;
+    Some(Duration::from_micros(usec) / 2)
+}
+
+/// When the relay loop last went around, so that the watchdog stops
+/// pinging once it is stuck
+#[derive(Clone)]
+pub struct Liveness(Arc<Mutex<Instant>>);
+
+impl Liveness {
+    pub fn new() -> Self {
+        Liveness(Arc::new(Mutex::new(Instant::now())))
+    }
+
+    pub fn beat(&self) {
+        *self.0.lock().unwrap() = Instant::now();
+    }
+
+    fn since_beat(&self) -> Duration {
+        self.0.lock().unwrap().elapsed()
+    }
+}
+
+/// Whether a beat `since_beat` ago is recent enough to ping the
+/// watchdog every `interval`, allowing for an idle relay loop that only
+/// beats every `LIVENESS_INTERVAL`
+fn is_live(since_beat: Duration, interval: Duration) -> bool {
+    since_beat <= interval.max(2 * LIVENESS_INTERVAL)
+}
+
+/// Pings the watchdog on a timer, regardless of traffic, so that a
+/// quiet relay does not get restarted, but not once `liveness` has gone
+/// stale. Does nothing without `WatchdogSec=`.
+pub fn spawn_watchdog(liveness: Liveness) {
+    let Some(interval) = watchdog_interval(
+        std::env::var("WATCHDOG_USEC").ok().as_deref(),
+        std::env::var("WATCHDOG_PID").ok().as_deref(),
+    ) else {
+        return;
+    };
+    tracing::info!("pinging systemd watchdog every {:?}", interval);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            let since_beat = liveness.since_beat();
+            if is_live(since_beat, interval.period()) {
+                watchdog();
+            } else {
+                tracing::error!("relay loop stuck for {:?}, skipping watchdog ping", since_beat);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn watchdog_intervals() {
+        assert_eq!(watchdog_interval(Some("30000000"), None), Some(Duration::from_secs(15)));
+        let pid = std::process::id().to_string();
+        assert_eq!(watchdog_interval(Some("30000000"), Some(&pid)), Some(Duration::from_secs(15)));
+        assert_eq!(watchdog_interval(Some("30000000"), Some("1")), None);
+        assert_eq!(watchdog_interval(Some("0"), None), None);
+        assert_eq!(watchdog_interval(Some("soon"), None), None);
+        assert_eq!(watchdog_interval(None, None), None);
+    }
+
+    #[test]
+    fn liveness() {
+        let liveness = Liveness::new();
+        liveness.clone().beat();
+        assert!(is_live(liveness.since_beat(), Duration::from_secs(15)));
+        assert!(is_live(Duration::from_secs(15), Duration::from_secs(15)));
+        assert!(! is_live(Duration::from_secs(16), Duration::from_secs(15)));
+        // Short watchdog intervals still allow for idle beats
+        assert!(is_live(2 * LIVENESS_INTERVAL, Duration::from_secs(1)));
+        assert!(! is_live(3 * LIVENESS_INTERVAL, Duration::from_secs(1)));
+    }
+}
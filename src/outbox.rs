@@ -0,0 +1,71 @@
+use std::{
+    collections::VecDeque,
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+};
+use lru::LruCache;
+
+/// Actors whose recent activities are remembered
+const OUTBOX_ACTORS: usize = 4096;
+
+/// Recent activities of each relay actor, in memory only
+#[derive(Clone)]
+pub struct Outbox {
+    size: usize,
+    activities: Arc<Mutex<LruCache<String, VecDeque<serde_json::Value>>>>,
+}
+
+impl Outbox {
+    /// Keeps the last `size` activities per actor, 0 disables
+    pub fn new(size: usize) -> Self {
+        Outbox {
+            size,
+            activities: Arc::new(Mutex::new(LruCache::new(
+                NonZeroUsize::new(OUTBOX_ACTORS).unwrap()
+            ))),
+        }
+    }
+
+    pub fn push(&self, actor_id: &str, activity: &serde_json::Value) {
+        if self.size == 0 {
+            return;
+        }
+        let mut activities = self.activities.lock().unwrap();
+        let actor_activities = activities.get_or_insert_mut(actor_id.to_string(), VecDeque::new);
+        if actor_activities.len() >= self.size {
+            actor_activities.pop_back();
+        }
+        actor_activities.push_front(activity.clone());
+    }
+
+    /// Newest first
+    pub fn get(&self, actor_id: &str) -> Vec<serde_json::Value> {
+        self.activities.lock().unwrap()
+            .get(actor_id)
+            .map(|actor_activities| actor_activities.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+    use super::*;
+
+    #[test]
+    fn bounded() {
+        let outbox = Outbox::new(2);
+        for i in 0..3 {
+            outbox.push("https://relay.example/tag/rust", &json!(i));
+        }
+        assert_eq!(outbox.get("https://relay.example/tag/rust"), vec![json!(2), json!(1)]);
+        assert!(outbox.get("https://relay.example/tag/tokio").is_empty());
+    }
+
+    #[test]
+    fn disabled() {
+        let outbox = Outbox::new(0);
+        outbox.push("https://relay.example/tag/rust", &json!(0));
+        assert!(outbox.get("https://relay.example/tag/rust").is_empty());
+    }
+}
@@ -0,0 +1,107 @@
+//! Delivery through NATS for relays that leave sending to a separate
+//! fleet of delivery workers
+
+use std::sync::Arc;
+use metrics::increment_counter;
+use sigh::PrivateKey;
+use crate::{error::Error, send::{signed_post, SignedHeaders}};
+
+/// Message header with the inbox URL that the request is for
+pub const INBOX_HEADER: &str = "Inbox";
+
+/// Publishes signed requests instead of sending them
+#[derive(Clone)]
+pub struct Publisher {
+    client: async_nats::Client,
+    subject: String,
+}
+
+impl Publisher {
+    /// Reconnects by itself after connection loss
+    pub async fn connect(url: &str, subject: String) -> Result<Self, async_nats::ConnectError> {
+        let client = async_nats::connect(url).await?;
+        Ok(Publisher { client, subject })
+    }
+
+    /// Publishes the POST request that `send::send_raw()` would send:
+    /// the body as payload, and the inbox and signed HTTP headers as
+    /// message headers.
+    pub async fn publish_raw(
+        &self,
+        uri: &str,
+        key_id: &str,
+        private_key: &PrivateKey,
+        body: Arc<Vec<u8>>,
+    ) -> Result<(), Error> {
+        let url = reqwest::Url::parse(uri)
+            .map_err(|_| Error::InvalidUri)?;
+        let host = format!("{}", url.host().ok_or(Error::InvalidUri)?);
+        let req = signed_post(uri, &host, key_id, private_key, body.to_vec(), SignedHeaders::Date)?;
+        let mut headers = async_nats::HeaderMap::new();
+        headers.insert(INBOX_HEADER, uri);
+        for (name, value) in req.headers() {
+            let value = value.to_str()
+                .map_err(|e| Error::Queue(e.to_string()))?;
+            headers.insert(name.as_str(), value);
+        }
+        let result = self.client.publish_with_headers(self.subject.clone(), headers, req.into_body().into())
+            .await;
+        increment_counter!("relay_published_total", "status" => if result.is_ok() { "ok" } else { "error" });
+        result.map_err(|e| Error::Queue(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use sigh::alg::{Algorithm, RsaSha256};
+    use tokio::{io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader}, net::TcpListener};
+    use super::*;
+
+    /// Just enough of a NATS server to accept one client and return
+    /// the first message it publishes
+    async fn fake_nats(listener: TcpListener) -> String {
+        let (socket, _) = listener.accept().await.unwrap();
+        let (read, mut write) = socket.into_split();
+        write.write_all(b"INFO {\"server_id\":\"test\",\"version\":\"2.10.0\",\"headers\":true,\"max_payload\":1048576,\"proto\":1}\r\n").await.unwrap();
+        let mut read = BufReader::new(read);
+        let mut line = String::new();
+        while read.read_line(&mut line).await.unwrap() > 0 {
+            if line.starts_with("PING") {
+                write.write_all(b"PONG\r\n").await.unwrap();
+            } else if line.starts_with("HPUB ") {
+                // HPUB <subject> <header bytes> <total bytes>
+                let total_len = line.split_whitespace()
+                    .last()
+                    .and_then(|len| len.parse::<usize>().ok())
+                    .unwrap();
+                let mut message = vec![0; total_len];
+                read.read_exact(&mut message).await.unwrap();
+                return format!("{}{}", line, String::from_utf8(message).unwrap());
+            }
+            line.clear();
+        }
+        panic!("no message published");
+    }
+
+    #[tokio::test]
+    async fn publish_raw() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("nats://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(fake_nats(listener));
+
+        let publisher = Publisher::connect(&url, "deliveries".to_string()).await.unwrap();
+        let (private_key, _) = RsaSha256.generate_keys().unwrap();
+        publisher.publish_raw(
+            "https://example.com/inbox",
+            "https://relay.example/tag/rust#key",
+            &private_key,
+            Arc::new(br#"{"type":"Announce"}"#.to_vec()),
+        ).await.unwrap();
+        let message = server.await.unwrap();
+        assert!(message.starts_with("HPUB deliveries "));
+        assert!(message.contains("\r\nInbox: https://example.com/inbox\r\n"));
+        assert!(message.contains("\r\nsignature: keyId=\"https://relay.example/tag/rust#key\""));
+        assert!(message.contains("\r\ndigest: SHA-256="));
+        assert!(message.ends_with(r#"{"type":"Announce"}"#));
+    }
+}
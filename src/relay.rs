@@ -1,30 +1,71 @@
-use std::{sync::Arc, collections::{HashSet, HashMap}, time::{Duration, Instant}};
-use futures::{channel::mpsc::{channel, Sender}, StreamExt};
-use metrics::{increment_counter, histogram};
+use std::{borrow::Cow, sync::{atomic::{AtomicI64, Ordering}, Arc}, collections::{HashSet, HashMap}, future::Future, num::NonZeroUsize, time::{Duration, Instant}};
+use futures::{channel::mpsc::{channel, Sender, TrySendError}, future::poll_fn, StreamExt};
+use lru::LruCache;
+use metrics::{counter, gauge, increment_counter, histogram};
+use rand::Rng;
 use serde::Deserialize;
 use serde_json::json;
 use sigh::PrivateKey;
+use http::StatusCode;
+use tracing::Instrument;
 use tokio::{
-    sync::mpsc::Receiver,
+    sync::{mpsc::{self, Receiver}, watch},
+    task::JoinHandle,
+    time::{sleep, timeout},
 };
-use crate::{db::Database, send, actor};
+use crate::{config::{Config, DeliveryMode, RelayKind}, keys::KeyRing, domain_list::{DomainList, SharedDomainList}, db::{Announce, Database, SpooledJob}, error::Error, nodeinfo::SoftwareCache, resolver::AddressFilter, outbox::Outbox, queue::Publisher, send::{self, SignedHeaders}, stream, actor, notify};
 
-#[derive(Deserialize)]
+/// Whether `body` stays within an optional `max_body_size`
+fn body_size_allowed(body: &[u8], max_body_size: Option<usize>) -> bool {
+    max_body_size.is_none_or(|max_body_size| body.len() <= max_body_size)
+}
+
+#[derive(Deserialize, Default)]
 struct Post<'a> {
+    /// Status id on the streaming instance
+    #[serde(default)]
+    pub id: &'a str,
     pub url: Option<&'a str>,
     pub uri: &'a str,
     pub tags: Option<Vec<Tag<'a>>>,
+    #[serde(borrow)]
+    pub account: Option<Account<'a>>,
+    pub created_at: Option<&'a str>,
+    pub edited_at: Option<&'a str>,
+    pub in_reply_to_id: Option<&'a str>,
+    pub language: Option<&'a str>,
+    /// `public`, `unlisted`, `private`, or `direct`
+    pub visibility: Option<&'a str>,
+    #[serde(default)]
+    pub sensitive: bool,
+    /// Content warning
+    #[serde(default, borrow)]
+    pub spoiler_text: Cow<'a, str>,
+    /// HTML
+    #[serde(default, borrow)]
+    pub content: Cow<'a, str>,
+    /// The boosted post if this is a reblog
+    #[serde(borrow)]
+    pub reblog: Option<Box<Post<'a>>>,
 }
 
 impl Post<'_> {
+    /// The host of the instance the post originates from, taken from
+    /// the ActivityPub id because `url` may point to another host
     pub fn host(&self) -> Option<String> {
-        reqwest::Url::parse(self.url?)
+        reqwest::Url::parse(self.uri)
             .ok()
             .and_then(|url| url.domain()
                       .map(str::to_lowercase)
             )
     }
 
+    /// `created_at` as RFC3339, if it parses
+    pub fn published(&self) -> Option<String> {
+        let created_at = chrono::DateTime::parse_from_rfc3339(self.created_at?).ok()?;
+        Some(created_at.with_timezone(&chrono::Utc).to_rfc3339_opts(chrono::SecondsFormat::Secs, true))
+    }
+
     pub fn tags(&self) -> Vec<String> {
         match &self.tags {
             None =>
@@ -36,14 +77,120 @@ impl Post<'_> {
         }
     }
 
-    fn relay_target_kinds(&self) -> impl Iterator<Item = actor::ActorKind> {
-        self.host()
+    /// Keeps only the first `max_tags` tags, returning how many were
+    /// dropped
+    pub fn truncate_tags(&mut self, max_tags: usize) -> usize {
+        match &mut self.tags {
+            Some(tags) if tags.len() > max_tags => {
+                let dropped = tags.len() - max_tags;
+                tags.truncate(max_tags);
+                dropped
+            }
+            _ => 0,
+        }
+    }
+
+    /// Only public posts, and optionally unlisted ones, may be relayed.
+    /// Without a visibility, a post may be anything.
+    pub fn visibility_allowed(&self, relay_unlisted: bool) -> bool {
+        match self.visibility {
+            Some("public") => true,
+            Some("unlisted") => relay_unlisted,
+            _ => false,
+        }
+    }
+
+    /// Marked sensitive or behind a content warning
+    pub fn is_sensitive(&self) -> bool {
+        self.sensitive || ! self.spoiler_text.trim().is_empty()
+    }
+
+    /// The `content` with HTML tags removed and basic entities decoded
+    pub fn text(&self) -> String {
+        let mut text = String::with_capacity(self.content.len());
+        let mut parts = self.content.split('<');
+        text.push_str(parts.next().unwrap_or(""));
+        for part in parts {
+            let Some((tag, rest)) = part.split_once('>') else { continue };
+            let name = tag.trim_start_matches('/')
+                .split([' ', '/'])
+                .next()
+                .unwrap_or("");
+            // Unlike inline elements, these separate words
+            if ["br", "p", "div", "li"].contains(&name) {
+                text.push(' ');
+            }
+            text.push_str(rest);
+        }
+        text.replace("&lt;", "<")
+            .replace("&gt;", ">")
+            .replace("&quot;", "\"")
+            .replace("&#39;", "'")
+            .replace("&amp;", "&")
+    }
+
+    /// Links in `content`, not counting hashtags and mentions which
+    /// Mastodon marks with the `mention` class
+    pub fn link_count(&self) -> usize {
+        self.content.split('<')
+            .skip(1)
+            .filter_map(|tag| tag.split_once('>').map(|(tag, _)| tag))
+            .filter(|tag| tag.starts_with("a ") && ! tag.contains("mention"))
+            .count()
+    }
+
+    /// Checks the content against the `min_content_length`,
+    /// `max_links`, and `require_text` filters
+    pub fn content_allowed(&self, min_length: usize, max_links: Option<usize>, require_text: bool) -> bool {
+        if max_links.is_some_and(|max_links| self.link_count() > max_links) {
+            return false;
+        }
+        if min_length == 0 && ! require_text {
+            return true;
+        }
+        let text = self.text();
+        if text.trim().chars().count() < min_length {
+            return false;
+        }
+        ! require_text || text.split_whitespace()
+            .any(|word| ! word.starts_with(['#', '@']))
+    }
+
+    /// Checks the ISO 639 language against `allowed_languages`,
+    /// ignoring any region subtag
+    pub fn language_allowed(&self, allowed_languages: &HashSet<String>, allow_unknown: bool) -> bool {
+        match self.language {
+            None => allow_unknown,
+            Some(language) => {
+                let language = language.split(['-', '_'])
+                    .next()
+                    .unwrap_or(language)
+                    .to_lowercase();
+                allowed_languages.contains(&language)
+            }
+        }
+    }
+
+    /// `user@host` of the author
+    pub fn account(&self) -> Option<String> {
+        Some(format!("{}@{}", self.account.as_ref()?.username, self.host()?))
+    }
+
+    fn relay_target_kinds<'l>(&self, firehose: bool, allowed_tags: Option<&'l HashSet<String>>, followed_prefixes: &'l HashSet<String>) -> impl Iterator<Item = actor::ActorKind> + 'l {
+        firehose.then_some(actor::ActorKind::Firehose)
             .into_iter()
-            .map(actor::ActorKind::InstanceRelay)
+            .chain(
+                self.host()
+                    .map(actor::ActorKind::InstanceRelay)
+            )
+            .chain(
+                self.account()
+                    .and_then(|account| actor::ActorKind::from_account(&account))
+            )
             .chain(
                 self.tags()
                     .into_iter()
-                    .flat_map(|ref s| {
+                    .flat_map(move |ref s| {
                         // Don't handle the empty hashtag `#`
                         if s.is_empty() {
                             return vec![];
@@ -66,19 +213,25 @@ impl Post<'_> {
                                 scanning_digits = false;
                             }
                         }
+                        let mut kinds = vec![actor1];
                         if scanning_digits && first_trailing_digit > 0 {
                             let tag = &s[..first_trailing_digit];
-                            let actor2 = actor::ActorKind::from_tag(tag);
-                            vec![actor1, actor2]
-                        } else {
-                            vec![actor1]
+                            kinds.push(actor::ActorKind::from_tag(tag));
                         }
+                        kinds.extend(actor::ActorKind::tag_prefixes(s, followed_prefixes));
+                        kinds
                     })
+                    .filter(move |kind| allowed_tags.is_none_or(|allowed_tags| kind.tags_allowed(allowed_tags)))
             )
     }
 
-    pub fn relay_targets(&self, hostname: Arc<String>) -> impl Iterator<Item = actor::Actor> {
-        self.relay_target_kinds()
+    pub fn relay_targets<'l>(&self, hostname: Arc<String>, allowlist: Option<&'l DomainList>, allowed_tags: Option<&'l HashSet<String>>, followed_prefixes: &'l HashSet<String>, firehose: bool) -> impl Iterator<Item = actor::Actor> + 'l {
+        self.relay_target_kinds(firehose, allowed_tags, followed_prefixes)
+            .filter(move |kind| match kind {
+                actor::ActorKind::InstanceRelay(host) =>
+                    allowed(allowlist, host),
+                _ => true,
+            })
             .map(move |kind| actor::Actor {
                 host: hostname.clone(),
                 kind,
@@ -86,6 +239,129 @@ impl Post<'_> {
     }
 }
 
+/// Replaces `followed_prefixes` with those of the database, also
+/// dropping unfollowed ones
+async fn load_followed_prefixes(database: &Database, hostname: &str, followed_prefixes: &actor::FollowedPrefixes) {
+    let prefix = format!("https://{}/tag/", hostname);
+    match database.get_tag_prefix_actors().await {
+        Ok(actors) => followed_prefixes.replace(
+            actors
+                .filter_map(|uri| match uri.strip_prefix(&prefix)
+                            .and_then(actor::ActorKind::from_tag_path) {
+                    Some(actor::ActorKind::TagPrefixRelay(prefix)) => Some(prefix),
+                    _ => None,
+                })
+                .collect()
+        ),
+        Err(e) => {
+            tracing::error!("get_tag_prefix_actors: {}", e);
+            increment_counter!("relay_db_errors_total");
+        }
+    }
+}
+
+/// Followed tag set actors that include any tags of the `targets`
+async fn tag_set_targets(database: &Database, hostname: &Arc<String>, targets: &[actor::Actor]) -> Vec<actor::Actor> {
+    let tags = targets.iter()
+        .filter_map(|actor| match &actor.kind {
+            actor::ActorKind::TagRelay(tag) => Some(tag.clone()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    if tags.is_empty() {
+        return vec![];
+    }
+
+    let prefix = format!("https://{}/tag/", hostname);
+    match database.get_tag_sets(&tags).await {
+        Ok(actors) => actors
+            .filter_map(|uri| uri.strip_prefix(&prefix)
+                        .and_then(actor::ActorKind::from_tag_path))
+            .map(|kind| actor::Actor {
+                host: hostname.clone(),
+                kind,
+            })
+            .collect(),
+        Err(e) => {
+            tracing::error!("get_tag_sets: {}", e);
+            increment_counter!("relay_db_errors_total");
+            vec![]
+        }
+    }
+}
+
+/// Sorts by `ActorKind::precedence()`, removing duplicates
+fn sort_targets(targets: &mut Vec<actor::Actor>) {
+    targets.sort_by(|a, b| a.kind.precedence().cmp(&b.kind.precedence())
+                    .then_with(|| a.kind.cmp(&b.kind)));
+    targets.dedup();
+}
+
+/// Assigns every inbox only to the first of the `actor_ids` that it
+/// follows, so that each instance receives a post once.
+///
+/// `actor_ids` should be in the order of `sort_targets()` to make the
+/// choice deterministic.
+fn assign_inboxes(actor_ids: &[Arc<String>], mut following_inboxes: HashMap<String, Vec<String>>) -> Vec<Vec<String>> {
+    let mut seen_inboxes = HashSet::new();
+    actor_ids.iter()
+        .map(|actor_id| {
+            let mut inboxes = following_inboxes.remove(actor_id.as_str())
+                .unwrap_or_default();
+            inboxes.sort();
+            inboxes.retain(|inbox| seen_inboxes.insert(inbox.clone()));
+            inboxes
+        })
+        .collect()
+}
+
+/// The id of an activity by `actor_id` about `object`: distinct
+/// across actors, so that receivers do not drop one actor's Announce
+/// as a duplicate of another's, but the same on every retry
+fn activity_id(actor_id: &str, activity: &str, object: &str) -> String {
+    let hash = openssl::sha::sha256(object.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>();
+    format!("{}/{}/{}", actor_id, activity, hash)
+}
+
+/// Undo of the Announce `announce_id` of `object` by `actor`
+fn undo_announce(jsonld_context: &serde_json::Value, actor: &str, announce_id: &str, object: &str, undo_id: &str) -> serde_json::Value {
+    json!({
+        "@context": jsonld_context,
+        "type": "Undo",
+        "actor": actor,
+        "to": ["https://www.w3.org/ns/activitystreams#Public"],
+        "object": {
+            "type": "Announce",
+            "id": announce_id,
+            "actor": actor,
+            "object": object,
+        },
+        "id": undo_id,
+    })
+}
+
+/// Whether `host` passes an optional allowlist
+fn allowed(allowlist: Option<&DomainList>, host: &str) -> bool {
+    allowlist.is_none_or(|allowlist| allowlist.contains(host))
+}
+
+/// Whether a post from `host` passes an optional `source_allowlist`,
+/// which posts without a host never do
+fn source_allowed(source_allowlist: Option<&DomainList>, host: Option<&str>) -> bool {
+    match host {
+        Some(host) => allowed(source_allowlist, host),
+        None => source_allowlist.is_none(),
+    }
+}
+
+#[derive(Deserialize, Default)]
+struct Account<'a> {
+    pub username: &'a str,
+}
+
 #[derive(Deserialize)]
 struct Tag<'a> {
     pub name: &'a str,
@@ -98,72 +374,842 @@ struct Job {
     key_id: String,
     private_key: Arc<PrivateKey>,
     inbox_url: reqwest::Url,
+    /// Delivery attempts so far
+    attempts: u32,
 }
 
-fn spawn_worker(client: Arc<reqwest::Client>) -> Sender<Job> {
-    let (tx, mut rx) = channel(1024);
+/// Workers without jobs for this long exit
+const WORKER_IDLE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+/// How long stopped workers get to hand back their queued jobs
+const ABORT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Interval for writing the collected `ActorStats` to the database
+const STATS_FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+/// Subsequent HTTP 404 responses after which an inbox is considered gone
+const NOT_FOUND_THRESHOLD: u32 = 5;
+/// Delay after the first failed delivery to an inbox
+const MIN_BACKOFF: Duration = Duration::from_secs(10);
+/// Upper bound for the delay between delivery attempts
+const MAX_BACKOFF: Duration = Duration::from_secs(30 * 60);
 
-    tokio::spawn(async move {
-        let mut errors = 0u32;
-        let mut last_request = None;
-
-        while let Some(Job { post_url, actor_id, key_id, private_key, body, inbox_url }) = rx.next().await {
-            if errors > 0 && last_request.map_or(false, |last_request|
-                Instant::now() - last_request < Duration::from_secs(10) * errors
-            ) {
-                // there have been errors, skip for time proportional
-                // to the number of subsequent errors
-                tracing::trace!("skip {} from {} to {}", post_url, actor_id, inbox_url);
-                continue;
+/// Exponential backoff with jitter after `errors` subsequent failures
+fn backoff(errors: u32) -> Duration {
+    let delay = MIN_BACKOFF
+        .saturating_mul(1 << errors.saturating_sub(1).min(16))
+        .min(MAX_BACKOFF);
+    // spread retries of many inboxes over [delay/2, delay)
+    delay.mul_f64(rand::thread_rng().gen_range(0.5..1.0))
+}
+
+/// Consecutive errors in all workers
+static WORKER_ERRORS: AtomicI64 = AtomicI64::new(0);
+/// Workers with consecutive errors
+static FAILING_WORKERS: AtomicI64 = AtomicI64::new(0);
+
+fn track_worker_errors(errors_delta: i64, failing_delta: i64) {
+    let errors = WORKER_ERRORS.fetch_add(errors_delta, Ordering::Relaxed) + errors_delta;
+    gauge!("relay_worker_consecutive_errors", errors as f64);
+    if failing_delta != 0 {
+        let failing = FAILING_WORKERS.fetch_add(failing_delta, Ordering::Relaxed) + failing_delta;
+        gauge!("relay_workers_failing", failing as f64);
+    }
+}
+
+/// Subsequent failed deliveries of a worker, which determine its
+/// backoff. Summed up over all workers in the
+/// `relay_worker_consecutive_errors` gauge.
+#[derive(Default)]
+struct ConsecutiveErrors(u32);
+
+impl ConsecutiveErrors {
+    fn get(&self) -> u32 {
+        self.0
+    }
+
+    fn fail(&mut self) {
+        if self.0 == u32::MAX {
+            return;
+        }
+        if self.0 == 0 {
+            tracing::warn!("inbox started failing");
+        }
+        self.0 += 1;
+        track_worker_errors(1, if self.0 == 1 { 1 } else { 0 });
+    }
+
+    fn succeed(&mut self) {
+        if self.0 > 0 {
+            tracing::info!(errors = self.0, "inbox recovered");
+            track_worker_errors(-i64::from(self.0), -1);
+            self.0 = 0;
+        }
+    }
+}
+
+impl Drop for ConsecutiveErrors {
+    /// Workers exit when idle, even if still failing
+    fn drop(&mut self) {
+        if self.0 > 0 {
+            track_worker_errors(-i64::from(self.0), -1);
+        }
+    }
+}
+
+/// Enforces a minimum interval between requests
+struct Throttle {
+    interval: Option<Duration>,
+    last_request: Option<Instant>,
+}
+
+impl Throttle {
+    fn new(interval: Option<Duration>) -> Self {
+        Throttle {
+            interval,
+            last_request: None,
+        }
+    }
+
+    async fn wait(&mut self) {
+        if let (Some(interval), Some(last_request)) = (self.interval, self.last_request) {
+            let elapsed = last_request.elapsed();
+            if elapsed < interval {
+                sleep(interval - elapsed).await;
             }
+        }
+        self.last_request = Some(Instant::now());
+    }
+}
+
+/// Outcome of `deliver()`
+#[derive(Debug, PartialEq, Eq)]
+enum Delivery {
+    Delivered,
+    /// The inbox does not exist anymore
+    Gone,
+    /// Still failing after the maximum number of attempts
+    Abandoned,
+}
 
-            tracing::debug!("relay {} from {} to {}", post_url, actor_id, inbox_url);
-            last_request = Some(Instant::now());
-            if let Err(e) = send::send_raw(
-                &client, inbox_url.as_str(),
-                &key_id, &private_key, body
-            ).await {
+/// Retries `send` until it succeeds, sleeping for the backoff of
+/// `errors` (or the remote's `Retry-After:`) before each attempt.
+/// `send` is called anew each time so that the request gets signed
+/// with a current `Date:`.
+///
+/// Gives up if the inbox responds with HTTP 410 Gone, or a number of
+/// subsequent HTTP 404 Not Found, or once `attempts` reaches
+/// `max_attempts`.
+async fn deliver<F, R>(
+    errors: &mut ConsecutiveErrors,
+    attempts: &mut u32,
+    max_attempts: u32,
+    throttle: &mut Throttle,
+    mut send: F,
+) -> Delivery
+where
+    F: FnMut() -> R,
+    R: Future<Output = Result<(), Error>>,
+{
+    let mut retry_after = None;
+    let mut not_found = 0;
+    loop {
+        if *attempts >= max_attempts {
+            return Delivery::Abandoned;
+        }
+        if let Some(delay) = retry_after.take() {
+            sleep(delay).await;
+        } else if errors.get() > 0 {
+            sleep(backoff(errors.get())).await;
+        }
+        throttle.wait().await;
+
+        *attempts += 1;
+        match send().await {
+            Ok(()) => {
+                errors.succeed();
+                return Delivery::Delivered;
+            }
+            Err(Error::RateLimited(delay)) => {
+                tracing::warn!("relay::send rate limited, retry after {:?}", delay);
+                errors.fail();
+                retry_after = delay.map(|delay| delay.min(MAX_BACKOFF));
+            }
+            Err(Error::Response(StatusCode::GONE, _)) => {
+                return Delivery::Gone;
+            }
+            Err(Error::BlockedAddress) => {
+                return Delivery::Abandoned;
+            }
+            // anything else, including timeouts, is retried
+            Err(e) => {
                 tracing::error!("relay::send {:?}", e);
-                errors = errors.saturating_add(1);
-            } else {
-                // success
-                errors = 0;
-                systemd::daemon::notify(
-                    false, [
-                        (systemd::daemon::STATE_WATCHDOG, "1")
-                    ].iter()
-                ).unwrap();
+                errors.fail();
+                if matches!(e, Error::Response(StatusCode::NOT_FOUND, _)) {
+                    not_found += 1;
+                    if not_found >= NOT_FOUND_THRESHOLD {
+                        return Delivery::Gone;
+                    }
+                }
             }
         }
+    }
+}
+
+/// Where workers deliver jobs to
+#[derive(Clone)]
+enum Sink {
+    /// With the software of inbox hosts if `detect_software` is set
+    Http(Arc<reqwest::Client>, AddressFilter, Option<Arc<SoftwareCache>>),
+    Queue(Publisher),
+}
+
+/// Jobs in all worker queues, shared by `Workers` and the workers.
+/// Jobs leave a queue when a worker takes them, including those that
+/// it hands back when stopped.
+#[derive(Clone, Default)]
+struct QueueDepth(Arc<AtomicI64>);
+
+impl QueueDepth {
+    fn add(&self, delta: i64) {
+        let depth = self.0.fetch_add(delta, Ordering::Relaxed) + delta;
+        gauge!("relay_worker_queue_depth", depth as f64);
+    }
+}
+
+/// What every worker gets a copy of
+#[derive(Clone)]
+struct WorkerContext {
+    sink: Sink,
+    config: Arc<Config>,
+    gone_tx: mpsc::Sender<reqwest::Url>,
+    delivered: DeliveredActors,
+    queue_depth: QueueDepth,
+    abort: watch::Receiver<bool>,
+    paused: watch::Receiver<bool>,
+    leftover_tx: mpsc::UnboundedSender<Job>,
+}
+
+/// Once `abort` is set, the worker stops delivering and hands the
+/// current job and what is left in its queue to `leftover_tx`. While
+/// `paused` is set, the worker holds its jobs.
+fn spawn_worker(ctx: WorkerContext) -> Sender<Job> {
+    let WorkerContext { sink, config, gone_tx, delivered, queue_depth, mut abort, mut paused, leftover_tx } = ctx;
+    let (tx, mut rx) = channel(config.worker_queue_size.get());
+
+    tokio::spawn(async move {
+        let mut errors = ConsecutiveErrors::default();
+        let mut throttle = Throttle::new(config.min_request_interval());
+        let mut idle = false;
 
-        panic!("Worker dead");
+        let aborted = loop {
+            let next = tokio::select! {
+                biased;
+                _ = abort.wait_for(|abort| *abort) => break true,
+                next = timeout(WORKER_IDLE_TIMEOUT, rx.next()) => next,
+            };
+            let mut job = match next {
+                Ok(Some(job)) => job,
+                Ok(None) => {
+                    if ! idle {
+                        tracing::warn!("relay worker channel closed");
+                    }
+                    break false;
+                }
+                Err(_) => {
+                    // Idle: refuse new jobs, then deliver what has
+                    // been enqueued until now.
+                    tracing::debug!("relay worker idle, exiting");
+                    rx.close();
+                    idle = true;
+                    continue;
+                }
+            };
+            queue_depth.add(-1);
+            let aborted = tokio::select! {
+                biased;
+                _ = abort.wait_for(|abort| *abort) => true,
+                _ = async {
+                    // Fails only once `Workers` are gone
+                    let _ = paused.wait_for(|paused| ! *paused).await;
+                    process_job(&sink, &gone_tx, &delivered, &mut errors, config.max_attempts.get(), &mut throttle, &mut job).await
+                } => false,
+            };
+            if aborted {
+                let _ = leftover_tx.send(job);
+                break true;
+            }
+        };
+        if aborted {
+            rx.close();
+            while let Ok(Some(job)) = rx.try_next() {
+                queue_depth.add(-1);
+                let _ = leftover_tx.send(job);
+            }
+        }
     });
 
     tx
 }
 
+async fn process_job(
+    sink: &Sink,
+    gone_tx: &mpsc::Sender<reqwest::Url>,
+    delivered: &DeliveredActors,
+    errors: &mut ConsecutiveErrors,
+    max_attempts: u32,
+    throttle: &mut Throttle,
+    job: &mut Job,
+) {
+    let Job { post_url, actor_id, key_id, private_key, body, inbox_url, attempts } = job;
+    let span = tracing::info_span!(
+        "deliver",
+        post_url = %post_url,
+        actor_id = %actor_id,
+        inbox = %inbox_url,
+    );
+    tracing::debug!(parent: &span, "relay");
+    let signed_headers = match sink {
+        Sink::Http(client, _, Some(software)) =>
+            software.signed_headers(client, inbox_url.host_str().unwrap_or(""))
+                .instrument(span.clone())
+                .await,
+        _ =>
+            SignedHeaders::Date,
+    };
+    let delivery = deliver(errors, attempts, max_attempts, throttle, || {
+        let body = body.clone();
+        let (inbox, key_id, private_key) = (inbox_url.as_str(), &key_id, &private_key);
+        async move {
+            match sink {
+                Sink::Http(client, filter, _) =>
+                    send::send_raw(client, filter, inbox, key_id, private_key, body, signed_headers).await,
+                Sink::Queue(publisher) =>
+                    publisher.publish_raw(inbox, key_id, private_key, body).await,
+            }
+        }
+    }).instrument(span.clone()).await;
+
+    match delivery {
+        Delivery::Delivered => {
+            delivered.add(actor_id);
+        }
+        Delivery::Gone => {
+            tracing::warn!(parent: &span, "inbox is gone");
+            let _ = gone_tx.try_send(inbox_url.clone());
+        }
+        Delivery::Abandoned => {
+            tracing::warn!(parent: &span, attempts = *attempts, "abandoning job");
+            increment_counter!("relay_jobs_abandoned_total");
+        }
+    }
+}
+
+/// Removes inboxes reported as gone by the workers from the database.
+///
+/// Finishes once all workers have exited.
+fn spawn_inbox_remover(database: Database) -> (mpsc::Sender<reqwest::Url>, JoinHandle<()>) {
+    let (tx, mut rx) = mpsc::channel::<reqwest::Url>(1024);
+
+    let handle = tokio::spawn(async move {
+        while let Some(inbox) = rx.recv().await {
+            match database.remove_gone_inbox(inbox.as_str()).await {
+                Ok(count) => {
+                    tracing::info!(%inbox, count, "removed follows of gone inbox");
+                    increment_counter!("relay_inboxes_removed_total");
+                }
+                Err(e) => {
+                    tracing::error!("remove_gone_inbox: {}", e);
+                    increment_counter!("relay_db_errors_total");
+                }
+            }
+        }
+    });
+
+    (tx, handle)
+}
+
+/// Announces per actor, counted in memory to keep the database off
+/// the fan-out path
+#[derive(Default)]
+struct ActorStats {
+    announces: HashMap<Arc<String>, i64>,
+}
+
+impl ActorStats {
+    fn add(&mut self, actor_id: &Arc<String>) {
+        *self.announces.entry(actor_id.clone()).or_insert(0) += 1;
+    }
+
+    fn take(&mut self) -> (Vec<String>, Vec<i64>) {
+        self.announces.drain()
+            .map(|(actor_id, announces)| (actor_id.to_string(), announces))
+            .unzip()
+    }
+
+    async fn write(actors: Vec<String>, announces: Vec<i64>, database: &Database) {
+        if actors.is_empty() {
+            return;
+        }
+        if let Err(e) = database.add_actor_stats(&actors, &announces).await {
+            tracing::error!("add_actor_stats: {}", e);
+            increment_counter!("relay_db_errors_total");
+        }
+    }
+
+    /// Writes in the background
+    fn flush(&mut self, database: &Database) {
+        let (actors, announces) = self.take();
+        let database = database.clone();
+        tokio::spawn(async move {
+            Self::write(actors, announces, &database).await;
+        });
+    }
+}
+
+/// Actors that delivered to any inbox since the last flush, shared
+/// with the workers so that the database sees one write per actor
+/// and interval instead of one per inbox
+#[derive(Clone, Default)]
+struct DeliveredActors(Arc<std::sync::Mutex<HashSet<Arc<String>>>>);
+
+impl DeliveredActors {
+    fn add(&self, actor_id: &Arc<String>) {
+        self.0.lock().unwrap()
+            .insert(actor_id.clone());
+    }
+
+    fn take(&self) -> Vec<String> {
+        self.0.lock().unwrap()
+            .drain()
+            .map(|actor_id| actor_id.to_string())
+            .collect()
+    }
+
+    async fn write(actors: Vec<String>, database: &Database) {
+        if actors.is_empty() {
+            return;
+        }
+        if let Err(e) = database.add_actor_deliveries(&actors).await {
+            tracing::error!("add_actor_deliveries: {}", e);
+            increment_counter!("relay_db_errors_total");
+        }
+    }
+
+    /// Writes in the background
+    fn flush(&self, database: &Database) {
+        let actors = self.take();
+        let database = database.clone();
+        tokio::spawn(async move {
+            Self::write(actors, &database).await;
+        });
+    }
+}
+
+/// Operator requests from the admin API
+pub enum Command {
+    /// Reread `blocklist_file`
+    ReloadBlocklist,
+    /// Replace all workers, the old ones still finish their queues
+    RespawnWorkers,
+    /// Consume the streams and hold queued deliveries until `Resume`.
+    /// Deletes still get queued.
+    Pause,
+    Resume,
+}
+
+/// Store a job for `Workers::replay_spool()`, returns `false` if that
+/// failed
+async fn spool_job(database: &Database, job: &Job, reason: &'static str) -> bool {
+    match database.add_spool(&job.post_url, &job.actor_id, job.inbox_url.as_str(), &job.key_id, &job.body, job.attempts).await {
+        Ok(()) => {
+            tracing::debug!(inbox = %job.inbox_url, reason, "spooling job");
+            increment_counter!("relay_jobs_spooled_total", "reason" => reason);
+            true
+        }
+        Err(e) => {
+            tracing::error!("add_spool: {}", e);
+            increment_counter!("relay_db_errors_total");
+            false
+        }
+    }
+}
+
+/// Per-inbox-host worker queues
+struct Workers {
+    context: WorkerContext,
+    config: Arc<Config>,
+    database: Database,
+    inbox_remover: JoinHandle<()>,
+    senders: HashMap<String, Sender<Job>>,
+    /// Stops the workers, see `spawn_worker()`
+    abort: watch::Sender<bool>,
+    /// Holds deliveries of all workers
+    paused: watch::Sender<bool>,
+    leftovers: mpsc::UnboundedReceiver<Job>,
+    /// Also for jobs from the spool or for Undos, which did not pass
+    /// the checks of fan-out
+    address_filter: AddressFilter,
+}
+
+impl Workers {
+    /// Publishes to the queue instead of sending if there is a
+    /// `publisher`
+    fn new(client: Arc<reqwest::Client>, publisher: Option<Publisher>, config: Arc<Config>, database: Database) -> Self {
+        let (gone_tx, inbox_remover) = spawn_inbox_remover(database.clone());
+        let address_filter = config.address_filter();
+        let (leftover_tx, leftovers) = mpsc::unbounded_channel();
+        let (abort, abort_rx) = watch::channel(false);
+        let (paused, paused_rx) = watch::channel(false);
+        Workers {
+            context: WorkerContext {
+                sink: publisher.map_or_else(|| {
+                    // Detection would change nothing
+                    let software = (config.detect_software && ! config.sign_created_software.is_empty())
+                        .then(|| Arc::new(SoftwareCache::new(&config.sign_created_software, address_filter.clone())));
+                    Sink::Http(client, address_filter.clone(), software)
+                }, Sink::Queue),
+                config: config.clone(),
+                gone_tx,
+                delivered: DeliveredActors::default(),
+                queue_depth: QueueDepth::default(),
+                abort: abort_rx,
+                paused: paused_rx,
+                leftover_tx,
+            },
+            config,
+            database,
+            inbox_remover,
+            senders: HashMap::new(),
+            abort,
+            paused,
+            leftovers,
+            address_filter,
+        }
+    }
+
+    /// Lookup/create worker queue for the job's inbox and enqueue it
+    async fn enqueue(&mut self, job: Job) {
+        if self.address_filter.check_url(&job.inbox_url).is_err() {
+            increment_counter!("relay_jobs_dropped_total", "reason" => "blocked_address");
+            return;
+        }
+        if self.config.dry_run {
+            tracing::info!(
+                post_url = %job.post_url,
+                actor_id = %job.actor_id,
+                inbox = %job.inbox_url,
+                body_size = job.body.len(),
+                "dry run"
+            );
+            increment_counter!("relay_dryrun_deliveries_total");
+            return;
+        }
+        let host = job.inbox_url.host_str().unwrap_or("").to_string();
+        let tx = self.senders.entry(host)
+            .or_insert_with(|| spawn_worker(self.context.clone()));
+        if tx.is_closed() {
+            // Exited after idling, not reaped yet
+            *tx = spawn_worker(self.context.clone());
+        }
+        let result = match tx.try_send(job) {
+            Err(e) if e.is_disconnected() => {
+                // Worker is gone, replace it.
+                let job = e.into_inner();
+                tracing::warn!(inbox = %job.inbox_url, "respawning worker");
+                increment_counter!("relay_worker_restarts_total");
+                *tx = spawn_worker(self.context.clone());
+                tx.try_send(job)
+            }
+            Err(e) if e.is_full() && self.config.delivery_mode == DeliveryMode::Block => {
+                // Slow down intake until there is room in the queue
+                let job = e.into_inner();
+                let _ = timeout(self.config.block_timeout(), poll_fn(|cx| tx.poll_ready(cx))).await;
+                tx.try_send(job)
+            }
+            result => result,
+        };
+        match result {
+            Ok(()) => self.context.queue_depth.add(1),
+            Err(e) => self.job_dropped(e).await,
+        }
+    }
+
+    /// Spool a job that could not be enqueued if enabled, drop otherwise
+    async fn job_dropped(&self, e: TrySendError<Job>) {
+        let reason = if e.is_full() {
+            "full"
+        } else {
+            "disconnected"
+        };
+        let job = e.into_inner();
+        if ! self.config.spool || ! spool_job(&self.database, &job, reason).await {
+            tracing::debug!(inbox = %job.inbox_url, reason, "dropping job");
+            increment_counter!("relay_jobs_dropped_total", "reason" => reason);
+        }
+    }
+
+    /// Current key id and private key of an actor that this relay
+    /// generated
+    fn signing_key(&self, keys: &KeyRing, actor_id: &str) -> Option<(String, Arc<PrivateKey>)> {
+        let Some(kind) = RelayKind::from_actor_uri(actor_id) else {
+            tracing::warn!(actor = actor_id, "no key for unknown actor");
+            return None;
+        };
+        Some((self.config.key_id(kind, actor_id), keys.get(kind).private_key.clone()))
+    }
+
+    /// Enqueue the jobs spooled by a previous run
+    async fn replay_spool(&mut self, keys: &KeyRing) {
+        self.prune_spool().await;
+        let spooled = match self.database.take_spool().await {
+            Ok(spooled) => spooled,
+            Err(e) => {
+                tracing::error!("take_spool: {}", e);
+                increment_counter!("relay_db_errors_total");
+                return;
+            }
+        };
+        for SpooledJob { post_url, actor_id, inbox, body, attempts } in spooled {
+            let Ok(inbox_url) = reqwest::Url::parse(&inbox) else { continue; };
+            // The stored key id may predate a key rotation
+            let Some((key_id, private_key)) = self.signing_key(keys, &actor_id) else { continue; };
+            self.enqueue(Job {
+                post_url: Arc::new(post_url),
+                actor_id: Arc::new(actor_id),
+                body: Arc::new(body),
+                key_id,
+                private_key,
+                inbox_url,
+                attempts,
+            }).await;
+        }
+    }
+
+    async fn prune_spool(&self) {
+        if let Err(e) = self.database.prune_spool(self.config.spool_ttl()).await {
+            tracing::error!("prune_spool: {}", e);
+            increment_counter!("relay_db_errors_total");
+        }
+    }
+
+    /// Undo the Announces of a deleted status
+    async fn relay_delete(&mut self, hostname: &str, keys: &KeyRing, stream: &str, status_id: &str) {
+        let announces = match self.database.take_announces(stream, status_id).await {
+            Ok(announces) => announces,
+            Err(e) => {
+                tracing::error!("take_announces: {}", e);
+                increment_counter!("relay_db_errors_total");
+                return;
+            }
+        };
+        for Announce { actor, announce_id, object, inboxes, .. } in announces {
+            let Some((key_id, private_key)) = self.signing_key(keys, &actor) else { continue; };
+            let undo_id = format!("https://{}/undo/{}", hostname, urlencoding::encode(&announce_id));
+            let body = undo_announce(&self.config.jsonld_context(), &actor, &announce_id, &object, &undo_id);
+            let body = match serde_json::to_vec(&body) {
+                Ok(body) => Arc::new(body),
+                Err(e) => {
+                    tracing::error!("serialize undo of {}: {}", object, e);
+                    increment_counter!("relay_serialize_errors_total");
+                    continue;
+                }
+            };
+            let object = Arc::new(object);
+            let actor = Arc::new(actor);
+            for inbox in inboxes {
+                let Ok(inbox_url) = reqwest::Url::parse(&inbox) else { continue; };
+                self.enqueue(Job {
+                    post_url: object.clone(),
+                    actor_id: actor.clone(),
+                    body: body.clone(),
+                    key_id: key_id.clone(),
+                    private_key: private_key.clone(),
+                    inbox_url,
+                    attempts: 0,
+                }).await;
+            }
+            increment_counter!("relay_posts_total", "action" => "undo");
+        }
+    }
+
+    /// Forget workers that have exited
+    fn reap(&mut self) {
+        self.senders.retain(|_, tx| !tx.is_closed());
+    }
+
+    /// Stop all workers so that new ones get spawned on demand. The
+    /// stopped workers hand back their jobs to `leftovers` for
+    /// enqueueing again.
+    fn respawn(&mut self) {
+        tracing::info!("respawning {} workers", self.senders.len());
+        self.senders.clear();
+        let (abort, abort_rx) = watch::channel(false);
+        std::mem::replace(&mut self.abort, abort)
+            .send_replace(true);
+        self.context.abort = abort_rx;
+    }
+
+    /// Close all queues and wait up to `grace` for the workers to
+    /// deliver what is left in them. Jobs that are still queued after
+    /// that get spooled if enabled.
+    async fn shutdown(self, grace: Duration) {
+        let Workers { context, config, database, mut inbox_remover, senders, abort, mut leftovers, .. } = self;
+        tracing::info!("draining {} workers", senders.len());
+        drop(senders);
+        // Workers hold the other `gone_tx`, so `inbox_remover` exits
+        // with the last worker.
+        drop(context);
+        if timeout(grace, &mut inbox_remover).await.is_err() {
+            tracing::warn!("workers still busy after {:?}, stopping them", grace);
+            let _ = abort.send(true);
+            if timeout(ABORT_TIMEOUT, &mut inbox_remover).await.is_err() {
+                tracing::error!("workers did not stop");
+            }
+        }
+        let mut undelivered = 0;
+        while let Ok(job) = leftovers.try_recv() {
+            undelivered += 1;
+            if ! config.spool || ! spool_job(&database, &job, "shutdown").await {
+                increment_counter!("relay_jobs_dropped_total", "reason" => "shutdown");
+            }
+        }
+        if undelivered > 0 {
+            tracing::warn!(spooled = config.spool, "{} jobs left undelivered", undelivered);
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn spawn(
     client: Arc<reqwest::Client>,
+    publisher: Option<Publisher>,
     hostname: Arc<String>,
     database: Database,
-    private_key: PrivateKey,
-    mut stream_rx: Receiver<String>
-) {
-    let private_key = Arc::new(private_key);
-
+    keys: KeyRing,
+    blocklist: SharedDomainList,
+    mut stream_rx: Receiver<stream::SourcedEvent>,
+    config: Arc<Config>,
+    outbox: Outbox,
+    mut commands: Receiver<Command>,
+    mut shutdown: watch::Receiver<bool>,
+    liveness: notify::Liveness,
+    followed_prefixes: actor::FollowedPrefixes,
+) -> JoinHandle<()> {
     tokio::spawn(async move {
-        let mut workers = HashMap::new();
+        let mut workers = Workers::new(client, publisher, config.clone(), database.clone());
+        let mut last_reap = Instant::now();
+        let mut stats = ActorStats::default();
+        let mut last_stats_flush = last_reap;
+        let jsonld_context = config.jsonld_context();
+        let allowed_tags = config.allowed_tags();
+        let mut seen_posts = NonZeroUsize::new(config.dedup_cache_size)
+            .map(LruCache::new);
+        let address_filter = config.address_filter();
+        gauge!("relay_paused", 0.0);
+        load_followed_prefixes(&database, &hostname, &followed_prefixes).await;
+        if config.spool {
+            workers.replay_spool(&keys).await;
+        }
+
+        let mut liveness_tick = tokio::time::interval(notify::LIVENESS_INTERVAL);
 
-        while let Some(data) = stream_rx.recv().await {
+        loop {
+            liveness.beat();
+            let (source, event) = tokio::select! {
+                event = stream_rx.recv() => match event {
+                    Some(event) => event,
+                    None => break,
+                },
+                Some(command) = commands.recv() => {
+                    match command {
+                        Command::ReloadBlocklist => match config.try_blocklist() {
+                            Ok(new_blocklist) => {
+                                blocklist.replace(new_blocklist);
+                                tracing::info!("reloaded blocklist");
+                            }
+                            Err(e) =>
+                                tracing::error!("reload blocklist: {}", e),
+                        },
+                        Command::RespawnWorkers =>
+                            workers.respawn(),
+                        Command::Pause | Command::Resume => {
+                            let paused = matches!(command, Command::Pause);
+                            workers.paused.send_replace(paused);
+                            tracing::warn!(paused, "relaying {}", if paused { "paused" } else { "resumed" });
+                            gauge!("relay_paused", if paused { 1.0 } else { 0.0 });
+                        }
+                    }
+                    continue;
+                }
+                Some(job) = workers.leftovers.recv() => {
+                    // From respawned workers
+                    workers.enqueue(job).await;
+                    continue;
+                }
+                _ = liveness_tick.tick() => continue,
+                _ = shutdown.changed() => break,
+            };
             let t1 = Instant::now();
+            if t1 - last_reap >= WORKER_IDLE_TIMEOUT {
+                workers.reap();
+                load_followed_prefixes(&database, &hostname, &followed_prefixes).await;
+                if config.spool {
+                    // Jobs that have been spooled while their queue was
+                    // full
+                    workers.replay_spool(&keys).await;
+                }
+                if config.relay_deletes {
+                    if let Err(e) = database.prune_announces(config.delivery_log_ttl()).await {
+                        tracing::error!("prune_announces: {}", e);
+                        increment_counter!("relay_db_errors_total");
+                    }
+                }
+                last_reap = t1;
+            }
+            if t1 - last_stats_flush >= STATS_FLUSH_INTERVAL {
+                stats.flush(&database);
+                workers.context.delivered.flush(&database);
+                last_stats_flush = t1;
+            }
+            let (data, edit) = match event {
+                // Undos of what has been relayed before pausing
+                stream::Event::Delete(status_id) => {
+                    if config.relay_deletes {
+                        workers.relay_delete(&hostname, &keys, &source, &status_id).await;
+                    }
+                    continue;
+                }
+                _ if *workers.paused.borrow() => {
+                    increment_counter!("relay_posts_total", "action" => "paused");
+                    continue;
+                }
+                stream::Event::Update(data) => (data, false),
+                stream::Event::StatusUpdate(data) if config.relay_updates => (data, true),
+                stream::Event::StatusUpdate(_) => continue,
+            };
             let post: Post = match serde_json::from_str(&data) {
                 Ok(post) => post,
                 Err(e) => {
                     tracing::error!("parse error: {}", e);
                     tracing::trace!("data: {}", data);
+                    increment_counter!("relay_parse_errors_total");
                     continue;
                 }
             };
+            let mut post = match post.reblog {
+                Some(reblog) if post.url.is_none() && config.relay_reblogs =>
+                    *reblog,
+                _ => post,
+            };
+            if ! post.visibility_allowed(config.relay_unlisted) {
+                match post.visibility {
+                    Some("unlisted") => {}
+                    None =>
+                        tracing::warn!(uri = post.uri, "post without visibility in stream"),
+                    Some(visibility) =>
+                        tracing::warn!(uri = post.uri, visibility, "non-public post in stream"),
+                }
+                increment_counter!("relay_posts_total", "action" => "visibility");
+                continue;
+            }
             let post_url = match post.url {
                 Some(ref url) => Arc::new(url.to_string()),
                 // skip reposts
@@ -172,77 +1218,222 @@ pub fn spawn(
                     continue;
                 }
             };
-            let mut seen_actors = HashSet::new();
-            let mut seen_inboxes = HashSet::new();
-            let published = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
-            for actor in post.relay_targets(hostname.clone()) {
-                if seen_actors.contains(&actor) {
+            if let Some(seen_posts) = seen_posts.as_mut() {
+                if ! edit && seen_posts.put(post.uri.to_string(), ()).is_some() {
+                    increment_counter!("relay_posts_total", "action" => "duplicate");
+                    continue;
+                }
+            }
+            if let Some(allowed_languages) = &config.allowed_languages {
+                if ! post.language_allowed(allowed_languages, config.allow_unknown_language) {
+                    increment_counter!("relay_posts_total", "action" => "language");
+                    continue;
+                }
+            }
+            if ! config.sensitive_policy.allows(post.is_sensitive()) {
+                increment_counter!("relay_posts_total", "action" => "sensitive");
+                continue;
+            }
+            if ! post.content_allowed(config.min_content_length, config.max_links, config.require_text) {
+                increment_counter!("relay_posts_total", "action" => "filtered");
+                continue;
+            }
+            // Blocked source domain
+            match post.host() {
+                Some(host) if blocklist.contains(&host) => {
+                    increment_counter!("relay_blocked_total", "domain" => host);
                     continue;
                 }
+                Some(_) => {}
+                None =>
+                    tracing::warn!(uri = post.uri, "no host in post uri, skipping instance relays"),
+            }
+            if ! source_allowed(config.source_allowlist(), post.host().as_deref()) {
+                increment_counter!("relay_posts_total", "action" => "source_not_allowed");
+                continue;
+            }
+            let dropped_tags = post.truncate_tags(config.max_tags_per_post);
+            if dropped_tags > 0 {
+                tracing::warn!(uri = post.uri, dropped_tags, "too many tags");
+                counter!("relay_tags_dropped_total", dropped_tags as u64);
+            }
+            let mut targets = post.relay_targets(hostname.clone(), config.allowlist(), allowed_tags.as_ref(), &followed_prefixes.get(), config.firehose)
+                .collect::<Vec<_>>();
+            let tag_sets = tag_set_targets(&database, &hostname, &targets).await;
+            targets.extend(tag_sets);
+            if post.in_reply_to_id.is_some() {
+                let len = targets.len();
+                targets.retain(|actor| ! config.excludes_replies(&actor.kind));
+                if targets.len() < len {
+                    increment_counter!("relay_replies_filtered_total");
+                }
+            }
+            sort_targets(&mut targets);
+            let actor_ids = targets.iter()
+                .map(|actor| Arc::new(actor.uri()))
+                .collect::<Vec<_>>();
+            let assigned_inboxes = match database.get_following_inboxes_for_actors(&actor_ids).await {
+                Ok(following_inboxes) => assign_inboxes(&actor_ids, following_inboxes),
+                Err(e) => {
+                    tracing::error!("get_following_inboxes_for_actors: {}", e);
+                    increment_counter!("relay_db_errors_total");
+                    continue;
+                }
+            };
+            let Ok(post_url_url) = reqwest::Url::parse(&post_url) else { continue; };
+            // Resolve deliverable inboxes first: each inbox belongs to
+            // one actor, and actors left without any build no body
+            let assigned_inboxes = assigned_inboxes.into_iter()
+                .map(|inboxes| inboxes.into_iter()
+                    .filter_map(|inbox| {
+                        let inbox_url = reqwest::Url::parse(&inbox).ok()?;
+
+                        // Prevent relaying back to the originating instance.
+                        if inbox_url.host_str() == post_url_url.host_str() {
+                            return None;
+                        }
 
-                let actor_id = Arc::new(actor.uri());
-                let announce_id = format!("https://{}/announce/{}", hostname, urlencoding::encode(&post_url));
-                let body = json!({
-                    "@context": "https://www.w3.org/ns/activitystreams",
+                        // Blocked destination domain
+                        let inbox_host = inbox_url.host_str().unwrap_or("");
+                        if blocklist.contains(inbox_host) {
+                            increment_counter!("relay_blocked_total", "domain" => inbox_host.to_string());
+                            return None;
+                        }
+                        if ! allowed(config.allowlist(), inbox_host) {
+                            return None;
+                        }
+                        // Hosts that are names are checked on resolving
+                        if ! address_filter.permits_host(inbox_host) {
+                            increment_counter!("relay_blocked_addresses_total");
+                            return None;
+                        }
+                        Some(inbox_url)
+                    })
+                    .collect::<Vec<_>>()
+                )
+                .collect::<Vec<_>>();
+            let published = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+            // Receivers order Announces by the original post
+            let post_published = post.published();
+            let mut relayed = false;
+            for ((actor, actor_id), inboxes) in targets.into_iter().zip(actor_ids).zip(assigned_inboxes) {
+                if inboxes.is_empty() {
+                    continue;
+                }
+                let announce_id = activity_id(&actor_id, "announce", post.uri);
+                let mut body = json!({
+                    "@context": &jsonld_context,
                     "type": "Announce",
                     "actor": *actor_id,
-                    "published": &published,
+                    "published": post_published.as_ref().unwrap_or(&published),
                     "to": ["https://www.w3.org/ns/activitystreams#Public"],
                     "object": &post.uri,
-                    "id": announce_id,
+                    "id": &announce_id,
                 });
-                let Ok(post_url_url) = reqwest::Url::parse(&post_url) else { continue; };
-                let body = Arc::new(
-                    serde_json::to_vec(&body)
-                        .unwrap()
-                );
-                for inbox in database.get_following_inboxes(&actor_id).await.unwrap() {
-                    let Ok(inbox_url) = reqwest::Url::parse(&inbox) else { continue; };
-
-                    // Avoid duplicate processing.
-                    if seen_inboxes.contains(&inbox) {
+                if config.cc_followers {
+                    body["cc"] = json!([actor.followers_uri()]);
+                }
+                let serialized = match serde_json::to_vec(&body) {
+                    Ok(serialized) => Arc::new(serialized),
+                    Err(e) => {
+                        tracing::error!(post_url = %post_url, post_uri = post.uri, "serialize: {}", e);
+                        increment_counter!("relay_serialize_errors_total");
                         continue;
                     }
-                    seen_inboxes.insert(inbox);
-
-                    // Prevent relaying back to the originating instance.
-                    if inbox_url.host_str() == post_url_url.host_str() {
-                        continue;
+                };
+                if ! body_size_allowed(&serialized, config.max_body_size) {
+                    tracing::warn!(post_url = %post_url, post_uri = post.uri, size = serialized.len(), "body too large");
+                    increment_counter!("relay_posts_total", "action" => "too_large");
+                    continue;
+                }
+                // Receivers ignore Updates by anyone else than the
+                // author, and Announces of what they have seen
+                // announced. Undo the Announce first so that the
+                // Announce with the same id is new again.
+                let undo = if edit {
+                    let undo_id = format!(
+                        "{}/{}",
+                        activity_id(&actor_id, "undo", post.uri),
+                        urlencoding::encode(post.edited_at.unwrap_or(&published)),
+                    );
+                    let undo = undo_announce(&jsonld_context, &actor_id, &announce_id, post.uri, &undo_id);
+                    match serde_json::to_vec(&undo) {
+                        Ok(undo) => Some(Arc::new(undo)),
+                        Err(e) => {
+                            tracing::error!(post_url = %post_url, post_uri = post.uri, "serialize undo: {}", e);
+                            increment_counter!("relay_serialize_errors_total");
+                            continue;
+                        }
+                    }
+                } else {
+                    None
+                };
+                relayed = true;
+                if ! edit {
+                    stats.add(&actor_id);
+                    outbox.push(&actor_id, &body);
+                }
+                let body = serialized;
+                let mut announced_inboxes = vec![];
+                for inbox_url in inboxes {
+                    if config.relay_deletes && ! edit && ! post.id.is_empty() {
+                        announced_inboxes.push(inbox_url.to_string());
+                    }
+                    // Create queue items, the Undo first on the same
+                    // worker queue.
+                    for body in undo.iter().chain([&body]) {
+                        let job = Job {
+                            post_url: post_url.clone(),
+                            actor_id: actor_id.clone(),
+                            body: body.clone(),
+                            key_id: actor.key_id(&config),
+                            private_key: keys.get(&actor.kind).private_key.clone(),
+                            inbox_url: inbox_url.clone(),
+                            attempts: 0,
+                        };
+                        // Enqueue job for worker.
+                        workers.enqueue(job).await;
                     }
+                }
 
-                    // Lookup/create worker queue per inbox.
-                    let tx = workers.entry(inbox_url.host_str().unwrap_or("").to_string())
-                        .or_insert_with(|| spawn_worker(client.clone()));
-                    // Create queue item.
-                    let job = Job {
-                        post_url: post_url.clone(),
-                        actor_id: actor_id.clone(),
-                        body: body.clone(),
-                        key_id: actor.key_id(),
-                        private_key: private_key.clone(),
-                        inbox_url,
+                if ! announced_inboxes.is_empty() {
+                    let announce = Announce {
+                        actor: actor_id.to_string(),
+                        key_id: actor.key_id(&config),
+                        announce_id,
+                        object: post.uri.to_string(),
+                        inboxes: announced_inboxes,
                     };
-                    // Enqueue job for worker.
-                    let _ = tx.try_send(job);
+                    if let Err(e) = database.add_announce(&source, post.id, &announce).await {
+                        tracing::error!("add_announce: {}", e);
+                        increment_counter!("relay_db_errors_total");
+                    }
                 }
-
-                seen_actors.insert(actor);
             }
-            if seen_inboxes.is_empty() {
+            if ! relayed {
                 increment_counter!("relay_posts_total", "action" => "no_relay");
+            } else if edit {
+                increment_counter!("relay_posts_total", "action" => "update");
             } else {
                 increment_counter!("relay_posts_total", "action" => "relay");
             }
             let t2 = Instant::now();
             histogram!("relay_post_duration", t2 - t1);
         }
-    });
+
+        let (actors, announces) = stats.take();
+        ActorStats::write(actors, announces, &database).await;
+        let delivered = workers.context.delivered.clone();
+        workers.shutdown(config.shutdown_grace()).await;
+        DeliveredActors::write(delivered.take(), &database).await;
+    })
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
     use actor::ActorKind;
+    use crate::mock_inbox::{test_database, MockInbox};
 
     #[test]
     fn post_relay_kind() {
@@ -252,13 +1443,97 @@ mod test {
             tags: Some(vec![Tag {
                 name: "foo",
             }]),
+            ..Post::default()
         };
-        let mut kinds = post.relay_target_kinds();
+        let followed_prefixes = ["foo".to_string()].into();
+        let mut kinds = post.relay_target_kinds(false, None, &followed_prefixes);
         assert_eq!(kinds.next(), Some(ActorKind::InstanceRelay("example.com".to_string())));
         assert_eq!(kinds.next(), Some(ActorKind::TagRelay("foo".to_string())));
+        assert_eq!(kinds.next(), Some(ActorKind::TagPrefixRelay("foo".to_string())));
+        assert_eq!(kinds.next(), None);
+    }
+
+    #[test]
+    fn post_relay_kind_firehose() {
+        let post = Post {
+            url: Some("http://example.com/post/1"),
+            uri: "http://example.com/post/1",
+            ..Post::default()
+        };
+        let followed_prefixes = HashSet::new();
+        let mut kinds = post.relay_target_kinds(true, None, &followed_prefixes);
+        assert_eq!(kinds.next(), Some(ActorKind::Firehose));
+        assert_eq!(kinds.next(), Some(ActorKind::InstanceRelay("example.com".to_string())));
+        assert_eq!(kinds.next(), None);
+    }
+
+    #[test]
+    fn post_relay_kind_foreign_url() {
+        let post = Post {
+            url: Some("https://example.net/@user/1"),
+            uri: "https://example.com/objects/1",
+            ..Post::default()
+        };
+        assert_eq!(post.host(), Some("example.com".to_string()));
+        let followed_prefixes = HashSet::new();
+        let mut kinds = post.relay_target_kinds(false, None, &followed_prefixes);
+        assert_eq!(kinds.next(), Some(ActorKind::InstanceRelay("example.com".to_string())));
+        assert_eq!(kinds.next(), None);
+    }
+
+    #[test]
+    fn post_relay_kind_account() {
+        let post = Post {
+            url: Some("http://example.com/@Alice/1"),
+            uri: "http://example.com/users/Alice/statuses/1",
+            account: Some(Account {
+                username: "Alice",
+            }),
+            ..Post::default()
+        };
+        let followed_prefixes = HashSet::new();
+        let mut kinds = post.relay_target_kinds(false, None, &followed_prefixes);
+        assert_eq!(kinds.next(), Some(ActorKind::InstanceRelay("example.com".to_string())));
+        assert_eq!(kinds.next(), Some(ActorKind::AccountRelay("alice@example.com".to_string())));
         assert_eq!(kinds.next(), None);
     }
 
+    #[test]
+    fn assign_inboxes_overlapping() {
+        let hostname = Arc::new("relay.example".to_string());
+        let mut targets = vec![
+            ActorKind::from_tag("zebra"),
+            ActorKind::from_tag_path("rust.tokio").unwrap(),
+            ActorKind::from_tag("rust"),
+            ActorKind::InstanceRelay("example.com".to_string()),
+        ].into_iter()
+            .map(|kind| actor::Actor { host: hostname.clone(), kind })
+            .collect::<Vec<_>>();
+        sort_targets(&mut targets);
+        let actor_ids = targets.iter()
+            .map(|actor| Arc::new(actor.uri()))
+            .collect::<Vec<_>>();
+        assert_eq!(actor_ids.iter().map(|id| id.as_str()).collect::<Vec<_>>(), vec![
+            "https://relay.example/instance/example.com",
+            "https://relay.example/tag/rust",
+            "https://relay.example/tag/zebra",
+            "https://relay.example/tag/rust.tokio",
+        ]);
+
+        let following_inboxes = HashMap::from([
+            ("https://relay.example/tag/zebra".to_string(), vec!["https://a/inbox".to_string(), "https://b/inbox".to_string()]),
+            ("https://relay.example/tag/rust".to_string(), vec!["https://b/inbox".to_string()]),
+            ("https://relay.example/tag/rust.tokio".to_string(), vec!["https://b/inbox".to_string(), "https://c/inbox".to_string()]),
+            ("https://relay.example/instance/example.com".to_string(), vec!["https://a/inbox".to_string()]),
+        ]);
+        assert_eq!(assign_inboxes(&actor_ids, following_inboxes), vec![
+            vec!["https://a/inbox".to_string()],
+            vec!["https://b/inbox".to_string()],
+            vec![],
+            vec!["https://c/inbox".to_string()],
+        ]);
+    }
+
     #[test]
     fn post_relay_kind_empty() {
         let post = Post {
@@ -267,8 +1542,10 @@ mod test {
             tags: Some(vec![Tag {
                 name: "",
             }]),
+            ..Post::default()
         };
-        let mut kinds = post.relay_target_kinds();
+        let followed_prefixes = HashSet::new();
+        let mut kinds = post.relay_target_kinds(false, None, &followed_prefixes);
         assert_eq!(kinds.next(), Some(ActorKind::InstanceRelay("example.com".to_string())));
         assert_eq!(kinds.next(), None);
     }
@@ -281,8 +1558,10 @@ mod test {
             tags: Some(vec![Tag {
                 name: "23",
             }]),
+            ..Post::default()
         };
-        let mut kinds = post.relay_target_kinds();
+        let followed_prefixes = HashSet::new();
+        let mut kinds = post.relay_target_kinds(false, None, &followed_prefixes);
         assert_eq!(kinds.next(), Some(ActorKind::InstanceRelay("example.com".to_string())));
         assert_eq!(kinds.next(), Some(ActorKind::TagRelay("23".to_string())));
         assert_eq!(kinds.next(), None);
@@ -296,11 +1575,14 @@ mod test {
             tags: Some(vec![Tag {
                 name: "dd1302",
             }]),
+            ..Post::default()
         };
-        let mut kinds = post.relay_target_kinds();
+        let followed_prefixes = ["dd13".to_string(), "dd2".to_string()].into();
+        let mut kinds = post.relay_target_kinds(false, None, &followed_prefixes);
         assert_eq!(kinds.next(), Some(ActorKind::InstanceRelay("example.com".to_string())));
         assert_eq!(kinds.next(), Some(ActorKind::TagRelay("dd1302".to_string())));
         assert_eq!(kinds.next(), Some(ActorKind::TagRelay("dd".to_string())));
+        assert_eq!(kinds.next(), Some(ActorKind::TagPrefixRelay("dd13".to_string())));
         assert_eq!(kinds.next(), None);
     }
 
@@ -312,10 +1594,809 @@ mod test {
             tags: Some(vec![Tag {
                 name: "スコティッシュ・フォールド・ロングヘアー",
             }]),
+            ..Post::default()
         };
-        let mut kinds = post.relay_target_kinds();
+        let followed_prefixes = ["suk".to_string(), "sukoteitusiyuhuorudoronguhea".to_string()].into();
+        let mut kinds = post.relay_target_kinds(false, None, &followed_prefixes);
         assert_eq!(kinds.next(), Some(ActorKind::InstanceRelay("example.com".to_string())));
         assert_eq!(kinds.next(), Some(ActorKind::TagRelay("sukoteitusiyuhuorudoronguhea".to_string())));
-        assert_eq!(kinds.next(), None);
+        assert_eq!(kinds.next(), Some(ActorKind::TagPrefixRelay("suk".to_string())));
+        assert_eq!(kinds.last(), Some(ActorKind::TagPrefixRelay("sukoteitusiyuhuorudoronguhea".to_string())));
+    }
+
+    #[test]
+    fn post_relay_targets_allowlist() {
+        let post = Post {
+            url: Some("http://example.com/post/1"),
+            uri: "http://example.com/post/1",
+            tags: Some(vec![Tag {
+                name: "foo",
+            }]),
+            ..Post::default()
+        };
+        let hostname = Arc::new("relay.example".to_string());
+        let allowlist = DomainList::from(vec!["example.com".to_string()]);
+        let followed_prefixes = ["foo".to_string()].into();
+        let kinds = post.relay_targets(hostname.clone(), Some(&allowlist), None, &followed_prefixes, false)
+            .map(|actor| actor.kind)
+            .collect::<Vec<_>>();
+        assert_eq!(kinds, vec![
+            ActorKind::InstanceRelay("example.com".to_string()),
+            ActorKind::TagRelay("foo".to_string()),
+            ActorKind::TagPrefixRelay("foo".to_string()),
+        ]);
+
+        let allowlist = DomainList::from(vec!["other.example".to_string()]);
+        let kinds = post.relay_targets(hostname, Some(&allowlist), None, &followed_prefixes, false)
+            .map(|actor| actor.kind)
+            .collect::<Vec<_>>();
+        assert_eq!(kinds, vec![
+            ActorKind::TagRelay("foo".to_string()),
+            ActorKind::TagPrefixRelay("foo".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn post_relay_targets_allowed_tags() {
+        let post = Post {
+            url: Some("http://example.com/post/1"),
+            uri: "http://example.com/post/1",
+            tags: Some(vec![Tag {
+                name: "Rust",
+            }, Tag {
+                name: "python",
+            }, Tag {
+                name: "dd1302",
+            }]),
+            ..Post::default()
+        };
+        let allowed_tags = ["rust".to_string(), "dd".to_string()].into();
+        let followed_prefixes = HashSet::new();
+        let kinds = post.relay_target_kinds(false, Some(&allowed_tags), &followed_prefixes)
+            .collect::<Vec<_>>();
+        assert_eq!(kinds, vec![
+            ActorKind::InstanceRelay("example.com".to_string()),
+            ActorKind::TagRelay("rust".to_string()),
+            ActorKind::TagRelay("dd".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn delivered_actors() {
+        let rust = Arc::new("https://relay.example/tag/rust".to_string());
+        let tokio = Arc::new("https://relay.example/tag/tokio".to_string());
+        let delivered = DeliveredActors::default();
+        delivered.clone().add(&rust);
+        delivered.add(&tokio);
+        delivered.add(&rust);
+        let mut actors = delivered.take();
+        actors.sort();
+        assert_eq!(actors, vec![rust.to_string(), tokio.to_string()]);
+        assert!(delivered.take().is_empty());
+    }
+
+    #[test]
+    fn post_published() {
+        let post: Post = serde_json::from_str(r#"{"uri":"https://example.com/1","created_at":"2023-01-02T03:04:05.678Z"}"#).unwrap();
+        assert_eq!(post.published().as_deref(), Some("2023-01-02T03:04:05Z"));
+        let post: Post = serde_json::from_str(r#"{"uri":"https://example.com/1","created_at":"2023-01-02T04:04:05+01:00"}"#).unwrap();
+        assert_eq!(post.published().as_deref(), Some("2023-01-02T03:04:05Z"));
+        let post: Post = serde_json::from_str(r#"{"uri":"https://example.com/1","created_at":"yesterday"}"#).unwrap();
+        assert_eq!(post.published(), None);
+        let post: Post = serde_json::from_str(r#"{"uri":"https://example.com/1"}"#).unwrap();
+        assert_eq!(post.published(), None);
+    }
+
+    #[test]
+    fn truncate_tags() {
+        let mut post = Post {
+            tags: Some(["a", "b", "c"].into_iter()
+                .map(|name| Tag { name })
+                .collect()),
+            ..Post::default()
+        };
+        assert_eq!(post.truncate_tags(3), 0);
+        assert_eq!(post.truncate_tags(2), 1);
+        assert_eq!(post.tags(), ["a", "b"]);
+        assert_eq!(Post::default().truncate_tags(0), 0);
+    }
+
+    #[test]
+    fn body_size() {
+        assert!(body_size_allowed(&[0; 100], None));
+        assert!(body_size_allowed(&[0; 100], Some(100)));
+        assert!(! body_size_allowed(&[0; 101], Some(100)));
+    }
+
+    #[test]
+    fn undo_announce_references_announce() {
+        let context = json!("https://www.w3.org/ns/activitystreams");
+        let actor = "https://relay.example/tag/rust";
+        let object = "https://example.com/users/a/statuses/1";
+        let announce_id = activity_id(actor, "announce", object);
+        let undo = undo_announce(&context, actor, &announce_id, object, "https://relay.example/undo/1");
+        assert_eq!(undo["type"], "Undo");
+        assert_eq!(undo["id"], "https://relay.example/undo/1");
+        assert_eq!(undo["actor"], actor);
+        assert_eq!(undo["object"], json!({
+            "type": "Announce",
+            "id": announce_id,
+            "actor": actor,
+            "object": object,
+        }));
+    }
+
+    #[test]
+    fn activity_ids() {
+        let object = "https://example.com/users/a/statuses/1";
+        let rust = activity_id("https://relay.example/tag/rust", "announce", object);
+        let tokio = activity_id("https://relay.example/tag/tokio", "announce", object);
+        assert_ne!(rust, tokio);
+        assert!(rust.starts_with("https://relay.example/tag/rust/announce/"));
+        assert_eq!(rust, activity_id("https://relay.example/tag/rust", "announce", object));
+        assert_ne!(rust, activity_id("https://relay.example/tag/rust", "announce", "https://example.com/users/a/statuses/2"));
+    }
+
+    #[test]
+    fn actor_stats() {
+        let rust = Arc::new("https://relay.example/tag/rust".to_string());
+        let tokio = Arc::new("https://relay.example/tag/tokio".to_string());
+        let mut stats = ActorStats::default();
+        stats.add(&rust);
+        stats.add(&tokio);
+        stats.add(&rust);
+        let (actors, announces) = stats.take();
+        let mut counts = actors.into_iter().zip(announces).collect::<Vec<_>>();
+        counts.sort();
+        assert_eq!(counts, vec![(rust.to_string(), 2), (tokio.to_string(), 1)]);
+        assert!(stats.take().0.is_empty());
+    }
+
+    #[test]
+    fn inbox_allowlist() {
+        let allowlist = DomainList::from(vec!["*.example.com".to_string()]);
+        assert!(allowed(None, "other.example"));
+        assert!(allowed(Some(&allowlist), "social.example.com"));
+        assert!(! allowed(Some(&allowlist), "other.example"));
+    }
+
+    #[test]
+    fn source_allowlist() {
+        let source_allowlist = DomainList::from(vec!["*.example.com".to_string()]);
+        assert!(source_allowed(None, Some("other.example")));
+        assert!(source_allowed(None, None));
+        assert!(source_allowed(Some(&source_allowlist), Some("social.example.com")));
+        assert!(! source_allowed(Some(&source_allowlist), Some("other.example")));
+        assert!(! source_allowed(Some(&source_allowlist), None));
+    }
+
+    #[test]
+    fn post_language() {
+        let allowed = HashSet::from(["de".to_string()]);
+        let post = |language| Post {
+            uri: "http://example.com/post/1",
+            language,
+            ..Post::default()
+        };
+        assert!(post(Some("de")).language_allowed(&allowed, false));
+        assert!(post(Some("de-AT")).language_allowed(&allowed, false));
+        assert!(! post(Some("en")).language_allowed(&allowed, true));
+        assert!(post(None).language_allowed(&allowed, true));
+        assert!(! post(None).language_allowed(&allowed, false));
+    }
+
+    #[test]
+    fn post_sensitive() {
+        let post: Post = serde_json::from_str(r#"{
+            "uri": "http://example.com/post/1",
+            "sensitive": false,
+            "spoiler_text": "\"food\""
+        }"#).unwrap();
+        assert!(post.is_sensitive());
+        let post: Post = serde_json::from_str(r#"{
+            "uri": "http://example.com/post/1",
+            "sensitive": false,
+            "spoiler_text": ""
+        }"#).unwrap();
+        assert!(! post.is_sensitive());
+    }
+
+    #[test]
+    fn post_visibility() {
+        let post = |visibility| Post {
+            uri: "http://example.com/post/1",
+            visibility,
+            ..Post::default()
+        };
+        assert!(! post(None).visibility_allowed(false));
+        assert!(! post(None).visibility_allowed(true));
+        assert!(post(Some("public")).visibility_allowed(false));
+        assert!(! post(Some("unlisted")).visibility_allowed(false));
+        assert!(post(Some("unlisted")).visibility_allowed(true));
+        assert!(! post(Some("private")).visibility_allowed(true));
+        assert!(! post(Some("direct")).visibility_allowed(true));
+    }
+
+    #[test]
+    fn post_content_filters() {
+        let post = |content| Post {
+            uri: "http://example.com/post/1",
+            content: Cow::Borrowed(content),
+            ..Post::default()
+        };
+        let hashtag = r##"<a href="https://example.com/tags/rust" class="mention hashtag" rel="tag">#<span>rust</span></a>"##;
+        let hashtag_only = post(hashtag);
+        assert_eq!(hashtag_only.text().trim(), "#rust");
+        assert_eq!(hashtag_only.link_count(), 0);
+        assert!(hashtag_only.content_allowed(5, Some(0), false));
+        assert!(! hashtag_only.content_allowed(0, None, true));
+
+        let content = format!(r#"<p>Rust &amp; friends {}</p>"#, hashtag);
+        let text = post(&content);
+        assert_eq!(text.text().split_whitespace().collect::<Vec<_>>(), ["Rust", "&", "friends", "#rust"]);
+        assert!(text.content_allowed(10, Some(0), true));
+        assert!(! text.content_allowed(100, None, false));
+
+        let links = post(r#"<p><a href="https://spam.example/1">1</a> <a href="https://spam.example/2">2</a></p>"#);
+        assert_eq!(links.link_count(), 2);
+        assert!(links.content_allowed(0, Some(2), true));
+        assert!(! links.content_allowed(0, Some(1), false));
+    }
+
+    #[test]
+    fn backoff_bounds() {
+        assert!(backoff(1) < MIN_BACKOFF);
+        assert!(backoff(1) >= MIN_BACKOFF / 2);
+        assert!(backoff(u32::MAX) < MAX_BACKOFF);
+        assert!(backoff(u32::MAX) >= MAX_BACKOFF / 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn deliver_after_transient_failure() {
+        let body = Arc::new(b"announce".to_vec());
+        let mut attempts = 0;
+        let mut delivered = None;
+        let mut errors = ConsecutiveErrors::default();
+        let mut throttle = Throttle::new(None);
+        let delivery = deliver(&mut errors, &mut 0, 10, &mut throttle, || {
+            attempts += 1;
+            let result = if attempts == 1 {
+                Err(Error::Response(StatusCode::SERVICE_UNAVAILABLE, "unavailable".to_string()))
+            } else {
+                delivered = Some(body.clone());
+                Ok(())
+            };
+            async move { result }
+        }).await;
+        assert_eq!(delivery, Delivery::Delivered);
+        assert_eq!(attempts, 2);
+        assert_eq!(delivered, Some(body));
+        assert_eq!(errors.get(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn deliver_honors_retry_after() {
+        let retry_after = Duration::from_secs(120);
+        let mut attempts = vec![];
+        let mut errors = ConsecutiveErrors::default();
+        let mut throttle = Throttle::new(None);
+        deliver(&mut errors, &mut 0, 10, &mut throttle, || {
+            attempts.push(tokio::time::Instant::now());
+            let result = if attempts.len() == 1 {
+                Err(Error::RateLimited(Some(retry_after)))
+            } else {
+                Ok(())
+            };
+            async move { result }
+        }).await;
+        assert_eq!(attempts.len(), 2);
+        assert!(attempts[1] - attempts[0] >= retry_after);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn deliver_resumes_attempts() {
+        let mut sent = 0;
+        // Of a spooled job
+        let mut attempts = 2;
+        let mut errors = ConsecutiveErrors::default();
+        let mut throttle = Throttle::new(None);
+        let delivery = deliver(&mut errors, &mut attempts, 3, &mut throttle, || {
+            sent += 1;
+            async { Err(Error::Response(StatusCode::BAD_GATEWAY, String::new())) }
+        }).await;
+        assert_eq!(delivery, Delivery::Abandoned);
+        assert_eq!(sent, 1);
+        assert_eq!(attempts, 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn deliver_gives_up_on_gone() {
+        let mut attempts = 0;
+        let mut errors = ConsecutiveErrors::default();
+        let mut throttle = Throttle::new(None);
+        let delivery = deliver(&mut errors, &mut 0, 10, &mut throttle, || {
+            attempts += 1;
+            async { Err(Error::Response(StatusCode::GONE, String::new())) }
+        }).await;
+        assert_eq!(delivery, Delivery::Gone);
+        assert_eq!(attempts, 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn deliver_gives_up_on_not_found() {
+        let mut attempts = 0;
+        let mut errors = ConsecutiveErrors::default();
+        let mut throttle = Throttle::new(None);
+        let delivery = deliver(&mut errors, &mut 0, 10, &mut throttle, || {
+            attempts += 1;
+            async { Err(Error::Response(StatusCode::NOT_FOUND, String::new())) }
+        }).await;
+        assert_eq!(delivery, Delivery::Gone);
+        assert_eq!(attempts, NOT_FOUND_THRESHOLD);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn deliver_abandons_after_max_attempts() {
+        let mut sent = 0;
+        let mut attempts = 0;
+        let mut errors = ConsecutiveErrors::default();
+        let mut throttle = Throttle::new(None);
+        let delivery = deliver(&mut errors, &mut attempts, 3, &mut throttle, || {
+            sent += 1;
+            async { Err(Error::Response(StatusCode::BAD_GATEWAY, String::new())) }
+        }).await;
+        assert_eq!(delivery, Delivery::Abandoned);
+        assert_eq!(sent, 3);
+        assert_eq!(attempts, 3);
+        assert_eq!(errors.get(), 3);
+    }
+
+    /// Permits the `MockInbox`
+    fn loopback() -> AddressFilter {
+        AddressFilter::new(vec!["127.0.0.0/8".parse().unwrap()])
+    }
+
+    fn job(inbox: &str, private_key: &Arc<PrivateKey>, body: &serde_json::Value) -> Job {
+        Job {
+            post_url: Arc::new("https://example.com/@alice/1".to_string()),
+            actor_id: Arc::new("https://relay.example/tag/rust".to_string()),
+            body: Arc::new(serde_json::to_vec(body).unwrap()),
+            key_id: "https://relay.example/tag/rust#key".to_string(),
+            private_key: private_key.clone(),
+            inbox_url: reqwest::Url::parse(inbox).unwrap(),
+            attempts: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn process_job_delivers_signed() {
+        use sigh::alg::{Algorithm, RsaSha256};
+
+        let inbox = MockInbox::start().await;
+        let (private_key, public_key) = RsaSha256.generate_keys().unwrap();
+        let private_key = Arc::new(private_key);
+        let body = json!({ "type": "Announce" });
+        let (gone_tx, _gone_rx) = mpsc::channel(1);
+        process_job(
+            &Sink::Http(Arc::new(reqwest::Client::new()), loopback(), None), &gone_tx, &DeliveredActors::default(), &mut ConsecutiveErrors::default(), 10, &mut Throttle::new(None),
+            &mut job(&inbox.url("/inbox"), &private_key, &body),
+        ).await;
+
+        let received = inbox.received();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].method, http::Method::POST);
+        assert_eq!(received[0].uri.path(), "/inbox");
+        assert_eq!(received[0].headers["content-type"], "application/activity+json");
+        assert_eq!(received[0].signature_key_id().as_deref(), Some("https://relay.example/tag/rust#key"));
+        assert!(received[0].verify(&public_key));
+        assert_eq!(received[0].json(), body);
+    }
+
+    #[tokio::test]
+    async fn process_job_reports_gone() {
+        use sigh::alg::{Algorithm, RsaSha256};
+
+        let inbox = MockInbox::start().await;
+        inbox.respond_with([StatusCode::GONE]);
+        let (private_key, _) = RsaSha256.generate_keys().unwrap();
+        let (gone_tx, mut gone_rx) = mpsc::channel(1);
+        process_job(
+            &Sink::Http(Arc::new(reqwest::Client::new()), loopback(), None), &gone_tx, &DeliveredActors::default(), &mut ConsecutiveErrors::default(), 10, &mut Throttle::new(None),
+            &mut job(&inbox.url("/inbox"), &Arc::new(private_key), &json!({})),
+        ).await;
+
+        assert_eq!(inbox.received().len(), 1);
+        assert_eq!(gone_rx.try_recv().unwrap().as_str(), inbox.url("/inbox"));
+    }
+
+    #[tokio::test]
+    async fn aborted_worker_hands_back_jobs() {
+        use sigh::alg::{Algorithm, RsaSha256};
+
+        let inbox = MockInbox::start().await;
+        let (private_key, _) = RsaSha256.generate_keys().unwrap();
+        let private_key = Arc::new(private_key);
+        let (gone_tx, _gone_rx) = mpsc::channel(1);
+        let (_abort, abort_rx) = watch::channel(true);
+        let (leftover_tx, mut leftovers) = mpsc::unbounded_channel();
+        let queue_depth = QueueDepth::default();
+        let mut tx = spawn_worker(WorkerContext {
+            sink: Sink::Http(Arc::new(reqwest::Client::new()), loopback(), None),
+            config: Arc::new(Config::test("")),
+            gone_tx,
+            delivered: DeliveredActors::default(),
+            queue_depth: queue_depth.clone(),
+            abort: abort_rx,
+            paused: watch::channel(false).1,
+            leftover_tx,
+        });
+        for path in ["/inbox/1", "/inbox/2"] {
+            tx.try_send(job(&inbox.url(path), &private_key, &json!({}))).unwrap();
+            queue_depth.add(1);
+        }
+
+        let mut paths = vec![];
+        while let Some(job) = leftovers.recv().await {
+            paths.push(job.inbox_url.path().to_string());
+        }
+        assert_eq!(paths, ["/inbox/1", "/inbox/2"]);
+        assert!(inbox.received().is_empty());
+        assert_eq!(queue_depth.0.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn paused_worker_holds_jobs() {
+        use sigh::alg::{Algorithm, RsaSha256};
+
+        let inbox = MockInbox::start().await;
+        let (private_key, _) = RsaSha256.generate_keys().unwrap();
+        let private_key = Arc::new(private_key);
+        let (gone_tx, _gone_rx) = mpsc::channel(1);
+        let (_abort, abort_rx) = watch::channel(false);
+        let (paused, paused_rx) = watch::channel(true);
+        let (leftover_tx, _leftovers) = mpsc::unbounded_channel();
+        let mut tx = spawn_worker(WorkerContext {
+            sink: Sink::Http(Arc::new(reqwest::Client::new()), loopback(), None),
+            config: Arc::new(Config::test("")),
+            gone_tx,
+            delivered: DeliveredActors::default(),
+            queue_depth: QueueDepth::default(),
+            abort: abort_rx,
+            paused: paused_rx,
+            leftover_tx,
+        });
+        tx.try_send(job(&inbox.url("/inbox"), &private_key, &json!({}))).unwrap();
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert!(inbox.received().is_empty());
+
+        paused.send_replace(false);
+        assert_eq!(inbox.wait_for(1, Duration::from_secs(10)).await.len(), 1);
+    }
+
+    #[tokio::test]
+    #[ignore = "requires PostgreSQL at TEST_DATABASE"]
+    async fn respawn_reenqueues_jobs() {
+        use sigh::alg::{Algorithm, RsaSha256};
+
+        let inbox = MockInbox::start().await;
+        let (private_key, _) = RsaSha256.generate_keys().unwrap();
+        let private_key = Arc::new(private_key);
+        let config = Arc::new(Config::test("allowed_private_ranges: [127.0.0.0/8]"));
+        let mut workers = Workers::new(Arc::new(reqwest::Client::new()), None, config, test_database().await);
+        // Like a worker that exited after idling
+        let (closed, _) = channel(1);
+        workers.senders.insert("127.0.0.1".to_string(), closed);
+        for path in ["/inbox/1", "/inbox/2"] {
+            workers.enqueue(job(&inbox.url(path), &private_key, &json!({}))).await;
+        }
+        // Before the worker got to run
+        workers.respawn();
+        let mut leftovers = vec![];
+        for _ in 0..2 {
+            leftovers.push(workers.leftovers.recv().await.unwrap());
+        }
+        assert!(inbox.received().is_empty());
+        for job in leftovers {
+            workers.enqueue(job).await;
+        }
+        let received = inbox.wait_for(2, Duration::from_secs(10)).await;
+        let paths = received.iter()
+            .map(|received| received.uri.path())
+            .collect::<Vec<_>>();
+        assert_eq!(paths, ["/inbox/1", "/inbox/2"]);
+        assert_eq!(workers.context.queue_depth.0.load(Ordering::Relaxed), 0);
+        workers.shutdown(Duration::from_secs(1)).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "requires PostgreSQL at TEST_DATABASE"]
+    async fn spawn_relays_post() {
+        use sigh::alg::{Algorithm, RsaSha256};
+
+        let inbox = MockInbox::start().await;
+        let database = test_database().await;
+        // Distinct per run in a shared database
+        let tag = format!("test{}", rand::thread_rng().gen::<u32>());
+        let tag_actor = format!("https://relay.example/tag/{}", tag);
+        let instance_actor = "https://relay.example/instance/example.com";
+        // Follows both, receives the post once
+        database.add_follow("https://a.example/users/a", &inbox.url("/a"), &tag_actor, None).await.unwrap();
+        database.add_follow("https://a.example/users/a", &inbox.url("/a"), instance_actor, None).await.unwrap();
+        database.add_follow("https://b.example/users/b", &inbox.url("/b"), &tag_actor, None).await.unwrap();
+
+        let (private_key, public_key) = RsaSha256.generate_keys().unwrap();
+        // The instance actor signs with its own key
+        let (instance_private_key, instance_public_key) = RsaSha256.generate_keys().unwrap();
+        let keys = KeyRing::test((private_key, public_key.clone()), [(RelayKind::Instance, (instance_private_key, instance_public_key.clone()))]);
+        let config = Config::test("allowed_private_ranges: [127.0.0.0/8]");
+        let (stream_tx, stream_rx) = mpsc::channel(1);
+        let source = Arc::new("stream.example".to_string());
+        let (_commands_tx, commands_rx) = mpsc::channel(1);
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let relay = spawn(
+            Arc::new(reqwest::Client::new()),
+            None,
+            Arc::new("relay.example".to_string()),
+            database.clone(),
+            keys,
+            SharedDomainList::default(),
+            stream_rx,
+            Arc::new(config),
+            Outbox::new(0),
+            commands_rx,
+            shutdown_rx,
+            notify::Liveness::new(),
+            actor::FollowedPrefixes::default(),
+        );
+        let post = json!({
+            "id": "1",
+            "url": "https://example.com/@alice/1",
+            "uri": "https://example.com/users/alice/statuses/1",
+            "created_at": "2023-01-02T03:04:05.000Z",
+            "visibility": "public",
+            "tags": [{ "name": tag }],
+        });
+        stream_tx.send((source.clone(), stream::Event::Update(post.to_string()))).await.unwrap();
+        inbox.wait_for(2, Duration::from_secs(10)).await;
+        shutdown_tx.send(true).unwrap();
+        relay.await.unwrap();
+        database.remove_inbox(&inbox.url("/a")).await.unwrap();
+        database.remove_inbox(&inbox.url("/b")).await.unwrap();
+
+        let mut received = inbox.received();
+        assert_eq!(received.len(), 2, "{:?}", received);
+        received.sort_by(|a, b| a.uri.path().cmp(b.uri.path()));
+        let (a, b) = (&received[0], &received[1]);
+        assert_eq!(a.uri.path(), "/a");
+        assert_eq!(b.uri.path(), "/b");
+        assert!(a.verify(&instance_public_key));
+        assert!(! a.verify(&public_key));
+        assert!(b.verify(&public_key));
+        for received in [a, b] {
+            let announce = received.json();
+            assert_eq!(announce["type"], "Announce");
+            assert_eq!(announce["published"], "2023-01-02T03:04:05Z");
+            assert_eq!(announce["object"], "https://example.com/users/alice/statuses/1");
+            assert_eq!(announce["to"][0], "https://www.w3.org/ns/activitystreams#Public");
+        }
+        // The instance actor takes precedence for the inbox that
+        // follows both
+        assert_eq!(a.json()["actor"], instance_actor);
+        assert_eq!(a.signature_key_id().unwrap(), format!("{}#key", instance_actor));
+        assert_eq!(b.json()["actor"], tag_actor);
+        assert_eq!(b.signature_key_id().unwrap(), format!("{}#key", tag_actor));
+    }
+
+    #[tokio::test]
+    #[ignore = "requires PostgreSQL at TEST_DATABASE"]
+    async fn spawn_relays_edit() {
+        use sigh::alg::{Algorithm, RsaSha256};
+
+        let inbox = MockInbox::start().await;
+        let database = test_database().await;
+        let tag = format!("test{}", rand::thread_rng().gen::<u32>());
+        let tag_actor = format!("https://relay.example/tag/{}", tag);
+        database.add_follow("https://a.example/users/a", &inbox.url("/a"), &tag_actor, None).await.unwrap();
+
+        let keys = KeyRing::test(RsaSha256.generate_keys().unwrap(), []);
+        let config = Config::test("allowed_private_ranges: [127.0.0.0/8]\nrelay_updates: true");
+        let (stream_tx, stream_rx) = mpsc::channel(2);
+        let source = Arc::new("stream.example".to_string());
+        let (_commands_tx, commands_rx) = mpsc::channel(1);
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let relay = spawn(
+            Arc::new(reqwest::Client::new()),
+            None,
+            Arc::new("relay.example".to_string()),
+            database.clone(),
+            keys,
+            SharedDomainList::default(),
+            stream_rx,
+            Arc::new(config),
+            Outbox::new(0),
+            commands_rx,
+            shutdown_rx,
+            notify::Liveness::new(),
+            actor::FollowedPrefixes::default(),
+        );
+        let mut post = json!({
+            "id": "1",
+            "url": "https://example.com/@alice/1",
+            "uri": "https://example.com/users/alice/statuses/1",
+            "created_at": "2023-01-02T03:04:05.000Z",
+            "visibility": "public",
+            "tags": [{ "name": tag }],
+        });
+        stream_tx.send((source.clone(), stream::Event::Update(post.to_string()))).await.unwrap();
+        post["edited_at"] = json!("2023-01-02T04:05:06.000Z");
+        stream_tx.send((source.clone(), stream::Event::StatusUpdate(post.to_string()))).await.unwrap();
+        inbox.wait_for(3, Duration::from_secs(10)).await;
+        shutdown_tx.send(true).unwrap();
+        relay.await.unwrap();
+        database.remove_inbox(&inbox.url("/a")).await.unwrap();
+
+        let received = inbox.received()
+            .iter()
+            .map(|received| received.json())
+            .collect::<Vec<_>>();
+        assert_eq!(received.len(), 3, "{:?}", received);
+        let (announce, undo, reannounce) = (&received[0], &received[1], &received[2]);
+        assert_eq!(announce["type"], "Announce");
+        assert_eq!(undo["type"], "Undo");
+        assert_eq!(undo["actor"], tag_actor);
+        assert_eq!(undo["object"]["id"], announce["id"]);
+        assert_eq!(undo["object"]["object"], "https://example.com/users/alice/statuses/1");
+        assert_eq!(reannounce, announce);
+    }
+
+    #[tokio::test]
+    #[ignore = "requires PostgreSQL at TEST_DATABASE"]
+    async fn spawn_relays_delete() {
+        use sigh::alg::{Algorithm, RsaSha256};
+
+        let inbox = MockInbox::start().await;
+        let database = test_database().await;
+        let tag = format!("test{}", rand::thread_rng().gen::<u32>());
+        let tag_actor = format!("https://relay.example/tag/{}", tag);
+        database.add_follow("https://a.example/users/a", &inbox.url("/a"), &tag_actor, None).await.unwrap();
+
+        let keys = KeyRing::test(RsaSha256.generate_keys().unwrap(), []);
+        let config = Config::test("allowed_private_ranges: [127.0.0.0/8]\nrelay_deletes: true");
+        let (stream_tx, stream_rx) = mpsc::channel(3);
+        let (_commands_tx, commands_rx) = mpsc::channel(1);
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let relay = spawn(
+            Arc::new(reqwest::Client::new()),
+            None,
+            Arc::new("relay.example".to_string()),
+            database.clone(),
+            keys,
+            SharedDomainList::default(),
+            stream_rx,
+            Arc::new(config),
+            Outbox::new(0),
+            commands_rx,
+            shutdown_rx,
+            notify::Liveness::new(),
+            actor::FollowedPrefixes::default(),
+        );
+        // Status ids are only unique per stream
+        let id = rand::thread_rng().gen::<u32>().to_string();
+        let post = json!({
+            "id": id,
+            "url": "https://example.com/@alice/1",
+            "uri": "https://example.com/users/alice/statuses/1",
+            "created_at": "2023-01-02T03:04:05.000Z",
+            "visibility": "public",
+            "tags": [{ "name": tag }],
+        });
+        let source = Arc::new("stream.example".to_string());
+        let other_source = Arc::new("other-stream.example".to_string());
+        stream_tx.send((source.clone(), stream::Event::Update(post.to_string()))).await.unwrap();
+        stream_tx.send((other_source, stream::Event::Delete(id.clone()))).await.unwrap();
+        stream_tx.send((source, stream::Event::Delete(id))).await.unwrap();
+        inbox.wait_for(2, Duration::from_secs(10)).await;
+        shutdown_tx.send(true).unwrap();
+        relay.await.unwrap();
+        database.remove_inbox(&inbox.url("/a")).await.unwrap();
+
+        let received = inbox.received()
+            .iter()
+            .map(|received| received.json())
+            .collect::<Vec<_>>();
+        assert_eq!(received.len(), 2, "{:?}", received);
+        let (announce, undo) = (&received[0], &received[1]);
+        assert_eq!(announce["type"], "Announce");
+        assert_eq!(undo["type"], "Undo");
+        assert_eq!(undo["object"]["id"], announce["id"]);
+    }
+
+    #[tokio::test]
+    #[ignore = "requires PostgreSQL at TEST_DATABASE"]
+    async fn spawn_pauses() {
+        use sigh::alg::{Algorithm, RsaSha256};
+
+        let inbox = MockInbox::start().await;
+        let database = test_database().await;
+        let tag = format!("test{}", rand::thread_rng().gen::<u32>());
+        let tag_actor = format!("https://relay.example/tag/{}", tag);
+        database.add_follow("https://a.example/users/a", &inbox.url("/a"), &tag_actor, None).await.unwrap();
+
+        let keys = KeyRing::test(RsaSha256.generate_keys().unwrap(), []);
+        let config = Config::test("allowed_private_ranges: [127.0.0.0/8]\nrelay_deletes: true");
+        let (stream_tx, stream_rx) = mpsc::channel(3);
+        let (commands_tx, commands_rx) = mpsc::channel(1);
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let relay = spawn(
+            Arc::new(reqwest::Client::new()),
+            None,
+            Arc::new("relay.example".to_string()),
+            database.clone(),
+            keys,
+            SharedDomainList::default(),
+            stream_rx,
+            Arc::new(config),
+            Outbox::new(0),
+            commands_rx,
+            shutdown_rx,
+            notify::Liveness::new(),
+            actor::FollowedPrefixes::default(),
+        );
+        let id = rand::thread_rng().gen::<u32>() / 2;
+        let post = |id: u32| json!({
+            "id": id.to_string(),
+            "url": format!("https://example.com/@alice/{}", id),
+            "uri": format!("https://example.com/users/alice/statuses/{}", id),
+            "created_at": "2023-01-02T03:04:05.000Z",
+            "visibility": "public",
+            "tags": [{ "name": tag }],
+        });
+        let source = Arc::new("stream.example".to_string());
+        stream_tx.send((source.clone(), stream::Event::Update(post(id).to_string()))).await.unwrap();
+        inbox.wait_for(1, Duration::from_secs(10)).await;
+
+        commands_tx.send(Command::Pause).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        stream_tx.send((source.clone(), stream::Event::Update(post(id + 1).to_string()))).await.unwrap();
+        stream_tx.send((source, stream::Event::Delete(id.to_string()))).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert_eq!(inbox.received().len(), 1);
+
+        commands_tx.send(Command::Resume).await.unwrap();
+        inbox.wait_for(2, Duration::from_secs(10)).await;
+        shutdown_tx.send(true).unwrap();
+        relay.await.unwrap();
+        database.remove_inbox(&inbox.url("/a")).await.unwrap();
+
+        let received = inbox.received()
+            .iter()
+            .map(|received| received.json())
+            .collect::<Vec<_>>();
+        // Not the post while paused, but the Undo of the one before
+        assert_eq!(received.len(), 2, "{:?}", received);
+        let (announce, undo) = (&received[0], &received[1]);
+        assert_eq!(announce["object"], format!("https://example.com/users/alice/statuses/{}", id));
+        assert_eq!(undo["type"], "Undo");
+        assert_eq!(undo["object"]["id"], announce["id"]);
+    }
+
+    #[tokio::test]
+    #[ignore = "requires PostgreSQL at TEST_DATABASE"]
+    async fn loads_followed_prefixes() {
+        let inbox = MockInbox::start().await;
+        let database = test_database().await;
+        let prefix = format!("test{}", rand::thread_rng().gen::<u32>());
+        database.add_follow("https://a.example/users/a", &inbox.url("/a"), &format!("https://relay.example/tag/{}*", prefix), None).await.unwrap();
+        database.add_follow("https://a.example/users/a", &inbox.url("/a"), &format!("https://other.example/tag/{}x*", prefix), None).await.unwrap();
+
+        let followed_prefixes = actor::FollowedPrefixes::default();
+        followed_prefixes.insert("unfollowed");
+        load_followed_prefixes(&database, "relay.example", &followed_prefixes).await;
+        database.remove_inbox(&inbox.url("/a")).await.unwrap();
+
+        let prefixes = followed_prefixes.get();
+        assert!(prefixes.contains(&prefix));
+        assert!(! prefixes.contains(&format!("{}x", prefix)));
+        assert!(! prefixes.contains("unfollowed"));
     }
 }
@@ -0,0 +1,320 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+    sync::Arc,
+};
+use hyper::client::connect::dns::Name;
+use metrics::increment_counter;
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use serde::Deserialize;
+use crate::error::Error;
+
+/// Like the default policy of reqwest
+const MAX_REDIRECTS: usize = 10;
+
+/// An address range in CIDR notation like `10.0.0.0/8`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct IpRange {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpRange {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(range), IpAddr::V4(ip)) =>
+                prefix_eq(&range.octets(), &ip.octets(), self.prefix_len),
+            (IpAddr::V6(range), IpAddr::V6(ip)) =>
+                prefix_eq(&range.octets(), &ip.octets(), self.prefix_len),
+            _ => false,
+        }
+    }
+}
+
+const fn v4(a: u8, b: u8, c: u8, d: u8, prefix_len: u8) -> IpRange {
+    IpRange { addr: IpAddr::V4(Ipv4Addr::new(a, b, c, d)), prefix_len }
+}
+
+const fn v6(segments: [u16; 8], prefix_len: u8) -> IpRange {
+    let [a, b, c, d, e, f, g, h] = segments;
+    IpRange { addr: IpAddr::V6(Ipv6Addr::new(a, b, c, d, e, f, g, h)), prefix_len }
+}
+
+/// Special-purpose ranges that are not reachable on the public
+/// internet, or not as what they seem
+const NON_PUBLIC_RANGES: &[IpRange] = &[
+    v4(0, 0, 0, 0, 8),
+    v4(10, 0, 0, 0, 8),
+    // Shared address space of carrier-grade NAT
+    v4(100, 64, 0, 0, 10),
+    v4(127, 0, 0, 0, 8),
+    v4(169, 254, 0, 0, 16),
+    v4(172, 16, 0, 0, 12),
+    v4(192, 0, 0, 0, 24),
+    v4(192, 0, 2, 0, 24),
+    v4(192, 168, 0, 0, 16),
+    // Benchmarking
+    v4(198, 18, 0, 0, 15),
+    v4(198, 51, 100, 0, 24),
+    v4(203, 0, 113, 0, 24),
+    // Multicast
+    v4(224, 0, 0, 0, 4),
+    // Reserved, and broadcast
+    v4(240, 0, 0, 0, 4),
+    v6([0, 0, 0, 0, 0, 0, 0, 0], 128),
+    v6([0, 0, 0, 0, 0, 0, 0, 1], 128),
+    // NAT64, which could reach any IPv4 address
+    v6([0x64, 0xff9b, 0, 0, 0, 0, 0, 0], 96),
+    v6([0x64, 0xff9b, 1, 0, 0, 0, 0, 0], 48),
+    // Discard
+    v6([0x100, 0, 0, 0, 0, 0, 0, 0], 64),
+    // Documentation
+    v6([0x2001, 0xdb8, 0, 0, 0, 0, 0, 0], 32),
+    // 6to4, which could reach any IPv4 address
+    v6([0x2002, 0, 0, 0, 0, 0, 0, 0], 16),
+    // Unique local
+    v6([0xfc00, 0, 0, 0, 0, 0, 0, 0], 7),
+    // Link-local
+    v6([0xfe80, 0, 0, 0, 0, 0, 0, 0], 10),
+    // Multicast
+    v6([0xff00, 0, 0, 0, 0, 0, 0, 0], 8),
+];
+
+/// Whether an address is reachable on the public internet, as opposed
+/// to any of the `NON_PUBLIC_RANGES`
+pub fn is_public_ip(ip: IpAddr) -> bool {
+    let ip = match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped()
+            .map_or(ip, IpAddr::V4),
+        IpAddr::V4(_) => ip,
+    };
+    ! NON_PUBLIC_RANGES.iter().any(|range| range.contains(ip))
+}
+
+/// Whether the first `prefix_len` bits are equal
+fn prefix_eq(a: &[u8], b: &[u8], prefix_len: u8) -> bool {
+    let bytes = usize::from(prefix_len / 8);
+    let bits = prefix_len % 8;
+    a[..bytes] == b[..bytes]
+        && (bits == 0 || (a[bytes] ^ b[bytes]) >> (8 - bits) == 0)
+}
+
+impl FromStr for IpRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+        let addr = addr.parse::<IpAddr>()
+            .map_err(|e| format!("{}: {}", s, e))?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            None => max_len,
+            Some(prefix_len) => prefix_len.parse::<u8>()
+                .ok()
+                .filter(|prefix_len| *prefix_len <= max_len)
+                .ok_or_else(|| format!("{}: invalid prefix length", s))?,
+        };
+        Ok(IpRange { addr, prefix_len })
+    }
+}
+
+impl TryFrom<String> for IpRange {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// Decides which addresses outgoing requests may connect to: public
+/// ones, and those in the configured ranges
+#[derive(Debug, Clone, Default)]
+pub struct AddressFilter {
+    allowed: Arc<Vec<IpRange>>,
+}
+
+impl AddressFilter {
+    pub fn new(allowed: Vec<IpRange>) -> Self {
+        AddressFilter {
+            allowed: Arc::new(allowed),
+        }
+    }
+
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        is_public_ip(ip)
+            || self.allowed.iter().any(|range| range.contains(ip))
+    }
+
+    /// Checks a URL host that is an address. Names are left to the
+    /// `Resolver`.
+    pub fn permits_host(&self, host: &str) -> bool {
+        match host.trim_start_matches('[').trim_end_matches(']').parse() {
+            Ok(ip) => self.permits(ip),
+            Err(_) => true,
+        }
+    }
+
+    /// Checks the URL of an outgoing request, which the HTTP client
+    /// does not resolve if its host is an address
+    pub fn check_url(&self, url: &reqwest::Url) -> Result<(), Error> {
+        let host = url.host_str()
+            .ok_or(Error::InvalidUri)?;
+        if ! self.permits_host(host) {
+            tracing::warn!(url = %url, "refusing to connect to a non-public address");
+            increment_counter!("relay_blocked_addresses_total");
+            return Err(Error::BlockedAddress);
+        }
+        Ok(())
+    }
+
+    /// Follows redirects only to URLs that pass `check_url()`, so
+    /// that a remote cannot redirect the relay to an internal address
+    pub fn redirect_policy(&self) -> reqwest::redirect::Policy {
+        let filter = self.clone();
+        reqwest::redirect::Policy::custom(move |attempt| {
+            if attempt.previous().len() >= MAX_REDIRECTS {
+                attempt.error("too many redirects")
+            } else if filter.check_url(attempt.url()).is_err() {
+                attempt.error(Error::BlockedAddress)
+            } else {
+                attempt.follow()
+            }
+        })
+    }
+}
+
+/// Resolves with the system resolver, withholding the addresses that
+/// the `AddressFilter` does not permit, as a guard against the relay
+/// being made to request internal services.
+///
+/// The HTTP client does not resolve URLs with an address for a host,
+/// these need checking with `AddressFilter::permits_host()`.
+pub struct Resolver {
+    filter: AddressFilter,
+}
+
+impl Resolver {
+    pub fn new(filter: AddressFilter) -> Self {
+        Resolver { filter }
+    }
+}
+
+impl Resolve for Resolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let filter = self.filter.clone();
+        Box::pin(async move {
+            let addrs = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .collect::<Vec<SocketAddr>>();
+            let permitted = addrs.iter()
+                .filter(|addr| filter.permits(addr.ip()))
+                .cloned()
+                .collect::<Vec<_>>();
+            if permitted.is_empty() && ! addrs.is_empty() {
+                tracing::warn!(host = name.as_str(), "refusing to connect to non-public addresses");
+                increment_counter!("relay_blocked_addresses_total");
+                return Err(format!("{} has no public address", name.as_str()).into());
+            }
+            let addrs: Addrs = Box::new(permitted.into_iter());
+            Ok(addrs)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn ip_ranges() {
+        let range: IpRange = "10.0.0.0/8".parse().unwrap();
+        assert!(range.contains("10.1.2.3".parse().unwrap()));
+        assert!(! range.contains("11.0.0.1".parse().unwrap()));
+        assert!(! range.contains("::1".parse().unwrap()));
+        let range: IpRange = "172.16.0.0/12".parse().unwrap();
+        assert!(range.contains("172.31.255.255".parse().unwrap()));
+        assert!(! range.contains("172.32.0.0".parse().unwrap()));
+        let range: IpRange = "::1".parse().unwrap();
+        assert!(range.contains("::1".parse().unwrap()));
+        assert!(! range.contains("::2".parse().unwrap()));
+        assert!("10.0.0.0/33".parse::<IpRange>().is_err());
+        assert!("example.com/8".parse::<IpRange>().is_err());
+    }
+
+    #[test]
+    fn public_ips() {
+        for ip in ["93.184.216.34", "2606:2800:220:1::", "::ffff:93.184.216.34", "223.255.255.255"] {
+            assert!(is_public_ip(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in [
+            "0.0.0.0", "10.1.2.3", "100.64.0.1", "127.0.0.1", "169.254.169.254",
+            "172.16.0.1", "192.0.2.1", "192.168.1.1", "198.18.0.1", "198.19.255.255",
+            "224.0.0.1", "239.255.255.250", "240.0.0.1", "255.255.255.255",
+            "::", "::1", "::ffff:127.0.0.1", "64:ff9b::a00:1", "2001:db8::1",
+            "2002:a00:1::", "fd00::1", "fe80::1", "ff02::1",
+        ] {
+            assert!(! is_public_ip(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn address_filter() {
+        let filter = AddressFilter::default();
+        assert!(filter.permits("93.184.216.34".parse().unwrap()));
+        assert!(! filter.permits("169.254.169.254".parse().unwrap()));
+        assert!(! filter.permits_host("127.0.0.1"));
+        assert!(! filter.permits_host("[fd00::1]"));
+        assert!(filter.permits_host("example.com"));
+
+        let filter = AddressFilter::new(vec!["127.0.0.0/8".parse().unwrap()]);
+        assert!(filter.permits_host("127.0.0.1"));
+        assert!(! filter.permits_host("10.0.0.1"));
+    }
+
+    #[test]
+    fn check_url() {
+        let filter = AddressFilter::default();
+        let url = |url| reqwest::Url::parse(url).unwrap();
+        assert!(filter.check_url(&url("https://example.com/inbox")).is_ok());
+        assert!(filter.check_url(&url("https://93.184.216.34/inbox")).is_ok());
+        assert!(matches!(filter.check_url(&url("http://127.0.0.1/inbox")), Err(Error::BlockedAddress)));
+        assert!(matches!(filter.check_url(&url("http://169.254.169.254/latest/meta-data")), Err(Error::BlockedAddress)));
+        assert!(matches!(filter.check_url(&url("http://[::1]:8080/")), Err(Error::BlockedAddress)));
+        assert!(filter.check_url(&url("data:text/plain,x")).is_err());
+    }
+
+    #[tokio::test]
+    async fn redirect_to_internal_address() {
+        let inbox = crate::mock_inbox::MockInbox::start().await;
+        let client = reqwest::Client::builder()
+            .redirect(AddressFilter::new(vec!["127.0.0.0/8".parse().unwrap()]).redirect_policy())
+            .build()
+            .unwrap();
+        inbox.redirect_to(&inbox.url("/b"));
+        let res = client.get(inbox.url("/a")).send().await.unwrap();
+        assert_eq!(res.url().path(), "/b");
+
+        let client = reqwest::Client::builder()
+            .redirect(AddressFilter::default().redirect_policy())
+            .build()
+            .unwrap();
+        inbox.redirect_to(&inbox.url("/b"));
+        let error = client.get(inbox.url("/a")).send().await.unwrap_err();
+        assert!(error.is_redirect(), "{:?}", error);
+    }
+
+    #[tokio::test]
+    async fn resolver_withholds_loopback() {
+        let name = Name::from_str("localhost").unwrap();
+        assert!(Resolver::new(AddressFilter::default()).resolve(name).await.is_err());
+
+        let filter = AddressFilter::new(vec!["127.0.0.0/8".parse().unwrap(), "::1".parse().unwrap()]);
+        let name = Name::from_str("localhost").unwrap();
+        let addrs = Resolver::new(filter).resolve(name).await.unwrap();
+        assert!(addrs.count() > 0);
+    }
+}
@@ -1,15 +1,138 @@
 use std::{
     sync::Arc,
-    time::Instant,
+    time::{Duration, Instant},
 };
-use http::StatusCode;
-use metrics::histogram;
+use http::{header::RETRY_AFTER, HeaderMap, StatusCode};
+use metrics::{histogram, increment_counter};
 use serde::Serialize;
-use sigh::{PrivateKey, SigningConfig, alg::RsaSha256};
-use crate::{digest, error::Error};
+use openssl::pkey::Id;
+use sigh::{PrivateKey, SigningConfig, alg::{Algorithm, Hs2019, RsaSha256}};
+use crate::{digest, error::Error, resolver::AddressFilter};
+
+/// Signs with the algorithm that fits the key, `rsa-sha256` unless
+/// it is an Ed25519 key
+pub fn sign<B>(
+    req: &mut http::Request<B>,
+    key_id: &str,
+    private_key: &PrivateKey,
+) -> Result<(), sigh::Error> {
+    if private_key.0.id() == Id::ED25519 {
+        SigningConfig::new(Hs2019, private_key, key_id)
+            .sign(req)
+    } else {
+        SigningConfig::new(RsaSha256, private_key, key_id)
+            .sign(req)
+    }
+}
+
+/// Headers covered by the signature of a POST request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignedHeaders {
+    /// `(request-target) host date digest content-type`, like Mastodon
+    Date,
+    /// Additionally `(created)`, for implementations that require it
+    Created,
+}
+
+/// Headers covered with `SignedHeaders::Created`
+const CREATED_HEADERS: &[&str] = &[
+    "(request-target)", "(created)",
+    "host", "date",
+    "digest", "content-type",
+];
+
+/// The string to sign for `headers` of a request
+fn signing_string<B>(req: &http::Request<B>, headers: &[&str], created: &str) -> String {
+    headers.iter()
+        .map(|header| {
+            let value = match *header {
+                "(request-target)" =>
+                    format!("{} {}", req.method().as_str().to_lowercase(),
+                            req.uri().path_and_query().map_or("/", |path| path.as_str())),
+                "(created)" =>
+                    created.to_string(),
+                _ =>
+                    req.headers().get(*header)
+                        .and_then(|value| value.to_str().ok())
+                        .unwrap_or("")
+                        .to_string(),
+            };
+            format!("{}: {}", header, value)
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Signs including `(created)`, which requires the `hs2019`
+/// algorithm name even for RSA keys
+fn sign_created<B>(
+    req: &mut http::Request<B>,
+    key_id: &str,
+    private_key: &PrivateKey,
+) -> Result<(), Error> {
+    let created = chrono::Utc::now().timestamp().to_string();
+    let signing_string = signing_string(req, CREATED_HEADERS, &created);
+    let signature = if private_key.0.id() == Id::ED25519 {
+        Hs2019.sign(private_key, signing_string.as_bytes())?
+    } else {
+        RsaSha256.sign(private_key, signing_string.as_bytes())?
+    };
+    let header = format!(
+        r#"keyId="{}",algorithm="hs2019",headers="{}",signature="{}",created={}"#,
+        key_id,
+        CREATED_HEADERS.join(" "),
+        openssl::base64::encode_block(&signature),
+        created,
+    );
+    req.headers_mut().insert(
+        "signature",
+        http::HeaderValue::from_str(&header).map_err(sigh::Error::SerializeHeader)?
+    );
+    Ok(())
+}
+
+/// The current time for a `Date:` header.
+///
+/// Receivers reject signatures whose `Date:` is more than a few
+/// minutes off their clock, so this must be called right before
+/// signing, not when a job is created.
+pub fn http_date() -> String {
+    chrono::Utc::now()
+        .format("%a, %d %b %Y %H:%M:%S GMT")
+        .to_string()
+}
+
+/// A POST request with a `Digest:` of the body, covered by the
+/// signature. Build a fresh one for every attempt.
+pub fn signed_post(
+    uri: &str,
+    host: &str,
+    key_id: &str,
+    private_key: &PrivateKey,
+    body: Vec<u8>,
+    signed_headers: SignedHeaders,
+) -> Result<http::Request<Vec<u8>>, Error> {
+    let digest_header = digest::generate_header(&body)
+        .map_err(|()| Error::Digest)?;
+    let mut req = http::Request::builder()
+        .method("POST")
+        .uri(uri)
+        .header("host", host)
+        .header("content-type", "application/activity+json")
+        .header("date", http_date())
+        .header("digest", digest_header)
+        .body(body)
+        .map_err(Error::HttpReq)?;
+    match signed_headers {
+        SignedHeaders::Date => sign(&mut req, key_id, private_key)?,
+        SignedHeaders::Created => sign_created(&mut req, key_id, private_key)?,
+    }
+    Ok(req)
+}
 
 pub async fn send<T: Serialize>(
     client: &reqwest::Client,
+    filter: &AddressFilter,
     uri: &str,
     key_id: &str,
     private_key: &PrivateKey,
@@ -19,47 +142,224 @@ pub async fn send<T: Serialize>(
         serde_json::to_vec(body)
             .map_err(Error::Json)?
     );
-    send_raw(client, uri, key_id, private_key, body).await
+    send_raw(client, filter, uri, key_id, private_key, body, SignedHeaders::Date).await
 }
 
+/// Signs with `signed_headers`. If the inbox rejects that with
+/// HTTP 401 Unauthorized, tries once more with the other headers.
 pub async fn send_raw(
     client: &reqwest::Client,
+    filter: &AddressFilter,
     uri: &str,
     key_id: &str,
     private_key: &PrivateKey,
     body: Arc<Vec<u8>>,
+    signed_headers: SignedHeaders,
+) -> Result<(), Error> {
+    match post(client, filter, uri, key_id, private_key, &body, signed_headers).await {
+        Err(Error::Response(StatusCode::UNAUTHORIZED, _)) => {
+            increment_counter!("relay_signature_fallbacks_total");
+            let fallback = match signed_headers {
+                SignedHeaders::Date => SignedHeaders::Created,
+                SignedHeaders::Created => SignedHeaders::Date,
+            };
+            post(client, filter, uri, key_id, private_key, &body, fallback).await
+        }
+        result => result,
+    }
+}
+
+async fn post(
+    client: &reqwest::Client,
+    filter: &AddressFilter,
+    uri: &str,
+    key_id: &str,
+    private_key: &PrivateKey,
+    body: &[u8],
+    signed_headers: SignedHeaders,
 ) -> Result<(), Error> {
     let url = reqwest::Url::parse(uri)
         .map_err(|_| Error::InvalidUri)?;
+    filter.check_url(&url)?;
     let host = format!("{}", url.host().ok_or(Error::InvalidUri)?);
-    let digest_header = digest::generate_header(&body)
-        .map_err(|()| Error::Digest)?;
-    let mut req = http::Request::builder()
-        .method("POST")
-        .uri(uri)
-        .header("host", &host)
-        .header("content-type", "application/activity+json")
-        .header("date", chrono::Utc::now().to_rfc2822()
-            .replace("+0000", "GMT"))
-        .header("digest", digest_header)
-        .body(body.as_ref().clone())
-        .map_err(Error::HttpReq)?;
     let t1 = Instant::now();
-    SigningConfig::new(RsaSha256, private_key, key_id)
-        .sign(&mut req)?;
+    let req = signed_post(uri, &host, key_id, private_key, body.to_vec(), signed_headers)?;
     let t2 = Instant::now();
     let req: reqwest::Request = req.try_into()?;
-    let res = client.execute(req)
-        .await?;
+    let result = client.execute(req)
+        .await;
     let t3 = Instant::now();
     histogram!("relay_http_request_duration", t2 - t1);
+    histogram!("relay_delivery_duration", t3 - t2, "host" => host.clone());
+    let status = match &result {
+        Ok(res) => status_class(res.status()),
+        Err(e) if e.is_timeout() => "timeout",
+        Err(_) => "error",
+    };
+    increment_counter!("relay_deliveries_total", "status" => status, "host" => host.clone());
+    let res = result?;
     if res.status() >= StatusCode::OK && res.status() < StatusCode::MULTIPLE_CHOICES {
         histogram!("relay_http_response_duration", t3 - t2, "res" => "ok", "host" => host);
         Ok(())
     } else {
         histogram!("relay_http_response_duration", t3 - t2, "res" => "err", "host" => host);
         tracing::error!("send_raw {} response HTTP {}", url, res.status());
+        if res.status() == StatusCode::TOO_MANY_REQUESTS {
+            return Err(Error::RateLimited(retry_after(res.headers())));
+        }
+        let status = res.status();
         let response = res.text().await?;
-        Err(Error::Response(response))
+        Err(Error::Response(status, response))
+    }
+}
+
+/// Bounded label for a response status
+fn status_class(status: StatusCode) -> &'static str {
+    if status.is_success() {
+        "ok"
+    } else if status.is_client_error() {
+        "4xx"
+    } else if status.is_server_error() {
+        "5xx"
+    } else {
+        "other"
+    }
+}
+
+/// Parses the delay-seconds or HTTP-date form of `Retry-After:`
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim();
+    if let Ok(secs) = value.parse() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    (date.with_timezone(&chrono::Utc) - chrono::Utc::now())
+        .to_std()
+        .ok()
+}
+
+#[cfg(test)]
+mod test {
+    use sigh::{alg::Algorithm, Signature};
+    use super::*;
+
+    fn signed_request(private_key: &PrivateKey) -> http::Request<()> {
+        let mut req = http::Request::builder()
+            .method("POST")
+            .uri("https://example.com/inbox")
+            .header("host", "example.com")
+            .header("content-type", "application/activity+json")
+            .header("date", "Wed, 07 Dec 2022 17:25:25 GMT")
+            .header("digest", digest::generate_header(b"{}").unwrap())
+            .body(())
+            .unwrap();
+        sign(&mut req, "https://relay.example/tag/rust#key", private_key).unwrap();
+        req
+    }
+
+    #[test]
+    fn signed_post_digest() {
+        let (private_key, public_key) = RsaSha256.generate_keys().unwrap();
+        let req = signed_post(
+            "https://example.com/inbox", "example.com",
+            "https://relay.example/tag/rust#key", &private_key,
+            br#"{"type":"Follow"}"#.to_vec(), SignedHeaders::Date
+        ).unwrap();
+        assert_eq!(req.headers()["digest"], "SHA-256=GYwYnH3BiO6aICFt0ThC5bUIJ4byvqdpWtR8m5fNkww=");
+        let signature = Signature::from(&req);
+        assert!(signature.headers().unwrap().contains(&"digest"));
+        assert!(signature.headers().unwrap().contains(&"date"));
+        assert!(signature.verify(&public_key).unwrap());
+    }
+
+    #[test]
+    fn signed_post_date_is_current() {
+        let (private_key, _) = RsaSha256.generate_keys().unwrap();
+        let req = signed_post(
+            "https://example.com/inbox", "example.com",
+            "https://relay.example/tag/rust#key", &private_key,
+            vec![], SignedHeaders::Date
+        ).unwrap();
+        let date = req.headers()["date"].to_str().unwrap();
+        assert!(date.ends_with(" GMT"));
+        let date = chrono::DateTime::parse_from_rfc2822(date).unwrap();
+        let skew = chrono::Utc::now().signed_duration_since(date);
+        assert!(skew.num_seconds().abs() < 5);
+    }
+
+    #[test]
+    fn signed_post_created() {
+        for (private_key, public_key) in [
+            RsaSha256.generate_keys().unwrap(),
+            Hs2019.generate_keys().unwrap(),
+        ] {
+            let req = signed_post(
+                "https://example.com/inbox", "example.com",
+                "https://relay.example/tag/rust#key", &private_key,
+                br#"{"type":"Follow"}"#.to_vec(), SignedHeaders::Created
+            ).unwrap();
+            let header = req.headers()["signature"].to_str().unwrap();
+            assert!(header.contains(r#"algorithm="hs2019""#));
+            assert!(header.contains(r#"headers="(request-target) (created) host date digest content-type""#));
+            let signature = Signature::from(&req);
+            assert_eq!(signature.key_id(), Some("https://relay.example/tag/rust#key"));
+
+            let created = header.rsplit_once("created=").unwrap().1;
+            assert!((chrono::Utc::now().timestamp() - created.parse::<i64>().unwrap()).abs() < 5);
+            let signing_string = signing_string(&req, CREATED_HEADERS, created);
+            assert!(signing_string.starts_with(&format!("(request-target): post /inbox\n(created): {}\nhost: example.com\n", created)));
+            let signature_bytes = openssl::base64::decode_block(
+                header.split(r#"signature=""#).nth(1).unwrap().split('"').next().unwrap()
+            ).unwrap();
+            let verified = if private_key.0.id() == Id::ED25519 {
+                Hs2019.verify(&public_key, signing_string.as_bytes(), &signature_bytes)
+            } else {
+                RsaSha256.verify(&public_key, signing_string.as_bytes(), &signature_bytes)
+            };
+            assert!(verified.unwrap());
+        }
+    }
+
+    #[test]
+    fn status_classes() {
+        assert_eq!(status_class(StatusCode::ACCEPTED), "ok");
+        assert_eq!(status_class(StatusCode::UNAUTHORIZED), "4xx");
+        assert_eq!(status_class(StatusCode::BAD_GATEWAY), "5xx");
+        assert_eq!(status_class(StatusCode::MOVED_PERMANENTLY), "other");
+    }
+
+    #[test]
+    fn sign_rsa() {
+        let (private_key, public_key) = RsaSha256.generate_keys().unwrap();
+        let req = signed_request(&private_key);
+        let header = req.headers()["signature"].to_str().unwrap();
+        assert!(header.contains(r#"algorithm="rsa-sha256""#));
+        assert!(Signature::from(&req).verify(&public_key).unwrap());
+    }
+
+    #[test]
+    fn sign_ed25519() {
+        let (private_key, public_key) = Hs2019.generate_keys().unwrap();
+        let req = signed_request(&private_key);
+        let header = req.headers()["signature"].to_str().unwrap();
+        assert!(header.contains(r#"algorithm="hs2019""#));
+        assert!(Signature::from(&req).verify(&public_key).unwrap());
+    }
+
+    #[tokio::test]
+    async fn refuses_private_addresses() {
+        let inbox = crate::mock_inbox::MockInbox::start().await;
+        let (private_key, _) = RsaSha256.generate_keys().unwrap();
+        let client = reqwest::Client::new();
+        let result = send(&client, &AddressFilter::default(), &inbox.url("/inbox"), "https://relay.example/tag/rust#key", &private_key, &()).await;
+        assert!(matches!(result, Err(Error::BlockedAddress)));
+        assert!(inbox.received().is_empty());
+
+        let filter = AddressFilter::new(vec!["127.0.0.0/8".parse().unwrap()]);
+        send(&client, &filter, &inbox.url("/inbox"), "https://relay.example/tag/rust#key", &private_key, &()).await.unwrap();
+        assert_eq!(inbox.received().len(), 1);
     }
 }
@@ -1,20 +1,81 @@
-use std::time::Duration;
+use std::{
+    collections::HashSet,
+    sync::{atomic::{AtomicBool, Ordering}, Arc},
+    time::Duration,
+};
 use futures::{Stream, StreamExt};
+use metrics::{gauge, increment_counter};
 use eventsource_stream::Eventsource;
 use tokio::{
     sync::mpsc::{channel, Receiver},
     time::sleep,
 };
 
-#[derive(Debug)]
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+#[derive(Debug, thiserror::Error)]
 pub enum StreamError {
+    #[error("HTTP client error: {0}")]
     Http(reqwest::Error),
+    #[error("HTTP status {0}")]
     HttpStatus(reqwest::StatusCode),
+    #[error("Invalid content-type")]
     InvalidContentType,
 }
 
-async fn run(url: &str) -> Result<impl Stream<Item = String>, StreamError> {
-    let client = reqwest::Client::new();
+/// Streaming API events relevant for relaying, with the host of the
+/// stream that status ids refer to
+pub type SourcedEvent = (Arc<String>, Event);
+
+/// Streaming API events relevant for relaying
+#[derive(Debug)]
+pub enum Event {
+    /// JSON of a new status
+    Update(String),
+    /// JSON of an edited status
+    StatusUpdate(String),
+    /// Id of a deleted status
+    Delete(String),
+}
+
+impl Event {
+    /// Dispatches on the SSE event name, ignoring notifications,
+    /// `filters_changed` and the like. Heartbeats are SSE comments,
+    /// which the parser drops before this.
+    fn from_sse(event: &str, data: String) -> Option<Self> {
+        match event {
+            "update" => Some(Event::Update(data)),
+            "status.update" => Some(Event::StatusUpdate(data)),
+            "delete" => Some(Event::Delete(data)),
+            _ => {
+                tracing::trace!("ignoring stream event {:?}", event);
+                None
+            }
+        }
+    }
+}
+
+/// Ends the stream once nothing arrives for `timeout`. Mastodon sends
+/// a heartbeat comment every 15 seconds, so a silent connection is
+/// dead even if no posts are expected.
+fn with_idle_timeout<S: Stream + Unpin>(stream: S, timeout: Duration, url: String) -> impl Stream<Item = S::Item> {
+    futures::stream::unfold(stream, move |mut stream| {
+        let url = url.clone();
+        async move {
+            match tokio::time::timeout(timeout, stream.next()).await {
+                Ok(item) => Some((item?, stream)),
+                Err(_) => {
+                    tracing::warn!("stream {}: nothing received, not even a heartbeat, for {:?}", url, timeout);
+                    increment_counter!("stream_heartbeat_timeouts_total", "stream" => url);
+                    None
+                }
+            }
+        }
+    })
+}
+
+async fn run(client: &reqwest::Client, url: &str, heartbeat_timeout: Duration) -> Result<impl Stream<Item = Event>, StreamError> {
     let res = client.get(url)
         .timeout(Duration::MAX)
         .send()
@@ -25,43 +86,117 @@ async fn run(url: &str) -> Result<impl Stream<Item = String>, StreamError> {
     }
     let ct = res.headers().get("content-type")
         .and_then(|c| c.to_str().ok());
-    if ct.map_or(true, |ct| ct != "text/event-stream") {
+    if ct != Some("text/event-stream") {
         return Err(StreamError::InvalidContentType);
     }
 
-    let src = res.bytes_stream()
+    let src = with_idle_timeout(Box::pin(res.bytes_stream()), heartbeat_timeout, url.to_string())
         .eventsource()
         .filter_map(|result| async {
             let result = result.ok()?;
-            if result.event == "update" {
-                Some(result)
-            } else {
-                None
-            }
-        })
-        .map(|event| event.data);
+            Event::from_sse(&result.event, result.data)
+        });
     Ok(src)
 }
 
-pub fn spawn(hosts: impl Iterator<Item = impl Into<String>>) -> Receiver<String> {
+/// The host of a stream URL, which status ids are local to. Without
+/// the URL's query which may contain an access token.
+fn source(url: &str) -> String {
+    reqwest::Url::parse(url).ok()
+        .and_then(|url| url.host_str().map(str::to_string))
+        .unwrap_or_default()
+}
+
+/// Connects to every distinct stream URL independently, merging their
+/// events into one channel. The flag is set after the first successful
+/// connect. Connections that stay silent for `heartbeat_timeout` are
+/// reconnected.
+pub fn spawn(hosts: impl Iterator<Item = impl Into<String>>, heartbeat_timeout: Duration, proxy: Option<reqwest::Proxy>) -> (Receiver<SourcedEvent>, Arc<AtomicBool>) {
+    let mut client = reqwest::Client::builder();
+    if let Some(proxy) = proxy {
+        client = client.proxy(proxy);
+    }
+    let client = client.build()
+        .unwrap();
     let (tx, rx) = channel(1024);
+    let connected = Arc::new(AtomicBool::new(false));
+    let mut seen = HashSet::new();
     for host in hosts {
         let host = host.into();
+        if ! seen.insert(host.clone()) {
+            tracing::warn!("ignoring duplicate stream {}", host);
+            continue;
+        }
+        let client = client.clone();
         let tx = tx.clone();
+        let connected = connected.clone();
+        let source = Arc::new(source(&host));
         tokio::spawn(async move {
+            let mut delay = MIN_RECONNECT_DELAY;
             loop {
-                match run(&host).await {
-                    Ok(stream) =>
+                match run(&client, &host, heartbeat_timeout).await {
+                    Ok(stream) => {
+                        tracing::info!("stream {} connected", host);
+                        gauge!("stream_connected", 1.0, "stream" => host.clone());
+                        connected.store(true, Ordering::Relaxed);
+                        delay = MIN_RECONNECT_DELAY;
                         stream.for_each(|post| async {
-                            tx.send(post).await.unwrap();
-                        }).await,
-                    Err(e) =>
-                        tracing::error!("stream: {:?}", e),
+                            // Per stream, so that a stall shows next
+                            // to `stream_connected`
+                            increment_counter!("stream_events_total", "stream" => host.clone());
+                            gauge!("stream_last_event_timestamp_seconds", chrono::Utc::now().timestamp() as f64, "stream" => host.clone());
+                            tx.send((source.clone(), post)).await.unwrap();
+                        }).await;
+                        gauge!("stream_connected", 0.0, "stream" => host.clone());
+                        tracing::warn!("stream {} disconnected", host);
+                    }
+                    Err(e) => {
+                        tracing::error!("stream {}: {:?}", host, e);
+                    }
                 }
 
-                sleep(Duration::from_secs(1)).await;
+                tracing::info!("reconnecting stream {} in {:?}", host, delay);
+                increment_counter!("stream_reconnects_total", "stream" => host.clone());
+                sleep(delay).await;
+                // reset by the next successful connect
+                delay = (2 * delay).min(MAX_RECONNECT_DELAY);
             }
         });
     }
-    rx
+    (rx, connected)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn from_sse() {
+        assert!(matches!(Event::from_sse("update", "{}".to_string()), Some(Event::Update(_))));
+        assert!(matches!(Event::from_sse("status.update", "{}".to_string()), Some(Event::StatusUpdate(_))));
+        assert!(matches!(Event::from_sse("delete", "1".to_string()), Some(Event::Delete(id)) if id == "1"));
+        assert!(Event::from_sse("notification", "{}".to_string()).is_none());
+        assert!(Event::from_sse("filters_changed", String::new()).is_none());
+        assert!(Event::from_sse("", "{}".to_string()).is_none());
+    }
+
+    #[test]
+    fn sources() {
+        assert_eq!(source("https://example.com/api/v1/streaming/public?access_token=secret"), "example.com");
+        assert_eq!(source("https://example.com/api/v1/streaming/hashtag?tag=rust"), "example.com");
+        assert_eq!(source("example.com"), "");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_timeout() {
+        let timeout = Duration::from_secs(60);
+        let items = futures::stream::iter([1, 2]).chain(futures::stream::pending());
+        let stream = with_idle_timeout(Box::pin(items), timeout, "test".to_string());
+        let start = tokio::time::Instant::now();
+        assert_eq!(stream.collect::<Vec<_>>().await, [1, 2]);
+        assert_eq!(start.elapsed(), timeout);
+
+        let stream = with_idle_timeout(futures::stream::iter([1]), timeout, "test".to_string());
+        assert_eq!(stream.collect::<Vec<_>>().await, [1]);
+    }
 }
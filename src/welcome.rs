@@ -0,0 +1,116 @@
+//! Optional note that relay actors send to new followers, so that they
+//! see activity before the first relayed post
+
+use std::{
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use serde_json::json;
+use crate::actor::Actor;
+
+/// Followers get at most one welcome in this interval, however many
+/// actors they follow
+const WELCOME_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+/// Followers to remember
+const LIMITER_SIZE: usize = 16_384;
+
+/// When followers got welcomed last
+#[derive(Clone)]
+pub struct Limiter {
+    welcomed: Arc<Mutex<lru::LruCache<String, Instant>>>,
+}
+
+impl Limiter {
+    pub fn new() -> Self {
+        Limiter {
+            welcomed: Arc::new(Mutex::new(lru::LruCache::new(
+                NonZeroUsize::new(LIMITER_SIZE).unwrap()
+            ))),
+        }
+    }
+
+    /// Whether to welcome `follower_id` now, which counts as welcomed
+    /// if so
+    pub fn allow(&self, follower_id: &str) -> bool {
+        let mut welcomed = self.welcomed.lock().unwrap();
+        if welcomed.get(follower_id)
+            .is_some_and(|last| last.elapsed() < WELCOME_INTERVAL)
+        {
+            return false;
+        }
+        welcomed.put(follower_id.to_string(), Instant::now());
+        true
+    }
+}
+
+/// The `Note` with the HTML `message` to `follower_id`, also served at
+/// its id
+pub fn note(actor: &Actor, follower_id: &str, message: &str) -> serde_json::Value {
+    json!({
+        "type": "Note",
+        "id": format!(
+            "https://{}/welcome/{}/{}",
+            actor.host,
+            actor.username(),
+            urlencoding::encode(follower_id),
+        ),
+        "attributedTo": actor.uri(),
+        "content": message,
+        "to": [follower_id],
+    })
+}
+
+/// A `Create` of the `note()`, addressed only to the follower
+pub fn create_note(
+    jsonld_context: &serde_json::Value,
+    actor: &Actor,
+    follower_id: &str,
+    message: &str,
+) -> serde_json::Value {
+    let published = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+    let note = note(actor, follower_id, message);
+    json!({
+        "@context": jsonld_context,
+        "type": "Create",
+        "id": format!("{}#create", note["id"].as_str().unwrap_or_default()),
+        "actor": actor.uri(),
+        "published": &published,
+        "to": [follower_id],
+        "object": note,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::actor::ActorKind;
+    use super::*;
+
+    #[test]
+    fn once_per_follower() {
+        let limiter = Limiter::new();
+        assert!(limiter.allow("https://example.com/actor"));
+        assert!(! limiter.clone().allow("https://example.com/actor"));
+        assert!(limiter.allow("https://other.example/actor"));
+    }
+
+    #[test]
+    fn direct_note() {
+        let actor = Actor {
+            host: Arc::new("relay.example".to_string()),
+            kind: ActorKind::from_tag("rust"),
+        };
+        let create = create_note(&json!("https://www.w3.org/ns/activitystreams"), &actor, "https://example.com/actor", "<p>Hello</p>");
+        assert_eq!(create["type"], "Create");
+        assert_eq!(create["actor"], "https://relay.example/tag/rust");
+        assert_eq!(create["object"]["attributedTo"], create["actor"]);
+        assert_eq!(create["object"]["content"], "<p>Hello</p>");
+        assert_eq!(create["to"], json!(["https://example.com/actor"]));
+        assert_eq!(create["object"]["to"], create["to"]);
+        assert!(create.get("cc").is_none());
+        let note_id = create["object"]["id"].as_str().unwrap();
+        assert_eq!(note_id, "https://relay.example/welcome/tag-rust/https%3A%2F%2Fexample.com%2Factor");
+        assert_eq!(create["id"], format!("{}#create", note_id));
+        assert_eq!(create["object"], note(&actor, "https://example.com/actor", "<p>Hello</p>"));
+    }
+}
@@ -4,7 +4,7 @@
         var preEl = document.getElementById(id + "-url");
         function onChange(ev) {
             setTimeout(function() {
-                var value = encodeURIComponent(inputEl.value.replace(/^#/, ""));
+                var value = encodeURIComponent(inputEl.value.replace(/^[#@]/, ""));
                 preEl.innerText = value ?
                     "https://" + document.location.host + "/" + id + "/" + value :
                     "";
@@ -16,4 +16,5 @@
 
     setup("tag");
     setup("instance");
+    setup("account");
 })()